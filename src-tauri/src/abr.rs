@@ -0,0 +1,76 @@
+use crate::config::ClientAbrConfig;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+// Playback health reported by a client through push_client_feedback
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default)]
+#[serde(default)]
+pub struct ClientFeedback {
+    // How far playback is behind the newest video the client received
+    pub lag_ms: u32,
+    // Frames the client dropped or skipped since its previous report
+    pub dropped_frames: u32,
+}
+
+// Result of a report: the bitrate the stream runs at now
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AbrStatus {
+    pub bitrate_kbps: u32,
+    // The report changed the bitrate; FFmpeg restarts with it
+    pub changed: bool,
+}
+
+// Adaptation state of one stream (one FFmpeg)
+#[derive(Debug, Default)]
+pub struct AbrState {
+    // Bitrate chosen by the adaptation, None while at the configured one
+    pub bitrate_kbps: Option<u32>,
+    last_change: Option<Instant>,
+    // Start of the current run of healthy reports
+    healthy_since: Option<Instant>,
+}
+
+impl AbrState {
+    // Apply a client report and return the new bitrate if it should change.
+    // A lagging or dropping client steps the bitrate down right away (after
+    // the cooldown); it steps back up, never above `ceiling_kbps`, only once
+    // every report for up_after_secs was healthy.
+    pub fn observe(
+        &mut self,
+        config: &ClientAbrConfig,
+        ceiling_kbps: u32,
+        feedback: &ClientFeedback,
+        now: Instant,
+    ) -> Option<u32> {
+        let current = self.bitrate_kbps.unwrap_or(ceiling_kbps);
+        let congested = feedback.lag_ms > config.max_lag_ms || feedback.dropped_frames > 0;
+        if congested || feedback.lag_ms > config.target_lag_ms {
+            self.healthy_since = None;
+        } else if self.healthy_since.is_none() {
+            self.healthy_since = Some(now);
+        }
+
+        let cooldown = Duration::from_secs(config.cooldown_secs);
+        if self.last_change.is_some_and(|at| now.duration_since(at) < cooldown) {
+            return None;
+        }
+        let up_after = Duration::from_secs(config.up_after_secs);
+        let next = if congested {
+            ((current as f64 * config.step_down).round() as u32).max(config.min_bitrate_kbps)
+        } else if self.healthy_since.is_some_and(|since| now.duration_since(since) >= up_after) {
+            ((current as f64 * config.step_up).round() as u32).min(ceiling_kbps)
+        } else {
+            current
+        };
+        if next == current {
+            return None;
+        }
+
+        self.bitrate_kbps = (next < ceiling_kbps).then_some(next);
+        self.last_change = Some(now);
+        // Health at the new bitrate is judged from scratch
+        self.healthy_since = None;
+        Some(next)
+    }
+}
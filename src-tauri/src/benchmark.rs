@@ -0,0 +1,276 @@
+use crate::config::{BenchmarkConfig, StreamOptions};
+use crate::metrics::Progress;
+use crate::process::{FfmpegProcess, KillHandle};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use sysinfo::System;
+use tokio::sync::oneshot;
+
+// Source that selects FFmpeg's synthetic test pattern instead of a camera
+pub const TEST_SOURCE: &str = "testsrc";
+// 1080p at 25 fps, like a typical camera main stream
+const TEST_PATTERN: &str = "testsrc2=size=1920x1080:rate=25";
+
+// Each step is measured over this window, after settle_secs of warm-up.
+// FFmpeg reports progress twice a second, so the frame rate is taken
+// between the reports nearest to the window's ends, over the time that
+// actually passed between them.
+const MEASURE_WINDOW: Duration = Duration::from_secs(5);
+// A step is stable when every stream kept up with at least this share of
+// the target frame rate
+const MIN_FPS_RATIO: f64 = 0.9;
+
+static RUNNING: AtomicBool = AtomicBool::new(false);
+// Signals the running benchmark to stop, see cancel()
+static CANCEL: Mutex<Option<oneshot::Sender<()>>> = Mutex::new(None);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BenchmarkStep {
+    pub streams: usize,
+    // System-wide, over the measure window
+    pub cpu_percent: f32,
+    pub memory_percent: f32,
+    // Frame rate of the slowest stream over the measure window
+    pub min_fps: f64,
+    pub stable: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BenchmarkReport {
+    pub max_stable_streams: usize,
+    // What ended the benchmark: "cpu", "memory", "frame_rate",
+    // "ffmpeg_exited" or "max_streams"
+    pub limit: String,
+    pub target_fps: u32,
+    // System-wide CPU use before any stream was started
+    pub baseline_cpu_percent: f32,
+    pub steps: Vec<BenchmarkStep>,
+}
+
+// One benchmark stream: an FFmpeg encoding the source with the stream's
+// options, its output discarded. Dropping it kills and reaps FFmpeg.
+struct Worker {
+    process: FfmpegProcess,
+    kill: KillHandle,
+    // Latest progress report and when it arrived
+    progress: Arc<Mutex<Option<(Instant, Progress)>>>,
+    last_line: Arc<Mutex<String>>,
+}
+
+impl Worker {
+    fn progress(&self) -> Option<(Instant, u64)> {
+        self.progress.lock().unwrap().as_ref().map(|(at, progress)| (*at, progress.frame))
+    }
+}
+
+// Frames per second between two progress reports
+fn frame_rate(start: Option<(Instant, u64)>, end: Option<(Instant, u64)>) -> f64 {
+    match (start, end) {
+        (Some((start_at, start_frame)), Some((end_at, end_frame))) if end_at > start_at => {
+            end_frame.saturating_sub(start_frame) as f64 / (end_at - start_at).as_secs_f64()
+        }
+        _ => 0.0,
+    }
+}
+
+// Marks the benchmark finished once everything else of run() is dropped
+struct RunningGuard;
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        CANCEL.lock().unwrap().take();
+        RUNNING.store(false, Ordering::SeqCst);
+    }
+}
+
+// Add one stream of `options` at a time until system CPU or memory use
+// passes the thresholds or a stream can no longer keep its frame rate, and
+// report the last stream count that held. Every FFmpeg is stopped when the
+// benchmark ends, fails or is cancelled (the workers are dropped on any
+// return). Only one benchmark runs at a time.
+pub async fn run<F>(
+    source: &str,
+    options: &StreamOptions,
+    config: &BenchmarkConfig,
+    mut on_step: F,
+) -> Result<BenchmarkReport, String>
+where
+    F: FnMut(&BenchmarkStep),
+{
+    if RUNNING.swap(true, Ordering::SeqCst) {
+        return Err("A benchmark is already running".to_string());
+    }
+    let _running = RunningGuard;
+    let (cancel_tx, mut cancel_rx) = oneshot::channel();
+    *CANCEL.lock().unwrap() = Some(cancel_tx);
+
+    // Only the live output is measured
+    let mut options = StreamOptions {
+        recording_config: None,
+        shadow_buffer: None,
+        pipe_output: None,
+        quality_monitor: None,
+        debug_timestamps: false,
+        webrtc: false,
+        max_frames: None,
+        ..options.clone()
+    };
+    let url = if source == TEST_SOURCE {
+        options.input_format = Some("lavfi".to_string());
+        options.realtime = Some(true);
+        TEST_PATTERN
+    } else {
+        source
+    };
+    let args = crate::ffmpeg::build_ffmpeg_args(url, &options, false, None);
+    let target_fps = options.encode.framerate;
+
+    let mut system = System::new();
+    system.refresh_cpu_usage();
+    wait(Duration::from_secs(1), &mut cancel_rx).await?;
+    system.refresh_cpu_usage();
+    let baseline_cpu_percent = system.global_cpu_usage();
+
+    let mut workers: Vec<Worker> = Vec::new();
+    let mut steps = Vec::new();
+    let mut max_stable_streams = 0;
+    let mut limit = "max_streams";
+    while workers.len() < config.max_streams {
+        workers.push(spawn_worker(&args)?);
+        wait(Duration::from_secs(config.settle_secs), &mut cancel_rx).await?;
+
+        let start: Vec<Option<(Instant, u64)>> = workers.iter().map(Worker::progress).collect();
+        system.refresh_cpu_usage();
+        wait(MEASURE_WINDOW, &mut cancel_rx).await?;
+        system.refresh_cpu_usage();
+        system.refresh_memory();
+
+        let min_fps = workers
+            .iter()
+            .zip(&start)
+            .map(|(worker, start)| frame_rate(*start, worker.progress()))
+            .fold(f64::INFINITY, f64::min);
+        let cpu_percent = system.global_cpu_usage();
+        let memory_percent = system.used_memory() as f32 * 100.0 / system.total_memory().max(1) as f32;
+        let exited = workers.iter().find(|worker| worker.kill.has_exited());
+
+        let failed = if let Some(worker) = exited {
+            if workers.len() == 1 {
+                return Err(format!("FFmpeg exited: {}", worker.last_line.lock().unwrap()));
+            }
+            Some("ffmpeg_exited")
+        } else if cpu_percent > config.max_cpu_percent {
+            Some("cpu")
+        } else if memory_percent > config.max_memory_percent {
+            Some("memory")
+        } else if min_fps < target_fps as f64 * MIN_FPS_RATIO {
+            Some("frame_rate")
+        } else {
+            None
+        };
+
+        let step = BenchmarkStep {
+            streams: workers.len(),
+            cpu_percent,
+            memory_percent,
+            min_fps,
+            stable: failed.is_none(),
+        };
+        log::info!(
+            "Benchmark: {} streams, CPU {:.0}%, memory {:.0}%, slowest {:.1} fps",
+            step.streams,
+            cpu_percent,
+            memory_percent,
+            min_fps
+        );
+        on_step(&step);
+        steps.push(step);
+
+        match failed {
+            Some(reason) => {
+                limit = reason;
+                break;
+            }
+            None => max_stable_streams = workers.len(),
+        }
+    }
+
+    // Reaping blocks until every FFmpeg has exited
+    let _ = tokio::task::spawn_blocking(move || {
+        for worker in workers {
+            worker.process.finish();
+        }
+    })
+    .await;
+    Ok(BenchmarkReport {
+        max_stable_streams,
+        limit: limit.to_string(),
+        target_fps,
+        baseline_cpu_percent,
+        steps,
+    })
+}
+
+// Stop the running benchmark and wait until its FFmpeg processes are gone.
+// Returns false if no benchmark was running.
+pub async fn cancel() -> bool {
+    let Some(cancel_tx) = CANCEL.lock().unwrap().take() else {
+        return false;
+    };
+    let _ = cancel_tx.send(());
+    while RUNNING.load(Ordering::SeqCst) {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    true
+}
+
+async fn wait(duration: Duration, cancel_rx: &mut oneshot::Receiver<()>) -> Result<(), String> {
+    tokio::select! {
+        _ = tokio::time::sleep(duration) => Ok(()),
+        _ = cancel_rx => Err("Benchmark cancelled".to_string()),
+    }
+}
+
+fn spawn_worker(args: &[String]) -> Result<Worker, String> {
+    let mut cmd = crate::ffmpeg::ffmpeg_command();
+    cmd.args(args);
+    let progress = Arc::new(Mutex::new(None));
+    let last_line = Arc::new(Mutex::new(String::new()));
+    let (progress_clone, last_line_clone) = (Arc::clone(&progress), Arc::clone(&last_line));
+    let (process, mut stdout) = FfmpegProcess::spawn(cmd, move |line| match crate::metrics::parse_progress(&line) {
+        Some(update) => *progress_clone.lock().unwrap() = Some((Instant::now(), update)),
+        None => *last_line_clone.lock().unwrap() = crate::rtsp_url::redact(&line),
+    })
+    .map_err(|e| format!("Failed to start FFmpeg: {}", e))?;
+
+    // The output only exists to create the load; the reader ends when
+    // FFmpeg is killed
+    std::thread::spawn(move || {
+        let _ = std::io::copy(&mut stdout, &mut std::io::sink());
+    });
+    Ok(Worker {
+        kill: process.kill_handle(),
+        process,
+        progress,
+        last_line,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_rates_use_the_time_between_reports() {
+        let start = Instant::now();
+        // Reports 4.4 s apart in a 5 s window: 110 frames is 25 fps, not 22
+        let end = start + Duration::from_millis(4400);
+        assert!((frame_rate(Some((start, 1000)), Some((end, 1110))) - 25.0).abs() < 1e-9);
+
+        // No report yet, or none since the window started
+        assert_eq!(frame_rate(None, Some((end, 100))), 0.0);
+        assert_eq!(frame_rate(Some((start, 100)), Some((start, 100))), 0.0);
+    }
+}
@@ -0,0 +1,85 @@
+use crate::devices::{self, CaptureDevice};
+use crate::ffmpeg::{self, Encoder, SupportedProtocols};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Capabilities {
+    pub ffmpeg: FfmpegInfo,
+    pub encoders: Vec<Encoder>,
+    pub protocols: Option<SupportedProtocols>,
+    pub hwaccels: Vec<String>,
+    pub capture_devices: Vec<CaptureDevice>,
+    pub network_interfaces: Vec<NetworkInterface>,
+    // Parts that could not be determined; the rest of the manifest is still
+    // filled in
+    pub errors: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FfmpegInfo {
+    pub available: bool,
+    pub path: String,
+    pub version: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NetworkInterface {
+    pub name: String,
+    pub address: String,
+    pub loopback: bool,
+}
+
+// Gather the capabilities manifest. Failures are collected in `errors`
+// instead of failing the whole call, so the UI can still render what is known
+// (e.g. capture devices and interfaces when FFmpeg is missing).
+pub fn collect() -> Capabilities {
+    let mut errors = Vec::new();
+
+    let version = ffmpeg::version().map_err(|e| errors.push(e)).ok();
+    let ffmpeg_info = FfmpegInfo {
+        available: version.is_some(),
+        path: crate::get_ffmpeg_path(),
+        version,
+    };
+
+    // Skip the remaining FFmpeg queries when the binary doesn't run at all
+    let (encoders, protocols, hwaccels) = if ffmpeg_info.available {
+        (
+            ffmpeg::encoders().map_err(|e| errors.push(e)).unwrap_or_default(),
+            ffmpeg::supported_protocols().map_err(|e| errors.push(e)).ok(),
+            ffmpeg::hwaccels().map_err(|e| errors.push(e)).unwrap_or_default(),
+        )
+    } else {
+        (Vec::new(), None, Vec::new())
+    };
+
+    let capture_devices = devices::list_capture_devices()
+        .map_err(|e| errors.push(e))
+        .unwrap_or_default();
+    let network_interfaces = network_interfaces()
+        .map_err(|e| errors.push(e))
+        .unwrap_or_default();
+
+    Capabilities {
+        ffmpeg: ffmpeg_info,
+        encoders,
+        protocols,
+        hwaccels,
+        capture_devices,
+        network_interfaces,
+        errors,
+    }
+}
+
+fn network_interfaces() -> Result<Vec<NetworkInterface>, String> {
+    let interfaces = if_addrs::get_if_addrs()
+        .map_err(|e| format!("Failed to list network interfaces: {}", e))?;
+    Ok(interfaces
+        .into_iter()
+        .map(|interface| NetworkInterface {
+            address: interface.ip().to_string(),
+            loopback: interface.is_loopback(),
+            name: interface.name,
+        })
+        .collect())
+}
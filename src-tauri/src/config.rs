@@ -0,0 +1,1300 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+// Per-stream options accepted by start_stream. Every field has a default so
+// the frontend only has to send the settings it wants to change.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(default)]
+pub struct StreamOptions {
+    // FFmpeg input format for the main source (-f before -i), e.g. dshow,
+    // avfoundation or v4l2 for a local capture device. None = autodetect.
+    pub input_format: Option<String>,
+    // RTSP transport preset and extra demuxer flags (-rtsp_flags), applied to
+    // every rtsp:// input
+    pub rtsp_preset: RtspPreset,
+    pub rtsp_flags: Option<Vec<String>>,
+    // Socket timeout for network inputs, so a source that stops sending
+    // without closing the connection ends FFmpeg and gets reconnected. 0 = off.
+    pub input_timeout_ms: u64,
+    // Address family for the main source's host. The host is resolved before
+    // each FFmpeg start and replaced with the chosen address.
+    pub resolve: Resolve,
+    // Read inputs at their native frame rate (-re). None = only for local
+    // files, which FFmpeg otherwise reads as fast as it can.
+    pub realtime: Option<bool>,
+    // Send a JSON text message before each video message that contains a
+    // keyframe (MPEG1 only)
+    pub keyframe_markers: bool,
+    // Encode settings for the live (jsmpeg) output
+    pub encode: EncodeConfig,
+    // Video buffer between FFmpeg and the WebSocket clients
+    pub buffer: BufferConfig,
+    // TCP options and handshake limits for accepted client connections
+    pub socket: SocketConfig,
+    // Sampling of the metrics history kept for export_metrics_csv
+    pub metrics: MetricsConfig,
+    // WebSocket subprotocols accepted from clients, e.g. "jsmpeg"
+    pub subprotocols: Vec<String>,
+    // Optional recording written as a second output of the same FFmpeg
+    pub recording_config: Option<RecordingConfig>,
+    // Circular on-disk recording of the last few minutes (see shadow.rs)
+    pub shadow_buffer: Option<ShadowConfig>,
+    // Copy of the live output on a named pipe for other local tools
+    pub pipe_output: Option<PipeConfig>,
+    // Additional inputs for filter_complex (the main source is input 0, these
+    // follow as inputs 1, 2, ...)
+    pub sources: Vec<String>,
+    // Complex filtergraph (e.g. picture-in-picture). Its unlabeled output
+    // feeds the live output.
+    pub filter_complex: Option<String>,
+    // Logo/camera ID image drawn over the live output
+    pub image_overlay: Option<OverlayImageConfig>,
+    // Wall-clock text drawn over the live output, kept in step across streams
+    pub clock_overlay: Option<ClockOverlayConfig>,
+    // Actual color range/matrix of the source, for cameras whose video is
+    // tagged wrong (washed-out or crushed blacks). None = passthrough.
+    pub color_range: Option<ColorRange>,
+    pub color_space: Option<ColorSpace>,
+    // Resample non-square source pixels (e.g. 4CIF from analog encoders) to
+    // square ones, so players that ignore the aspect ratio show the right
+    // geometry. Off by default: the source geometry is passed through.
+    pub fix_aspect: bool,
+    // Black/frozen picture detection on the main source
+    pub quality_monitor: Option<QualityMonitorConfig>,
+    // Also serve the stream to WebRTC clients through a WHEP endpoint on the
+    // stream port (requires the `webrtc` build feature)
+    pub webrtc: bool,
+    // The source URL expires (e.g. signed cloud camera URLs): before each
+    // reconnect, emit stream://needs_url and wait up to dynamic_url_timeout_ms
+    // for provide_stream_url, falling back to the last URL
+    pub dynamic_url: bool,
+    #[schemars(range(min = 1))]
+    pub dynamic_url_timeout_ms: u64,
+    // Relay an existing stream of the same source and options (that also
+    // set share_source) on the new port instead of starting another FFmpeg
+    pub share_source: bool,
+    // Probe the source before starting and fail instead of starting a stream
+    // that can't connect (network sources only)
+    pub verify_before_start: bool,
+    #[schemars(range(min = 1000, max = 60000))]
+    pub verify_timeout_ms: u64,
+    // End the stream after this many frames of the live output (-frames:v)
+    // instead of running until stopped
+    #[schemars(range(min = 1))]
+    pub max_frames: Option<u64>,
+    // How long FFmpeg gets to quit on its own (finishing recordings) when
+    // the stream stops or restarts, before it is killed
+    #[schemars(range(max = 60000))]
+    pub graceful_stop_timeout_ms: u64,
+    // Rank against other streams when the stream limits in the settings are
+    // reached: higher-priority streams may degrade or stop lower ones
+    pub priority: u8,
+    // Log FFmpeg's per-packet timestamps (-debug_ts) to the stream log and
+    // count timestamp problems in get_stream_quality
+    pub debug_timestamps: bool,
+    // Adapt the bitrate to a client's push_client_feedback reports
+    pub client_abr: Option<ClientAbrConfig>,
+    // Restart policy for FFmpeg crashes, separate from source reconnects
+    pub crash_restart: CrashRestartConfig,
+}
+
+impl Default for StreamOptions {
+    fn default() -> Self {
+        Self {
+            input_format: None,
+            rtsp_preset: RtspPreset::Tcp,
+            rtsp_flags: None,
+            input_timeout_ms: 10_000,
+            resolve: Resolve::Auto,
+            realtime: None,
+            keyframe_markers: false,
+            encode: EncodeConfig::default(),
+            buffer: BufferConfig::default(),
+            socket: SocketConfig::default(),
+            metrics: MetricsConfig::default(),
+            subprotocols: vec!["jsmpeg".to_string()],
+            recording_config: None,
+            shadow_buffer: None,
+            pipe_output: None,
+            sources: Vec::new(),
+            filter_complex: None,
+            image_overlay: None,
+            clock_overlay: None,
+            color_range: None,
+            color_space: None,
+            fix_aspect: false,
+            quality_monitor: None,
+            webrtc: false,
+            dynamic_url: false,
+            dynamic_url_timeout_ms: 10_000,
+            share_source: false,
+            verify_before_start: false,
+            verify_timeout_ms: 10_000,
+            max_frames: None,
+            graceful_stop_timeout_ms: 3_000,
+            priority: 0,
+            debug_timestamps: false,
+            client_abr: None,
+            crash_restart: CrashRestartConfig::default(),
+        }
+    }
+}
+
+// RTSP session presets for cameras that drop or refuse sessions
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RtspPreset {
+    // Interleaved TCP only (the default, most reliable through NAT)
+    #[default]
+    Tcp,
+    // Try TCP first and fall back to UDP, for cameras that refuse
+    // interleaved TCP
+    PreferTcp,
+    // Plain UDP, for cameras that time out idle TCP sessions but keep UDP
+    // sessions alive through RTCP reports
+    Udp,
+    // RTSP tunneled over HTTP, for cameras behind proxies that cut idle
+    // connections
+    Http,
+}
+
+// Address family used to reach the source host
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Resolve {
+    // Leave resolution to FFmpeg
+    #[default]
+    Auto,
+    // Connect to an IPv4 (A record) address only
+    V4Only,
+    // Connect to an IPv6 (AAAA record) address only
+    V6Only,
+}
+
+// RTSP demuxer flags accepted in rtsp_flags. "listen" is left out on
+// purpose: it turns the input into an RTSP server.
+pub const RTSP_FLAGS: [&str; 3] = ["filter_src", "prefer_tcp", "satip_raw"];
+
+// Video codec of the live output
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputCodec {
+    // MPEG1 in MPEG-TS for jsmpeg
+    #[default]
+    Mpeg1,
+    // H.264 in fragmented MP4 for Media Source Extensions
+    H264,
+}
+
+impl OutputCodec {
+    // Codec name as reported by ffprobe
+    pub fn codec_name(&self) -> &'static str {
+        match self {
+            OutputCodec::Mpeg1 => "mpeg1video",
+            OutputCodec::H264 => "h264",
+        }
+    }
+}
+
+// Source color range
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorRange {
+    // Limited/TV range (16-235)
+    Tv,
+    // Full/PC range (0-255)
+    Pc,
+}
+
+// Source YUV color matrix
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorSpace {
+    Bt601,
+    Bt709,
+    Bt2020,
+}
+
+impl ColorRange {
+    // Name used by FFmpeg's setparams filter
+    pub fn filter_name(&self) -> &'static str {
+        match self {
+            ColorRange::Tv => "tv",
+            ColorRange::Pc => "pc",
+        }
+    }
+}
+
+impl ColorSpace {
+    // Name used by FFmpeg's setparams filter
+    pub fn filter_name(&self) -> &'static str {
+        match self {
+            ColorSpace::Bt601 => "smpte170m",
+            ColorSpace::Bt709 => "bt709",
+            ColorSpace::Bt2020 => "bt2020nc",
+        }
+    }
+}
+
+// Live output encode settings. Defaults match the original hardcoded command.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(default)]
+pub struct EncodeConfig {
+    pub codec: OutputCodec,
+    // Probe the source and stream-copy instead of re-encoding when it already
+    // uses the output codec and no change to the picture is requested
+    pub copy_when_compatible: bool,
+    pub resolution: String,
+    #[schemars(range(min = 1))]
+    pub bitrate_kbps: u32,
+    #[schemars(range(min = 1, max = 120))]
+    pub framerate: u32,
+    #[schemars(range(min = 1, max = 31))]
+    pub quality: u32,
+    // Keyframe interval in frames (None = encoder default). Keep it short for
+    // fast join when a viewer connects mid-stream.
+    pub gop: Option<u32>,
+    // Hard bitrate ceiling (-maxrate) and rate control buffer (-bufsize).
+    // Without them -b:v is only an average and bursts can exceed the link.
+    pub maxrate_kbps: Option<u32>,
+    pub bufsize_kbps: Option<u32>,
+    // Rate control preset for the MPEG1 output that keeps frame sizes within
+    // what jsmpeg buffers. Explicit maxrate/bufsize take precedence.
+    pub jsmpeg_tuning: Option<JsmpegTuning>,
+    // MPEG-TS interleaving and matching jsmpeg audio buffer hints, see
+    // AvSyncMode. None keeps FFmpeg's muxer defaults (-muxdelay 0.7,
+    // -muxpreload 0.5), which buffer more than any of the modes.
+    pub av_sync_mode: Option<AvSyncMode>,
+}
+
+// jsmpeg stutters when a burst (typically a large I-frame) outruns its
+// decode buffer. Both presets bound bursts with a VBV buffer (-bufsize).
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JsmpegTuning {
+    // Constant-ish frame sizes: maxrate = bitrate, half a second of buffer.
+    // Lowest latency, softer keyframes.
+    LowLatency,
+    // Bursts up to 1.5x the bitrate, one second of buffer. Sharper keyframes
+    // at the cost of a larger client buffer.
+    Smooth,
+}
+
+// jsmpeg's own default videoBufferSize (512 KiB)
+pub const JSMPEG_DEFAULT_BUFFER_BYTES: usize = 512 * 1024;
+// jsmpeg's own default audioBufferSize (128 KiB)
+pub const JSMPEG_DEFAULT_AUDIO_BUFFER_BYTES: usize = 128 * 1024;
+
+// jsmpeg plays audio and video from one MPEG-TS stream and drifts when the
+// muxer interleaves them unevenly. A larger mux delay lets FFmpeg line audio
+// and video packets up by timestamp before writing them, and a larger client
+// audio buffer rides out the bursts, both at the cost of latency. Every mode
+// delays less than FFmpeg's default 0.7 s; they differ in how much of that
+// buffering they give up.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AvSyncMode {
+    // Packets are written as soon as they are encoded, saving about 0.7 s
+    // over the default. Audio may run ahead or crackle on jittery sources.
+    LowLatency,
+    // About 100 ms of interleaving (0.6 s less than the default), jsmpeg's
+    // default audio buffering
+    Balanced,
+    // Half a second of interleaving, still 0.2 s less than the default, and
+    // twice the audio buffer for the steadiest sync
+    Stable,
+}
+
+impl AvSyncMode {
+    // (-muxdelay, -muxpreload) in seconds
+    pub fn mux_delays(self) -> (f64, f64) {
+        match self {
+            AvSyncMode::LowLatency => (0.001, 0.0),
+            AvSyncMode::Balanced => (0.1, 0.1),
+            AvSyncMode::Stable => (0.5, 0.5),
+        }
+    }
+
+    // Client-side (audioBufferSize in bytes, maxAudioLag in seconds). jsmpeg
+    // drops buffered audio to catch up once it lags video by maxAudioLag.
+    pub fn jsmpeg_audio_hints(self) -> (usize, f64) {
+        match self {
+            AvSyncMode::LowLatency => (JSMPEG_DEFAULT_AUDIO_BUFFER_BYTES, 0.1),
+            AvSyncMode::Balanced => (JSMPEG_DEFAULT_AUDIO_BUFFER_BYTES, 0.25),
+            AvSyncMode::Stable => (JSMPEG_DEFAULT_AUDIO_BUFFER_BYTES * 2, 0.5),
+        }
+    }
+}
+
+// Rate control settings reported in stream status
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct RateControl {
+    // "vbr" (average bitrate only) or "capped" (maxrate/bufsize set)
+    pub mode: String,
+    pub bitrate_kbps: u32,
+    pub maxrate_kbps: Option<u32>,
+    pub bufsize_kbps: Option<u32>,
+}
+
+impl Default for EncodeConfig {
+    fn default() -> Self {
+        Self {
+            codec: OutputCodec::Mpeg1,
+            copy_when_compatible: false,
+            resolution: "640x480".to_string(),
+            bitrate_kbps: 1000,
+            framerate: 25,
+            quality: 5,
+            gop: None,
+            maxrate_kbps: None,
+            bufsize_kbps: None,
+            jsmpeg_tuning: None,
+            av_sync_mode: None,
+        }
+    }
+}
+
+// Video buffer between FFmpeg and the WebSocket clients, in messages. A
+// client that falls more than `capacity` messages behind is disconnected.
+// With `adaptive`, the capacity moves between min_capacity and max_capacity
+// based on observed lag (see relay::adapt_capacity).
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(default)]
+pub struct BufferConfig {
+    #[schemars(range(min = 1))]
+    pub capacity: usize,
+    pub adaptive: bool,
+    pub min_capacity: usize,
+    pub max_capacity: usize,
+}
+
+impl Default for BufferConfig {
+    fn default() -> Self {
+        Self {
+            capacity: crate::relay::DEFAULT_CAPACITY,
+            adaptive: false,
+            min_capacity: 25,
+            max_capacity: 800,
+        }
+    }
+}
+
+// TCP options applied to each accepted client socket. Buffer sizes and
+// keep-alive default to the OS settings. Handshakes are bounded separately
+// from established clients: at most max_handshakes run at once, and each
+// must finish within handshake_timeout_ms. With idle_timeout_secs, clients
+// are pinged and disconnected when no pong arrives for that long.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(default)]
+pub struct SocketConfig {
+    // Disable Nagle's algorithm so small WebSocket frames are sent at once
+    // instead of waiting to be coalesced
+    pub nodelay: bool,
+    pub send_buffer_bytes: Option<usize>,
+    pub recv_buffer_bytes: Option<usize>,
+    // Idle time before TCP keep-alive probes are sent
+    #[schemars(range(min = 1))]
+    pub keepalive_secs: Option<u64>,
+    #[schemars(range(min = 1, max = 10000))]
+    pub max_handshakes: usize,
+    #[schemars(range(min = 100, max = 60000))]
+    pub handshake_timeout_ms: u64,
+    #[schemars(range(min = 3, max = 3600))]
+    pub idle_timeout_secs: Option<u64>,
+}
+
+impl Default for SocketConfig {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            send_buffer_bytes: None,
+            recv_buffer_bytes: None,
+            keepalive_secs: None,
+            max_handshakes: 64,
+            handshake_timeout_ms: 10_000,
+            idle_timeout_secs: None,
+        }
+    }
+}
+
+// Metrics history of a stream: one sample every interval_secs, keeping the
+// latest `history` samples (default: one hour)
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(default)]
+pub struct MetricsConfig {
+    #[schemars(range(min = 1, max = 3600))]
+    pub interval_secs: u64,
+    #[schemars(range(min = 1, max = 100000))]
+    pub history: usize,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: 5,
+            history: 720,
+        }
+    }
+}
+
+impl MetricsConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(1..=3600).contains(&self.interval_secs) {
+            return Err(format!(
+                "Metrics interval must be between 1 and 3600 seconds, got {}",
+                self.interval_secs
+            ));
+        }
+        if !(1..=100_000).contains(&self.history) {
+            return Err(format!(
+                "Metrics history must keep between 1 and 100000 samples, got {}",
+                self.history
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl SocketConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        const MAX_BUFFER_BYTES: usize = 64 * 1024 * 1024;
+        for (name, size) in [("send", self.send_buffer_bytes), ("receive", self.recv_buffer_bytes)] {
+            if let Some(size) = size {
+                if size == 0 || size > MAX_BUFFER_BYTES {
+                    return Err(format!(
+                        "Socket {} buffer must be between 1 byte and 64 MB, got {}",
+                        name, size
+                    ));
+                }
+            }
+        }
+        if self.keepalive_secs == Some(0) {
+            return Err("Keep-alive time must be greater than 0".to_string());
+        }
+        if !(1..=10_000).contains(&self.max_handshakes) {
+            return Err(format!(
+                "max_handshakes must be between 1 and 10000, got {}",
+                self.max_handshakes
+            ));
+        }
+        if !(100..=60_000).contains(&self.handshake_timeout_ms) {
+            return Err(format!(
+                "handshake_timeout_ms must be between 100 and 60000, got {}",
+                self.handshake_timeout_ms
+            ));
+        }
+        if let Some(timeout) = self.idle_timeout_secs {
+            if !(3..=3600).contains(&timeout) {
+                return Err(format!(
+                    "idle_timeout_secs must be between 3 and 3600, got {}",
+                    timeout
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl BufferConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.capacity == 0 {
+            return Err("Buffer capacity must be greater than 0".to_string());
+        }
+        if self.adaptive {
+            if self.min_capacity == 0 || self.min_capacity > self.max_capacity {
+                return Err(format!(
+                    "Invalid adaptive buffer range {}..{}",
+                    self.min_capacity, self.max_capacity
+                ));
+            }
+            if !(self.min_capacity..=self.max_capacity).contains(&self.capacity) {
+                return Err(format!(
+                    "Buffer capacity {} is outside the adaptive range {}..{}",
+                    self.capacity, self.min_capacity, self.max_capacity
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Recording output settings, independent from the live output so recordings
+// can use long GOPs and a different bitrate without hurting live latency.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(default)]
+pub struct RecordingConfig {
+    pub output_path: PathBuf,
+    pub codec: String,
+    pub bitrate_kbps: u32,
+    // Keyframe interval in frames (250 = 10s at 25fps)
+    pub gop: u32,
+    // Refuse to start unless the disk can hold this many minutes at the
+    // recording bitrate
+    pub min_free_minutes: u32,
+    // Planned recording length; only a warning if it won't fit
+    pub expected_duration_minutes: Option<u32>,
+}
+
+impl Default for RecordingConfig {
+    fn default() -> Self {
+        Self {
+            output_path: PathBuf::new(),
+            codec: "libx264".to_string(),
+            bitrate_kbps: 2000,
+            gop: 250,
+            min_free_minutes: 10,
+            expected_duration_minutes: None,
+        }
+    }
+}
+
+// Always-on recording that keeps only the last `minutes` of the source, for
+// saving the recent past after an incident (promote_shadow)
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(default)]
+pub struct ShadowConfig {
+    #[schemars(range(min = 1, max = 120))]
+    pub minutes: u32,
+    // Where the segments are kept (default: a per-port temp directory)
+    pub directory: Option<PathBuf>,
+}
+
+impl Default for ShadowConfig {
+    fn default() -> Self {
+        Self {
+            minutes: 5,
+            directory: None,
+        }
+    }
+}
+
+impl ShadowConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(1..=120).contains(&self.minutes) {
+            return Err(format!(
+                "Shadow buffer must keep between 1 and 120 minutes, got {}",
+                self.minutes
+            ));
+        }
+        if let Some(directory) = &self.directory {
+            if !directory.is_dir() {
+                return Err(format!("Shadow buffer directory does not exist: {}", directory.display()));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Named pipe (a FIFO on Unix) that receives the same MPEG-TS or fMP4 bytes
+// as the WebSocket clients
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct PipeConfig {
+    // Default: a per-port pipe (see pipe.rs)
+    pub path: Option<PathBuf>,
+}
+
+// Limits on the FFmpeg processes running at once, from the settings. Ports
+// relaying another stream through share_source don't count. There is no CPU
+// limit; max_streams is meant to be set from benchmark_capacity.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(default)]
+pub struct StreamLimits {
+    #[schemars(range(min = 1))]
+    pub max_streams: Option<usize>,
+    // Budget for the sum of the streams' configured bitrates
+    #[schemars(range(min = 1))]
+    pub max_total_bitrate_kbps: Option<u32>,
+    // What a new stream may do to lower-priority ones when a limit is reached
+    pub preemption: PreemptionPolicy,
+    // Degrading halves a stream's bitrate, but not below this
+    #[schemars(range(min = 1))]
+    pub min_bitrate_kbps: u32,
+}
+
+impl Default for StreamLimits {
+    fn default() -> Self {
+        Self {
+            max_streams: None,
+            max_total_bitrate_kbps: None,
+            preemption: PreemptionPolicy::None,
+            min_bitrate_kbps: 250,
+        }
+    }
+}
+
+impl StreamLimits {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_streams == Some(0) {
+            return Err("max_streams must be greater than 0".to_string());
+        }
+        if self.max_total_bitrate_kbps == Some(0) {
+            return Err("max_total_bitrate_kbps must be greater than 0".to_string());
+        }
+        if self.min_bitrate_kbps == 0 {
+            return Err("min_bitrate_kbps must be greater than 0".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PreemptionPolicy {
+    // First come, first served: a stream over the limits is refused
+    #[default]
+    None,
+    // Stop lower-priority streams to make room
+    Stop,
+    // Lower the bitrate of lower-priority streams first (which only frees
+    // bitrate budget) and stop them if that isn't enough
+    DegradeThenStop,
+}
+
+// Thresholds and pacing of benchmark_capacity
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(default)]
+pub struct BenchmarkConfig {
+    // Stop adding streams once system-wide CPU or memory use passes these
+    #[schemars(range(min = 10, max = 100))]
+    pub max_cpu_percent: f32,
+    #[schemars(range(min = 10, max = 100))]
+    pub max_memory_percent: f32,
+    // Upper bound on the streams started, even if the machine keeps up
+    #[schemars(range(min = 1, max = 256))]
+    pub max_streams: usize,
+    // How long each step runs before it is measured
+    #[schemars(range(min = 3, max = 120))]
+    pub settle_secs: u64,
+}
+
+impl Default for BenchmarkConfig {
+    fn default() -> Self {
+        Self {
+            max_cpu_percent: 80.0,
+            max_memory_percent: 90.0,
+            max_streams: 64,
+            settle_secs: 10,
+        }
+    }
+}
+
+impl BenchmarkConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(10.0..=100.0).contains(&self.max_cpu_percent) {
+            return Err(format!("max_cpu_percent must be between 10 and 100, got {}", self.max_cpu_percent));
+        }
+        if !(10.0..=100.0).contains(&self.max_memory_percent) {
+            return Err(format!(
+                "max_memory_percent must be between 10 and 100, got {}",
+                self.max_memory_percent
+            ));
+        }
+        if !(1..=256).contains(&self.max_streams) {
+            return Err(format!("max_streams must be between 1 and 256, got {}", self.max_streams));
+        }
+        if !(3..=120).contains(&self.settle_secs) {
+            return Err(format!("settle_secs must be between 3 and 120, got {}", self.settle_secs));
+        }
+        Ok(())
+    }
+}
+
+// HLS output settings for start_hls_stream
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(default)]
+pub struct HlsConfig {
+    // Target segment length; keyframes are forced at this interval
+    #[schemars(range(min = 1, max = 60))]
+    pub segment_seconds: u32,
+    // Segments listed in the playlist. Older segments are deleted.
+    #[schemars(range(min = 2))]
+    pub playlist_size: u32,
+}
+
+impl Default for HlsConfig {
+    fn default() -> Self {
+        Self {
+            segment_seconds: 2,
+            playlist_size: 6,
+        }
+    }
+}
+
+// Image (e.g. a PNG logo with transparency) overlaid on the live output
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(default)]
+pub struct OverlayImageConfig {
+    pub path: PathBuf,
+    pub position: OverlayPosition,
+    // Distance from the picture edges in pixels
+    pub margin: u32,
+    // Scale factor applied to the image's own size
+    #[schemars(range(max = 10))]
+    pub scale: f64,
+    // 0.0 (invisible) to 1.0; multiplies the image's own alpha
+    #[schemars(range(min = 0, max = 1))]
+    pub opacity: f64,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OverlayPosition {
+    TopLeft,
+    #[default]
+    TopRight,
+    BottomLeft,
+    BottomRight,
+    Center,
+}
+
+// Wall-clock text (drawtext) on the live output. The app sets the text of
+// every stream from its own clock at each second, so a wall of cameras shows
+// the same second instead of each FFmpeg's slightly drifting localtime.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(default)]
+pub struct ClockOverlayConfig {
+    // strftime-style format of the text (chrono syntax)
+    pub format: String,
+    pub position: OverlayPosition,
+    // Distance from the picture edges in pixels
+    pub margin: u32,
+    #[schemars(range(min = 8, max = 200))]
+    pub font_size: u32,
+    // Font for the text; None = FFmpeg's default font via fontconfig
+    pub font_file: Option<PathBuf>,
+}
+
+impl Default for ClockOverlayConfig {
+    fn default() -> Self {
+        Self {
+            format: "%Y-%m-%d %H:%M:%S".to_string(),
+            position: OverlayPosition::TopLeft,
+            margin: 10,
+            font_size: 24,
+            font_file: None,
+        }
+    }
+}
+
+impl Default for OverlayImageConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::new(),
+            position: OverlayPosition::TopRight,
+            margin: 10,
+            scale: 1.0,
+            opacity: 1.0,
+        }
+    }
+}
+
+// Thresholds for the blackdetect/freezedetect monitor
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(default)]
+pub struct QualityMonitorConfig {
+    // Ratio of black pixels for a picture to count as black (blackdetect pic_th)
+    #[schemars(range(min = 0, max = 1))]
+    pub black_ratio: f64,
+    // Seconds without change before the picture counts as frozen
+    pub freeze_duration: f64,
+    // Noise tolerance in dB for freeze detection (freezedetect n)
+    pub freeze_noise_db: f64,
+}
+
+impl Default for QualityMonitorConfig {
+    fn default() -> Self {
+        Self {
+            black_ratio: 0.98,
+            freeze_duration: 5.0,
+            freeze_noise_db: -60.0,
+        }
+    }
+}
+
+// Bitrate adaptation for a single dominant client, driven by its
+// push_client_feedback reports. The encode bitrate is the ceiling.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(default)]
+pub struct ClientAbrConfig {
+    #[schemars(range(min = 1))]
+    pub min_bitrate_kbps: u32,
+    // Lag behind the newest video above which the bitrate steps down
+    #[schemars(range(min = 1))]
+    pub max_lag_ms: u32,
+    // Lag at or below which a report counts as healthy
+    pub target_lag_ms: u32,
+    // Factor applied on each step down and step up
+    #[schemars(range(min = 0.1, max = 0.95))]
+    pub step_down: f64,
+    #[schemars(range(min = 1.05, max = 2.0))]
+    pub step_up: f64,
+    // Seconds of healthy reports before a step up
+    #[schemars(range(min = 1))]
+    pub up_after_secs: u64,
+    // Minimum seconds between changes; each one restarts FFmpeg
+    #[schemars(range(min = 1))]
+    pub cooldown_secs: u64,
+}
+
+impl Default for ClientAbrConfig {
+    fn default() -> Self {
+        Self {
+            min_bitrate_kbps: 250,
+            max_lag_ms: 1500,
+            target_lag_ms: 500,
+            step_down: 0.7,
+            step_up: 1.2,
+            up_after_secs: 30,
+            cooldown_secs: 10,
+        }
+    }
+}
+
+impl ClientAbrConfig {
+    pub fn validate(&self, bitrate_kbps: u32) -> Result<(), String> {
+        if self.min_bitrate_kbps == 0 || self.min_bitrate_kbps > bitrate_kbps {
+            return Err(format!(
+                "client_abr min_bitrate_kbps must be between 1 and the encode bitrate ({}), got {}",
+                bitrate_kbps, self.min_bitrate_kbps
+            ));
+        }
+        if self.max_lag_ms == 0 || self.target_lag_ms >= self.max_lag_ms {
+            return Err("client_abr target_lag_ms must be below max_lag_ms".to_string());
+        }
+        if !(0.1..=0.95).contains(&self.step_down) {
+            return Err(format!("client_abr step_down must be between 0.1 and 0.95, got {}", self.step_down));
+        }
+        if !(1.05..=2.0).contains(&self.step_up) {
+            return Err(format!("client_abr step_up must be between 1.05 and 2, got {}", self.step_up));
+        }
+        if self.up_after_secs == 0 || self.cooldown_secs == 0 {
+            return Err("client_abr up_after_secs and cooldown_secs must be greater than 0".to_string());
+        }
+        Ok(())
+    }
+}
+
+// A crashing FFmpeg (killed by a signal or an exception) is restarted after a
+// short fixed delay, without the source reconnect backoff. A stream whose
+// FFmpeg keeps crashing, typically a broken build or input it can't decode,
+// is stopped instead of restarted forever.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(default)]
+pub struct CrashRestartConfig {
+    // Crashes within window_secs that stop the stream
+    #[schemars(range(min = 1))]
+    pub max_crashes: u32,
+    #[schemars(range(min = 1))]
+    pub window_secs: u64,
+    #[schemars(range(max = 60000))]
+    pub restart_delay_ms: u64,
+}
+
+impl Default for CrashRestartConfig {
+    fn default() -> Self {
+        Self {
+            max_crashes: 5,
+            window_secs: 300,
+            restart_delay_ms: 1000,
+        }
+    }
+}
+
+impl CrashRestartConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_crashes == 0 || self.window_secs == 0 {
+            return Err("crash_restart max_crashes and window_secs must be greater than 0".to_string());
+        }
+        if self.restart_delay_ms > 60_000 {
+            return Err(format!(
+                "crash_restart restart_delay_ms must be at most 60000, got {}",
+                self.restart_delay_ms
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl StreamOptions {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(format) = &self.input_format {
+            if format.is_empty() || !format.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(format!("Invalid input format '{}'", format));
+            }
+        }
+        for flag in self.rtsp_flags.iter().flatten() {
+            if !RTSP_FLAGS.contains(&flag.as_str()) {
+                return Err(format!(
+                    "Unsupported RTSP flag '{}' (supported: {})",
+                    flag,
+                    RTSP_FLAGS.join(", ")
+                ));
+            }
+        }
+        self.encode.validate()?;
+        if self.keyframe_markers && self.encode.codec != OutputCodec::Mpeg1 {
+            return Err(
+                "keyframe_markers requires the mpeg1 codec; H.264 fragments always start at a keyframe"
+                    .to_string(),
+            );
+        }
+        self.buffer.validate()?;
+        self.socket.validate()?;
+        self.metrics.validate()?;
+        if let Some(shadow) = &self.shadow_buffer {
+            shadow.validate()?;
+        }
+        for protocol in &self.subprotocols {
+            // RFC 6455 subprotocols are HTTP tokens
+            let is_token = !protocol.is_empty()
+                && protocol.chars().all(|c| c.is_ascii_graphic() && !"()<>@,;:\\\"/[]?={}".contains(c));
+            if !is_token {
+                return Err(format!("Invalid WebSocket subprotocol '{}'", protocol));
+            }
+        }
+        if let Some(recording) = &self.recording_config {
+            recording.validate()?;
+        }
+        if self.sources.iter().any(|source| source.trim().is_empty()) {
+            return Err("Additional sources must not be empty".to_string());
+        }
+        match &self.filter_complex {
+            Some(graph) => validate_filter_complex(graph, 1 + self.sources.len())?,
+            None if !self.sources.is_empty() => {
+                return Err("Additional sources require a filter_complex graph".to_string());
+            }
+            None => {}
+        }
+        if (self.color_range.is_some() || self.color_space.is_some()) && self.filter_complex.is_some() {
+            return Err("color_range/color_space cannot be combined with filter_complex; add setparams to the graph instead".to_string());
+        }
+        if self.fix_aspect && self.filter_complex.is_some() {
+            return Err("fix_aspect cannot be combined with filter_complex; add setsar to the graph instead".to_string());
+        }
+        if let Some(overlay) = &self.image_overlay {
+            if self.filter_complex.is_some() {
+                return Err("image_overlay cannot be combined with filter_complex".to_string());
+            }
+            overlay.validate()?;
+        }
+        if let Some(clock) = &self.clock_overlay {
+            if self.filter_complex.is_some() {
+                return Err("clock_overlay cannot be combined with filter_complex; add drawtext to the graph instead".to_string());
+            }
+            clock.validate()?;
+        }
+        if let Some(monitor) = &self.quality_monitor {
+            monitor.validate()?;
+        }
+        if let Some(abr) = &self.client_abr {
+            abr.validate(self.encode.bitrate_kbps)?;
+        }
+        self.crash_restart.validate()?;
+        if self.verify_before_start && !(1_000..=60_000).contains(&self.verify_timeout_ms) {
+            return Err(format!(
+                "verify_timeout_ms must be between 1000 and 60000, got {}",
+                self.verify_timeout_ms
+            ));
+        }
+        if self.input_timeout_ms > 300_000 {
+            return Err(format!("input_timeout_ms must be at most 300000, got {}", self.input_timeout_ms));
+        }
+        if self.graceful_stop_timeout_ms > 60_000 {
+            return Err(format!(
+                "graceful_stop_timeout_ms must be at most 60000, got {}",
+                self.graceful_stop_timeout_ms
+            ));
+        }
+        if self.max_frames == Some(0) {
+            return Err("max_frames must be greater than 0".to_string());
+        }
+        if self.max_frames.is_some() && self.quality_monitor.is_some() {
+            // The monitor's downsampled output would outlast the live output
+            return Err("max_frames cannot be combined with quality_monitor".to_string());
+        }
+        if self.dynamic_url && self.dynamic_url_timeout_ms == 0 {
+            return Err("Dynamic URL timeout must be greater than 0".to_string());
+        }
+        if self.webrtc {
+            if !cfg!(feature = "webrtc") {
+                return Err("WebRTC output is not available in this build".to_string());
+            }
+            if self.filter_complex.is_some() || self.image_overlay.is_some() {
+                return Err("WebRTC output does not support filter_complex or image_overlay".to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl EncodeConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        parse_resolution(&self.resolution)?;
+        if self.bitrate_kbps == 0 {
+            return Err("Bitrate must be greater than 0".to_string());
+        }
+        if self.framerate == 0 || self.framerate > 120 {
+            return Err(format!("Invalid frame rate: {}", self.framerate));
+        }
+        if !(1..=31).contains(&self.quality) {
+            return Err(format!("Quality must be between 1 and 31, got {}", self.quality));
+        }
+        if self.gop == Some(0) {
+            return Err("GOP size must be greater than 0".to_string());
+        }
+        match (self.maxrate_kbps, self.bufsize_kbps) {
+            (Some(maxrate), _) if maxrate < self.bitrate_kbps => {
+                return Err(format!(
+                    "Max rate ({}k) must be at least the bitrate ({}k)",
+                    maxrate, self.bitrate_kbps
+                ));
+            }
+            (Some(_), None) => {
+                return Err("Buffer size is required when max rate is set".to_string());
+            }
+            (None, Some(_)) => {
+                return Err("Buffer size requires max rate to be set".to_string());
+            }
+            (_, Some(0)) => {
+                return Err("Buffer size must be greater than 0".to_string());
+            }
+            _ => {}
+        }
+        if self.jsmpeg_tuning.is_some() && self.codec != OutputCodec::Mpeg1 {
+            return Err("jsmpeg_tuning only applies to the mpeg1 codec".to_string());
+        }
+        if self.av_sync_mode.is_some() && self.codec != OutputCodec::Mpeg1 {
+            return Err("av_sync_mode only applies to the mpeg1 codec".to_string());
+        }
+        Ok(())
+    }
+
+    // Effective (maxrate, bufsize) in kbps: the explicit values, else the
+    // jsmpeg tuning preset
+    pub fn rate_limits(&self) -> Option<(u32, u32)> {
+        if let (Some(maxrate), Some(bufsize)) = (self.maxrate_kbps, self.bufsize_kbps) {
+            return Some((maxrate, bufsize));
+        }
+        match self.jsmpeg_tuning? {
+            JsmpegTuning::LowLatency => Some((self.bitrate_kbps, (self.bitrate_kbps / 2).max(1))),
+            JsmpegTuning::Smooth => Some((self.bitrate_kbps * 3 / 2, self.bitrate_kbps)),
+        }
+    }
+
+    // Lower the bitrate to `kbps` (a degraded or adapted stream) and make it
+    // a ceiling. MPEG1 without rate limits encodes at a fixed quantizer that
+    // ignores the bitrate, so the cap always brings maxrate/bufsize along.
+    pub fn cap_bitrate(&mut self, kbps: u32) {
+        self.bitrate_kbps = kbps;
+        let (maxrate, bufsize) = self.rate_limits().unwrap_or((kbps, kbps));
+        self.maxrate_kbps = Some(maxrate.min(kbps));
+        self.bufsize_kbps = Some(bufsize.min(kbps));
+    }
+
+    // Client-side videoBufferSize (bytes) that holds the largest burst the
+    // encoder can produce: twice the VBV buffer, or two seconds of video when
+    // the bitrate is uncapped. Never below jsmpeg's default.
+    pub fn jsmpeg_video_buffer_size(&self) -> usize {
+        let kbits = match self.rate_limits() {
+            Some((_, bufsize)) => bufsize,
+            None => self.bitrate_kbps,
+        };
+        (kbits as usize * 1000 / 8 * 2).max(JSMPEG_DEFAULT_BUFFER_BYTES)
+    }
+
+    pub fn rate_control(&self) -> RateControl {
+        let limits = self.rate_limits();
+        RateControl {
+            mode: if limits.is_some() { "capped" } else { "vbr" }.to_string(),
+            bitrate_kbps: self.bitrate_kbps,
+            maxrate_kbps: limits.map(|(maxrate, _)| maxrate),
+            bufsize_kbps: limits.map(|(_, bufsize)| bufsize),
+        }
+    }
+}
+
+impl QualityMonitorConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(0.0..=1.0).contains(&self.black_ratio) {
+            return Err(format!("Black ratio must be between 0 and 1, got {}", self.black_ratio));
+        }
+        if self.freeze_duration <= 0.0 {
+            return Err("Freeze duration must be greater than 0".to_string());
+        }
+        if self.freeze_noise_db >= 0.0 {
+            return Err("Freeze noise tolerance must be negative (dB)".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl OverlayImageConfig {
+    // Decoding is checked separately with FFmpeg (ffmpeg::check_image_decodes)
+    pub fn validate(&self) -> Result<(), String> {
+        if self.path.as_os_str().is_empty() {
+            return Err("Overlay image path is required".to_string());
+        }
+        if !self.path.is_file() {
+            return Err(format!("Overlay image not found: {}", self.path.display()));
+        }
+        if !(self.scale > 0.0 && self.scale <= 10.0) {
+            return Err(format!("Overlay scale must be between 0 and 10, got {}", self.scale));
+        }
+        if !(0.0..=1.0).contains(&self.opacity) {
+            return Err(format!("Overlay opacity must be between 0 and 1, got {}", self.opacity));
+        }
+        Ok(())
+    }
+}
+
+impl ClockOverlayConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        let invalid = chrono::format::StrftimeItems::new(&self.format)
+            .any(|item| matches!(item, chrono::format::Item::Error));
+        if self.format.trim().is_empty() || invalid {
+            return Err(format!("Invalid clock format '{}'", self.format));
+        }
+        // Quotes and line breaks can't be passed through the filter command
+        if self.format.contains(['\'', '\n', '\r']) || self.format.contains("%n") {
+            return Err("Clock format must not contain quotes or line breaks".to_string());
+        }
+        if !(8..=200).contains(&self.font_size) {
+            return Err(format!("Clock font size must be between 8 and 200, got {}", self.font_size));
+        }
+        if let Some(font) = &self.font_file {
+            if !font.is_file() {
+                return Err(format!("Clock font not found: {}", font.display()));
+            }
+            if font.to_string_lossy().contains('\'') {
+                return Err("Clock font path must not contain quotes".to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl RecordingConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.output_path.as_os_str().is_empty() {
+            return Err("Recording output path is required".to_string());
+        }
+        if let Some(parent) = self.output_path.parent() {
+            if !parent.as_os_str().is_empty() && !parent.is_dir() {
+                return Err(format!("Recording directory does not exist: {}", parent.display()));
+            }
+        }
+        if self.codec.is_empty() {
+            return Err("Recording codec is required".to_string());
+        }
+        if self.bitrate_kbps == 0 {
+            return Err("Recording bitrate must be greater than 0".to_string());
+        }
+        if self.gop == 0 {
+            return Err("Recording GOP size must be greater than 0".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl HlsConfig {
+    // Also rejects stream options the HLS output doesn't implement
+    pub fn validate(&self, options: &StreamOptions) -> Result<(), String> {
+        if !(1..=60).contains(&self.segment_seconds) {
+            return Err(format!(
+                "Segment duration must be between 1 and 60 seconds, got {}",
+                self.segment_seconds
+            ));
+        }
+        if self.playlist_size < 2 {
+            return Err("HLS playlist must hold at least 2 segments".to_string());
+        }
+        let unsupported = [
+            ("recording_config", options.recording_config.is_some()),
+            ("shadow_buffer", options.shadow_buffer.is_some()),
+            ("pipe_output", options.pipe_output.is_some()),
+            ("share_source", options.share_source),
+            ("sources", !options.sources.is_empty()),
+            ("filter_complex", options.filter_complex.is_some()),
+            ("image_overlay", options.image_overlay.is_some()),
+            ("clock_overlay", options.clock_overlay.is_some()),
+            ("quality_monitor", options.quality_monitor.is_some()),
+            ("webrtc", options.webrtc),
+            ("dynamic_url", options.dynamic_url),
+            ("max_frames", options.max_frames.is_some()),
+            ("debug_timestamps", options.debug_timestamps),
+            ("client_abr", options.client_abr.is_some()),
+            ("keyframe_markers", options.keyframe_markers),
+            // Only explicit maxrate_kbps/bufsize_kbps are applied to HLS
+            ("encode.jsmpeg_tuning", options.encode.jsmpeg_tuning.is_some()),
+            ("encode.av_sync_mode", options.encode.av_sync_mode.is_some()),
+            ("encode.copy_when_compatible", options.encode.copy_when_compatible),
+        ];
+        if let Some((name, _)) = unsupported.iter().find(|(_, set)| *set) {
+            return Err(format!("{} is not supported for HLS streams", name));
+        }
+        Ok(())
+    }
+}
+
+// Parse a "WIDTHxHEIGHT" string
+pub fn parse_resolution(resolution: &str) -> Result<(u32, u32), String> {
+    let invalid = || format!("Invalid resolution '{}', expected WIDTHxHEIGHT", resolution);
+    let (w, h) = resolution.split_once('x').ok_or_else(invalid)?;
+    let width: u32 = w.trim().parse().map_err(|_| invalid())?;
+    let height: u32 = h.trim().parse().map_err(|_| invalid())?;
+    if width == 0 || height == 0 {
+        return Err(invalid());
+    }
+    Ok((width, height))
+}
+
+// Loose syntax check for a complex filtergraph: brackets must pair up, labels
+// must be non-empty and input references like [1:v] must name an existing
+// input. Anything deeper is left to FFmpeg, whose errors end up in the stream
+// log.
+pub fn validate_filter_complex(graph: &str, input_count: usize) -> Result<(), String> {
+    if graph.trim().is_empty() {
+        return Err("filter_complex must not be empty".to_string());
+    }
+
+    let mut rest = graph;
+    while let Some(open) = rest.find(['[', ']']) {
+        if rest[open..].starts_with(']') {
+            return Err(format!("Unmatched ']' in filter_complex: {}", graph));
+        }
+        let after = &rest[open + 1..];
+        let close = after
+            .find(['[', ']'])
+            .filter(|i| after[*i..].starts_with(']'))
+            .ok_or_else(|| format!("Unclosed '[' in filter_complex: {}", graph))?;
+        let label = &after[..close];
+        if label.is_empty() {
+            return Err("Empty label '[]' in filter_complex".to_string());
+        }
+        if !label.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | ':' | '.' | '-')) {
+            return Err(format!("Invalid label '[{}]' in filter_complex", label));
+        }
+
+        // Stream specifiers start with the input index, e.g. [0:v] or [1]
+        let index = label.split(':').next().unwrap_or_default();
+        if let Ok(index) = index.parse::<usize>() {
+            if index >= input_count {
+                return Err(format!(
+                    "filter_complex references input {} but only {} input(s) are configured",
+                    index, input_count
+                ));
+            }
+        }
+
+        rest = &after[close + 1..];
+    }
+
+    // The graph's output is labeled by the app so each output can map it
+    if graph.trim_end().ends_with(']') {
+        return Err("filter_complex must end with its unlabeled output, not a label".to_string());
+    }
+
+    Ok(())
+}
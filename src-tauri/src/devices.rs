@@ -0,0 +1,170 @@
+use serde::{Deserialize, Serialize};
+
+// A local webcam/capture card/microphone. `input` and `format` can be passed
+// to start_stream as the source URL and `input_format` option.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CaptureDevice {
+    pub name: String,
+    // "video" or "audio"
+    pub kind: String,
+    pub index: Option<u32>,
+    // FFmpeg input device format: dshow, avfoundation or v4l2
+    pub format: String,
+    pub input: String,
+}
+
+// Enumerate capture devices using the platform's FFmpeg input device
+pub fn list_capture_devices() -> Result<Vec<CaptureDevice>, String> {
+    #[cfg(target_os = "windows")]
+    {
+        let stderr = list_devices_output(&["-f", "dshow", "-list_devices", "true", "-i", "dummy"])?;
+        Ok(parse_dshow_devices(&stderr))
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        let stderr = list_devices_output(&["-f", "avfoundation", "-list_devices", "true", "-i", ""])?;
+        Ok(parse_avfoundation_devices(&stderr))
+    }
+
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    {
+        Ok(scan_v4l2_devices())
+    }
+}
+
+// FFmpeg prints the device list to stderr and then fails on the dummy input,
+// so the exit status is ignored
+#[cfg(any(target_os = "windows", target_os = "macos"))]
+fn list_devices_output(args: &[&str]) -> Result<String, String> {
+    let output = crate::ffmpeg::ffmpeg_command()
+        .arg("-hide_banner")
+        .args(args)
+        .output()
+        .map_err(|e| format!("Failed to run FFmpeg: {}", e))?;
+    Ok(String::from_utf8_lossy(&output.stderr).to_string())
+}
+
+// Parse `-f dshow -list_devices true`. Newer FFmpeg tags each device with
+// "(video)"/"(audio)"; older builds print section headers instead.
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+fn parse_dshow_devices(stderr: &str) -> Vec<CaptureDevice> {
+    let mut devices = Vec::new();
+    let mut section = "video";
+
+    for line in stderr.lines() {
+        let text = strip_log_prefix(line);
+        if text.contains("DirectShow video devices") {
+            section = "video";
+            continue;
+        }
+        if text.contains("DirectShow audio devices") {
+            section = "audio";
+            continue;
+        }
+        if text.starts_with("Alternative name") {
+            continue;
+        }
+
+        let Some(name) = quoted(text) else { continue };
+        let kind = if text.ends_with("(audio)") {
+            "audio"
+        } else if text.ends_with("(video)") {
+            "video"
+        } else {
+            section
+        };
+        devices.push(CaptureDevice {
+            name: name.to_string(),
+            kind: kind.to_string(),
+            index: None,
+            format: "dshow".to_string(),
+            input: format!("{}={}", kind, name),
+        });
+    }
+
+    devices
+}
+
+// Parse `-f avfoundation -list_devices true`, which lists "[N] Name" entries
+// under video and audio headers
+#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+fn parse_avfoundation_devices(stderr: &str) -> Vec<CaptureDevice> {
+    let mut devices = Vec::new();
+    let mut section = None;
+
+    for line in stderr.lines() {
+        let text = strip_log_prefix(line);
+        if text.contains("AVFoundation video devices") {
+            section = Some("video");
+            continue;
+        }
+        if text.contains("AVFoundation audio devices") {
+            section = Some("audio");
+            continue;
+        }
+
+        let Some(kind) = section else { continue };
+        let Some((index, name)) = text
+            .strip_prefix('[')
+            .and_then(|rest| rest.split_once(']'))
+            .and_then(|(index, name)| Some((index.parse::<u32>().ok()?, name.trim())))
+        else {
+            continue;
+        };
+        devices.push(CaptureDevice {
+            name: name.to_string(),
+            kind: kind.to_string(),
+            index: Some(index),
+            format: "avfoundation".to_string(),
+            // avfoundation inputs are "video:audio" index pairs
+            input: if kind == "video" { format!("{}:none", index) } else { format!("none:{}", index) },
+        });
+    }
+
+    devices
+}
+
+// List /dev/video* nodes, named from sysfs when available
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+fn scan_v4l2_devices() -> Vec<CaptureDevice> {
+    let Ok(entries) = std::fs::read_dir("/dev") else {
+        return Vec::new();
+    };
+
+    let mut devices: Vec<CaptureDevice> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let node = entry.file_name().to_string_lossy().to_string();
+            let index: u32 = node.strip_prefix("video")?.parse().ok()?;
+            let name = std::fs::read_to_string(format!("/sys/class/video4linux/{}/name", node))
+                .map(|name| name.trim().to_string())
+                .unwrap_or_else(|_| node.clone());
+            Some(CaptureDevice {
+                name,
+                kind: "video".to_string(),
+                index: Some(index),
+                format: "v4l2".to_string(),
+                input: format!("/dev/{}", node),
+            })
+        })
+        .collect();
+
+    devices.sort_by_key(|device| device.index);
+    devices
+}
+
+// Drop FFmpeg's "[dshow @ 0x...] " log context prefix
+fn strip_log_prefix(line: &str) -> &str {
+    let line = line.trim();
+    match line.strip_prefix('[').and_then(|rest| rest.split_once("] ")) {
+        Some((context, rest)) if context.contains(" @ ") => rest.trim(),
+        _ => line,
+    }
+}
+
+fn quoted(text: &str) -> Option<&str> {
+    let start = text.find('"')? + 1;
+    let end = start + text[start..].find('"')?;
+    Some(&text[start..end])
+}
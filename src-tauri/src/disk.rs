@@ -0,0 +1,121 @@
+use crate::config::RecordingConfig;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// How often free space is re-checked while recording
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+// Recording stops once less than this much recording time fits on the disk,
+// leaving room for the muxer to finish and for the rest of the system
+const RESERVE_SECONDS: u64 = 120;
+
+// Estimated recording size per second: the video bitrate plus ~5% container
+// overhead (audio is not recorded)
+pub fn bytes_per_second(recording: &RecordingConfig) -> u64 {
+    recording.bitrate_kbps as u64 * 1000 / 8 * 105 / 100
+}
+
+// Free space on the volume the recording is written to
+pub fn available_space(recording: &RecordingConfig) -> Result<u64, String> {
+    let dir = match recording.output_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    fs2::available_space(dir)
+        .map_err(|e| format!("Failed to read free space for {}: {}", dir.display(), e))
+}
+
+// Check free space before a recording starts. Fails if the minimum retention
+// window doesn't fit; returns warnings if only the expected duration doesn't.
+pub fn preflight(recording: &RecordingConfig) -> Result<Vec<String>, String> {
+    let available = available_space(recording)?;
+    let rate = bytes_per_second(recording);
+
+    let required = rate * recording.min_free_minutes as u64 * 60;
+    if available < required {
+        return Err(format!(
+            "Not enough disk space to record: {} free, {} needed for {} minutes at {}k",
+            format_bytes(available),
+            format_bytes(required),
+            recording.min_free_minutes,
+            recording.bitrate_kbps
+        ));
+    }
+
+    let mut warnings = Vec::new();
+    if let Some(minutes) = recording.expected_duration_minutes {
+        let expected = rate * minutes as u64 * 60;
+        if available < expected {
+            warnings.push(format!(
+                "Disk space for about {} of the expected {} minutes of recording ({} free)",
+                format_minutes(available / rate.max(1)),
+                minutes,
+                format_bytes(available)
+            ));
+        }
+    }
+    Ok(warnings)
+}
+
+// File a new FFmpeg run records to: the configured path, or, if a file is
+// already there (e.g. from an earlier run of the stream), the same name with
+// the start time appended. FFmpeg runs with -n, so nothing is overwritten.
+pub fn recording_file(path: &Path) -> PathBuf {
+    if !path.exists() {
+        return path.to_path_buf();
+    }
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path
+        .extension()
+        .map(|ext| format!(".{}", ext.to_string_lossy()))
+        .unwrap_or_default();
+    let started = chrono::Local::now().format("%Y%m%d-%H%M%S");
+    (1..)
+        .map(|n| match n {
+            1 => path.with_file_name(format!("{}-{}{}", stem, started, extension)),
+            n => path.with_file_name(format!("{}-{}-{}{}", stem, started, n, extension)),
+        })
+        .find(|candidate| !candidate.exists())
+        .unwrap()
+}
+
+// Whether the recording must stop before the disk fills
+pub fn is_low(recording: &RecordingConfig, available: u64) -> bool {
+    available < bytes_per_second(recording) * RESERVE_SECONDS
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const GB: f64 = 1024.0 * 1024.0 * 1024.0;
+    const MB: f64 = 1024.0 * 1024.0;
+    if bytes as f64 >= GB {
+        format!("{:.1} GB", bytes as f64 / GB)
+    } else {
+        format!("{:.0} MB", bytes as f64 / MB)
+    }
+}
+
+fn format_minutes(seconds: u64) -> String {
+    format!("{} minutes", seconds / 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn existing_recordings_are_not_reused() {
+        let dir = std::env::temp_dir().join(format!("eroxii-disk-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("cam1.mp4");
+        assert_eq!(recording_file(&path), path);
+
+        std::fs::write(&path, b"").unwrap();
+        let first = recording_file(&path);
+        let name = first.file_name().unwrap().to_string_lossy().to_string();
+        assert!(name.starts_with("cam1-") && name.ends_with(".mp4"), "{}", name);
+
+        std::fs::write(&first, b"").unwrap();
+        let second = recording_file(&path);
+        assert!(second != first && !second.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
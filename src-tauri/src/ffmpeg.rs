@@ -0,0 +1,989 @@
+use crate::config::{
+    ClockOverlayConfig, EncodeConfig, HlsConfig, OutputCodec, OverlayImageConfig, OverlayPosition, QualityMonitorConfig,
+    RecordingConfig, RtspPreset, ShadowConfig, StreamOptions,
+};
+use crate::probe::MediaInfo;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::process::Command;
+use std::sync::RwLock;
+use std::time::Duration;
+
+// URL schemes a user can enter as a stream source
+const SOURCE_SCHEMES: [&str; 7] = ["rtsp", "rtsps", "http", "https", "srt", "rtmp", "file"];
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SupportedProtocols {
+    // Raw input/output protocol lists from `ffmpeg -protocols`
+    pub input: Vec<String>,
+    pub output: Vec<String>,
+    // Source URL schemes usable with the installed FFmpeg
+    pub source_schemes: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Encoder {
+    pub name: String,
+    // "video", "audio" or "subtitle"
+    pub media_type: String,
+    pub description: String,
+}
+
+// Lists queried from the FFmpeg binary, kept until clear_capability_cache
+static PROTOCOLS: RwLock<Option<SupportedProtocols>> = RwLock::new(None);
+static ENCODERS: RwLock<Option<Vec<Encoder>>> = RwLock::new(None);
+static HWACCELS: RwLock<Option<Vec<String>>> = RwLock::new(None);
+
+// Build the FFmpeg argument list for a stream. The live output always goes to
+// stdout; an optional recording is added as a second output so both share a
+// single camera connection and decode. `copy_video` stream-copies the live
+// output instead of re-encoding (see can_copy_video). `rtp_port` adds an H.264
+// RTP output to that local UDP port for the WHEP endpoint.
+pub fn build_ffmpeg_args(
+    rtsp_url: &str,
+    options: &StreamOptions,
+    copy_video: bool,
+    rtp_port: Option<u16>,
+) -> Vec<String> {
+    let mut args: Vec<String> = Vec::new();
+
+    if options.debug_timestamps {
+        args.push("-debug_ts".into()); // Print every packet's timestamps
+    }
+    if options.recording_config.is_some() || options.shadow_buffer.is_some() {
+        args.push("-n".into()); // Never overwrite a file, nor prompt on stdin
+    }
+
+    for (index, input) in std::iter::once(rtsp_url).chain(options.sources.iter().map(String::as_str)).enumerate() {
+        if let (0, Some(format)) = (index, &options.input_format) {
+            args.extend(["-f".to_string(), format.clone()]); // Capture device input
+        }
+        args.extend(source_input_args(input, options));
+        if read_realtime(input, options, index == 0) {
+            args.push("-re".to_string()); // Pace file inputs to playback speed
+        }
+        args.extend([
+            "-fflags", "nobuffer",         // Reduce buffering
+            "-flags", "low_delay",         // Low delay mode
+            "-i", input,                   // Input URL
+        ].map(String::from));
+    }
+
+    if let Some(overlay) = &options.image_overlay {
+        // A still image; overlay keeps repeating its only frame
+        args.extend(["-i".to_string(), overlay.path.to_string_lossy().to_string()]);
+    }
+
+    let color = color_filter(options);
+    let base = base_filter(options);
+    let graph = if let Some(graph) = &options.filter_complex {
+        Some(graph.clone())
+    } else if let Some(overlay) = &options.image_overlay {
+        let image_input = 1 + options.sources.len();
+        Some(image_overlay_filter(overlay, image_input, base.as_deref()))
+    } else {
+        if let Some(base) = base {
+            args.extend(["-vf".to_string(), base]);
+        }
+        None
+    };
+    // The graph's output is labeled so the recording maps the same picture as
+    // the live output instead of whichever input stream FFmpeg would pick;
+    // split feeds both from one filter run
+    let labeled = graph.is_some();
+    if let Some(graph) = graph {
+        let outputs = if options.recording_config.is_some() { ",split=2[live][rec]" } else { "[live]" };
+        args.extend(["-filter_complex".to_string(), format!("{}{}", graph, outputs)]);
+    }
+    let map = |label: &str, mut output: Vec<String>| {
+        if labeled {
+            output.splice(0..0, ["-map".to_string(), format!("[{}]", label)]);
+        }
+        output
+    };
+    if color.is_some() && options.encode.codec == OutputCodec::H264 {
+        // Tag the output so MSE players decode it with the matrix used above
+        args.extend(["-colorspace", "bt709", "-color_range", "tv"].map(String::from));
+    }
+
+    // max_frames ends every output, so FFmpeg exits (finalizing the
+    // recording) once the live output is done
+    let frames = |mut output: Vec<String>| {
+        if let Some(max_frames) = options.max_frames {
+            let url = output.len() - 1;
+            output.splice(url..url, ["-frames:v".to_string(), max_frames.to_string()]);
+        }
+        output
+    };
+
+    args.extend(frames(map("live", live_output_args(&options.encode, copy_video))));
+
+    if let Some(recording) = &options.recording_config {
+        args.extend(frames(map("rec", recording_output_args(recording))));
+    }
+
+    if let Some(shadow) = &options.shadow_buffer {
+        args.extend(frames(shadow_output_args(shadow, options.input_format.is_none())));
+    }
+
+    if let Some(monitor) = &options.quality_monitor {
+        args.extend(quality_monitor_args(monitor));
+    }
+
+    if let Some(port) = rtp_port {
+        args.extend(frames(webrtc_output_args(&options.encode, copy_video, port)));
+    }
+
+    args
+}
+
+// Build the FFmpeg argument list for an HLS stream: H.264 segments and a
+// rolling playlist written to `dir`. Keyframes are forced at every segment
+// boundary so segments start independently and have a steady length.
+pub fn build_hls_args(rtsp_url: &str, options: &StreamOptions, hls: &HlsConfig, dir: &Path) -> Vec<String> {
+    let mut args: Vec<String> = Vec::new();
+
+    if let Some(format) = &options.input_format {
+        args.extend(["-f".to_string(), format.clone()]);
+    }
+    args.extend(source_input_args(rtsp_url, options));
+    if read_realtime(rtsp_url, options, true) {
+        args.push("-re".to_string());
+    }
+    args.extend(["-i".to_string(), rtsp_url.to_string()]);
+
+    if let Some(base) = base_filter(options) {
+        args.extend(["-vf".to_string(), base]);
+    }
+    if color_filter(options).is_some() {
+        args.extend(["-colorspace", "bt709", "-color_range", "tv"].map(String::from));
+    }
+
+    let encode = &options.encode;
+    let gop = encode.framerate * hls.segment_seconds;
+    args.extend([
+        "-map".to_string(), "0:v:0".to_string(),
+        "-an".to_string(),
+        "-c:v".to_string(), "libx264".to_string(),
+        "-preset".to_string(), "veryfast".to_string(),
+        "-tune".to_string(), "zerolatency".to_string(),
+        "-pix_fmt".to_string(), "yuv420p".to_string(),
+        "-s".to_string(), encode.resolution.clone(),
+        "-b:v".to_string(), format!("{}k", encode.bitrate_kbps),
+        "-r".to_string(), encode.framerate.to_string(),
+        "-g".to_string(), gop.to_string(),
+        "-keyint_min".to_string(), gop.to_string(),
+        "-sc_threshold".to_string(), "0".to_string(), // No extra keyframes on scene cuts
+    ]);
+    if let (Some(maxrate), Some(bufsize)) = (encode.maxrate_kbps, encode.bufsize_kbps) {
+        args.extend([
+            "-maxrate".to_string(), format!("{}k", maxrate),
+            "-bufsize".to_string(), format!("{}k", bufsize),
+        ]);
+    }
+
+    args.extend([
+        "-f".to_string(), "hls".to_string(),
+        "-hls_time".to_string(), hls.segment_seconds.to_string(),
+        "-hls_list_size".to_string(), hls.playlist_size.to_string(),
+        "-hls_flags".to_string(), "delete_segments+independent_segments".to_string(),
+        "-hls_segment_filename".to_string(),
+        dir.join(crate::hls::SEGMENT_PATTERN).to_string_lossy().to_string(),
+        dir.join(crate::hls::PLAYLIST).to_string_lossy().to_string(),
+    ]);
+    args
+}
+
+// Whether an input is read at its native frame rate. Live sources (network
+// streams, capture devices) already arrive in real time; a local file would
+// otherwise be transcoded as fast as possible and relayed as a fast-forward
+// burst.
+fn read_realtime(input: &str, options: &StreamOptions, is_main_input: bool) -> bool {
+    options.realtime.unwrap_or_else(|| is_file_input(input, options, is_main_input))
+}
+
+// Whether an input is a local file (a plain path or a file: URL) rather than
+// a network stream or a capture device
+pub fn is_file_input(input: &str, options: &StreamOptions, is_main_input: bool) -> bool {
+    let is_device = is_main_input && options.input_format.is_some();
+    !is_device && (input.starts_with("file:") || !input.contains("://"))
+}
+
+// Whether a URL is a network source FFmpeg can open
+pub fn is_network_source(url: &str) -> bool {
+    url.split_once("://").is_some_and(|(scheme, _)| {
+        let scheme = scheme.to_ascii_lowercase();
+        scheme != "file" && SOURCE_SCHEMES.contains(&scheme.as_str())
+    })
+}
+
+// Options for opening a source: the RTSP transport and flags, and the socket
+// timeout. Probes and snapshots open the source the same way as the stream.
+pub fn source_input_args(input: &str, options: &StreamOptions) -> Vec<String> {
+    let mut args = Vec::new();
+    if input.starts_with("rtsp://") || input.starts_with("rtsps://") {
+        args.extend(rtsp_input_args(options));
+    }
+    args.extend(input_timeout_args(input, options));
+    args
+}
+
+// RTSP demuxer options from the stream's preset and extra flags
+fn rtsp_input_args(options: &StreamOptions) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut flags: Vec<&str> = options.rtsp_flags.iter().flatten().map(String::as_str).collect();
+
+    match options.rtsp_preset {
+        RtspPreset::Tcp => args.extend(["-rtsp_transport", "tcp"]), // Use TCP for RTSP (more reliable)
+        RtspPreset::PreferTcp => {
+            if !flags.contains(&"prefer_tcp") {
+                flags.push("prefer_tcp");
+            }
+        }
+        RtspPreset::Udp => args.extend(["-rtsp_transport", "udp"]),
+        RtspPreset::Http => args.extend(["-rtsp_transport", "http"]),
+    }
+
+    let mut args: Vec<String> = args.into_iter().map(String::from).collect();
+    if !flags.is_empty() {
+        let flags: String = flags.iter().map(|flag| format!("+{}", flag)).collect();
+        args.extend(["-rtsp_flags".to_string(), flags]);
+    }
+    args
+}
+
+// Socket timeout for a network input, in microseconds. Without it FFmpeg
+// waits forever on a camera that stops sending but keeps the connection
+// open, and the reconnect loop never starts. The RTSP demuxer takes -timeout
+// (FFmpeg 5 and later), other protocols the generic -rw_timeout.
+fn input_timeout_args(input: &str, options: &StreamOptions) -> Vec<String> {
+    if options.input_timeout_ms == 0 || !is_network_source(input) {
+        return Vec::new();
+    }
+    let rtsp = input.starts_with("rtsp://") || input.starts_with("rtsps://");
+    let flag = if rtsp { "-timeout" } else { "-rw_timeout" };
+    vec![flag.to_string(), (options.input_timeout_ms * 1000).to_string()]
+}
+
+// Arguments for the live output on stdout
+fn live_output_args(encode: &EncodeConfig, copy_video: bool) -> Vec<String> {
+    let mut args: Vec<String> = Vec::new();
+
+    match (encode.codec, copy_video) {
+        (OutputCodec::Mpeg1, false) => {
+            let bitrate = format!("{}k", encode.bitrate_kbps);
+            let framerate = encode.framerate.to_string();
+            args.extend([
+                "-f", "mpegts",                 // Output format: MPEG-TS
+                "-codec:v", "mpeg1video",       // Video codec for jsmpeg
+                "-s", encode.resolution.as_str(), // Resolution
+                "-b:v", &bitrate,               // Video bitrate
+                "-bf", "0",                     // No B-frames (lower latency)
+            ].map(String::from));
+            match encode.rate_limits() {
+                Some((maxrate, bufsize)) => {
+                    // A fixed quantizer (-q:v) disables rate control, so it is only
+                    // used when no ceiling is requested
+                    args.extend([
+                        "-maxrate".to_string(), format!("{}k", maxrate),
+                        "-bufsize".to_string(), format!("{}k", bufsize),
+                    ]);
+                }
+                _ => args.extend(["-q:v".to_string(), encode.quality.to_string()]), // Quality level
+            }
+            args.extend(["-r".to_string(), framerate]); // Frame rate
+            if let Some(gop) = encode.gop {
+                args.extend(["-g".to_string(), gop.to_string()]);
+            }
+        }
+        (OutputCodec::H264, false) => {
+            let gop = encode.gop.unwrap_or(encode.framerate); // 1s fragments by default
+            args.extend([
+                "-f".to_string(), "mp4".to_string(),
+                "-c:v".to_string(), "libx264".to_string(),
+                "-preset".to_string(), "veryfast".to_string(),
+                "-tune".to_string(), "zerolatency".to_string(), // No B-frames, no lookahead
+                "-pix_fmt".to_string(), "yuv420p".to_string(),
+                "-s".to_string(), encode.resolution.clone(),
+                "-b:v".to_string(), format!("{}k", encode.bitrate_kbps),
+                "-r".to_string(), encode.framerate.to_string(),
+                "-g".to_string(), gop.to_string(),
+            ]);
+            if let (Some(maxrate), Some(bufsize)) = (encode.maxrate_kbps, encode.bufsize_kbps) {
+                args.extend([
+                    "-maxrate".to_string(), format!("{}k", maxrate),
+                    "-bufsize".to_string(), format!("{}k", bufsize),
+                ]);
+            }
+        }
+        (codec, true) => {
+            let format = if codec == OutputCodec::H264 { "mp4" } else { "mpegts" };
+            args.extend(["-f", format, "-c:v", "copy"].map(String::from));
+        }
+    }
+
+    if encode.codec == OutputCodec::H264 {
+        // Fragment at keyframes so MSE clients can join on any fragment
+        args.extend([
+            "-movflags".to_string(),
+            "frag_keyframe+empty_moov+default_base_moof".to_string(),
+        ]);
+    }
+    if let Some(mode) = encode.av_sync_mode.filter(|_| encode.codec == OutputCodec::Mpeg1) {
+        let (delay, preload) = mode.mux_delays();
+        args.extend([
+            "-muxdelay".to_string(), delay.to_string(),
+            "-muxpreload".to_string(), preload.to_string(),
+        ]);
+    }
+
+    args.extend([
+        "-an",                          // No audio
+        "-flush_packets", "1",          // Flush packets immediately
+        "pipe:1",                       // Output to stdout
+    ].map(String::from));
+    args
+}
+
+// Decide whether the live output can stream-copy the source. Returns the
+// reason when it can't, so the caller can log why it falls back to transcoding.
+pub fn can_copy_video(options: &StreamOptions, media: &MediaInfo) -> Result<(), String> {
+    let encode = &options.encode;
+    if media.codec != encode.codec.codec_name() {
+        return Err(format!(
+            "source codec {} differs from output codec {}",
+            media.codec,
+            encode.codec.codec_name()
+        ));
+    }
+    if options.filter_complex.is_some() {
+        return Err("a filter_complex graph is applied".to_string());
+    }
+    if options.image_overlay.is_some() {
+        return Err("an image overlay is applied".to_string());
+    }
+    if options.color_range.is_some() || options.color_space.is_some() {
+        return Err("color correction is requested".to_string());
+    }
+    if options.fix_aspect {
+        return Err("aspect correction is requested".to_string());
+    }
+    if options.clock_overlay.is_some() {
+        return Err("a clock overlay is applied".to_string());
+    }
+    let (width, height) = crate::config::parse_resolution(&encode.resolution)?;
+    if (width, height) != (media.width, media.height) {
+        return Err(format!(
+            "resolution change requested ({}x{} -> {})",
+            media.width, media.height, encode.resolution
+        ));
+    }
+    // A source that doesn't report its rate is copied at whatever it sends
+    if let Some(rate) = media.framerate.filter(|rate| (rate - encode.framerate as f64).abs() > 0.5) {
+        return Err(format!("frame rate change requested ({:.2} -> {})", rate, encode.framerate));
+    }
+    if encode.codec == OutputCodec::H264 {
+        // What browsers decode: MSE takes 8-bit 4:2:0 profiles, WebRTC
+        // constrained baseline only
+        let playable: &[&str] = if options.webrtc {
+            &["Constrained Baseline"]
+        } else {
+            &["Constrained Baseline", "Baseline", "Main", "High"]
+        };
+        match media.profile.as_deref() {
+            Some(profile) if playable.contains(&profile) => {}
+            Some(profile) => return Err(format!("source profile {} is not playable in browsers", profile)),
+            None => return Err("source profile unknown".to_string()),
+        }
+    }
+    Ok(())
+}
+
+// Filters applied to the main video before any image overlay: aspect
+// correction, color correction, then the clock
+fn base_filter(options: &StreamOptions) -> Option<String> {
+    let clock = options.clock_overlay.as_ref().map(clock_filter);
+    let filters: Vec<String> = [aspect_filter(options), color_filter(options), clock]
+        .into_iter()
+        .flatten()
+        .collect();
+    (!filters.is_empty()).then(|| filters.join(","))
+}
+
+// Stretch the width by the source's sample aspect ratio so pixels become
+// square (scale treats an unknown ratio as 1:1); the output size is applied
+// afterwards by -s
+fn aspect_filter(options: &StreamOptions) -> Option<String> {
+    options
+        .fix_aspect
+        .then(|| "scale=trunc(iw*sar/2)*2:ih,setsar=1".to_string())
+}
+
+// Filter chain correcting the source color tagging, if requested: setparams
+// overrides what the decoder reported, then scale converts to limited range
+// with the matrix the player expects (BT.601 for jsmpeg, BT.709 for H.264).
+fn color_filter(options: &StreamOptions) -> Option<String> {
+    if options.color_range.is_none() && options.color_space.is_none() {
+        return None;
+    }
+
+    let mut params = Vec::new();
+    if let Some(range) = options.color_range {
+        params.push(format!("range={}", range.filter_name()));
+    }
+    if let Some(space) = options.color_space {
+        params.push(format!("colorspace={}", space.filter_name()));
+    }
+    let out_matrix = match options.encode.codec {
+        OutputCodec::Mpeg1 => "bt601",
+        OutputCodec::H264 => "bt709",
+    };
+    Some(format!(
+        "setparams={},scale=out_range=tv:out_color_matrix={}",
+        params.join(":"),
+        out_matrix
+    ))
+}
+
+// drawtext showing the current time. The text is a literal (no %{localtime}
+// expansion); the app replaces it every second with clock_command.
+fn clock_filter(clock: &ClockOverlayConfig) -> String {
+    let m = clock.margin;
+    let position = match clock.position {
+        OverlayPosition::TopLeft => format!("x={m}:y={m}"),
+        OverlayPosition::TopRight => format!("x=w-tw-{m}:y={m}"),
+        OverlayPosition::BottomLeft => format!("x={m}:y=h-th-{m}"),
+        OverlayPosition::BottomRight => format!("x=w-tw-{m}:y=h-th-{m}"),
+        OverlayPosition::Center => "x=(w-tw)/2:y=(h-th)/2".to_string(),
+    };
+    let text = chrono::Local::now().format(&clock.format).to_string();
+    let mut filter = format!(
+        "drawtext=expansion=none:text='{}':fontsize={}:fontcolor=white:box=1:boxcolor=black@0.5:boxborderw=4:{}",
+        escape_option(&text),
+        clock.font_size,
+        position
+    );
+    if let Some(font) = &clock.font_file {
+        filter.push_str(&format!(":fontfile='{}'", escape_option(&font.to_string_lossy())));
+    }
+    filter
+}
+
+// Filter command (see KillHandle::send_command) setting the clock text to
+// `time`
+pub fn clock_command(clock: &ClockOverlayConfig, time: &chrono::DateTime<chrono::Local>) -> String {
+    format!("drawtext -1 reinit text={}", escape_option(&time.format(&clock.format).to_string()))
+}
+
+// Escape a filter option value; in a filtergraph it is also quoted, which
+// keeps the graph parser from reading the escapes itself
+fn escape_option(value: &str) -> String {
+    value.replace('\\', "\\\\").replace(':', "\\:")
+}
+
+// Filtergraph drawing the image input over the main video, after the base
+// filter chain if any. Converting to rgba keeps PNG transparency, and
+// opacity scales the existing alpha.
+fn image_overlay_filter(overlay: &OverlayImageConfig, image_input: usize, base_filter: Option<&str>) -> String {
+    let m = overlay.margin;
+    let position = match overlay.position {
+        OverlayPosition::TopLeft => format!("{m}:{m}"),
+        OverlayPosition::TopRight => format!("W-w-{m}:{m}"),
+        OverlayPosition::BottomLeft => format!("{m}:H-h-{m}"),
+        OverlayPosition::BottomRight => format!("W-w-{m}:H-h-{m}"),
+        OverlayPosition::Center => "(W-w)/2:(H-h)/2".to_string(),
+    };
+    let logo = format!(
+        "[{}:v]format=rgba,scale=iw*{s}:ih*{s},colorchannelmixer=aa={}[logo]",
+        image_input,
+        overlay.opacity,
+        s = overlay.scale
+    );
+    match base_filter {
+        Some(base) => format!("{};[0:v]{}[base];[base][logo]overlay={}:format=auto", logo, base, position),
+        None => format!("{};[0:v][logo]overlay={}:format=auto", logo, position),
+    }
+}
+
+// Decode the first frame of an image with the stream's FFmpeg, so a corrupt
+// or unsupported overlay image is reported before the stream starts
+pub async fn check_image_decodes(ffmpeg: &str, path: &Path) -> Result<(), String> {
+    let mut cmd = tokio::process::Command::from(command(ffmpeg));
+    cmd.args(["-v", "error", "-i"])
+        .arg(path)
+        .args(["-frames:v", "1", "-f", "null", "-"])
+        .kill_on_drop(true);
+
+    let output = tokio::time::timeout(Duration::from_secs(10), cmd.output())
+        .await
+        .map_err(|_| format!("Timed out decoding {}", path.display()))?
+        .map_err(|e| format!("Failed to run FFmpeg: {}", e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("Cannot decode image {}: {}", path.display(), stderr.trim()));
+    }
+    Ok(())
+}
+
+// Analysis-only output that runs blackdetect/freezedetect on the main source.
+// Frames are downsampled first to keep the cost low; results are parsed from
+// stderr (the metadata filter prints black start/end, freezedetect logs its
+// own transitions).
+fn quality_monitor_args(monitor: &QualityMonitorConfig) -> Vec<String> {
+    let filters = format!(
+        "fps=2,scale=320:-2,blackdetect=d=0:pic_th={},metadata=mode=print,freezedetect=n={}dB:d={}",
+        monitor.black_ratio, monitor.freeze_noise_db, monitor.freeze_duration
+    );
+    vec![
+        "-map".to_string(), "0:v:0".to_string(),
+        "-vf".to_string(), filters,
+        "-an".to_string(),
+        "-f".to_string(), "null".to_string(),
+        "-".to_string(), // The null muxer never writes, so this doesn't touch stdout
+    ]
+}
+
+// H.264 RTP output for WebRTC viewers. Browsers need constrained baseline
+// without B-frames; a copied source is passed through as-is. Viewers can only
+// start on a keyframe, so the GOP defaults to one second.
+fn webrtc_output_args(encode: &EncodeConfig, copy_video: bool, rtp_port: u16) -> Vec<String> {
+    let mut args = vec!["-map".to_string(), "0:v:0".to_string()];
+    if copy_video && encode.codec == OutputCodec::H264 {
+        args.extend(["-c:v", "copy"].map(String::from));
+    } else {
+        let gop = encode.gop.unwrap_or(encode.framerate);
+        args.extend([
+            "-c:v".to_string(), "libx264".to_string(),
+            "-preset".to_string(), "veryfast".to_string(),
+            "-tune".to_string(), "zerolatency".to_string(),
+            "-profile:v".to_string(), "baseline".to_string(),
+            "-pix_fmt".to_string(), "yuv420p".to_string(),
+            "-s".to_string(), encode.resolution.clone(),
+            "-b:v".to_string(), format!("{}k", encode.bitrate_kbps),
+            "-r".to_string(), encode.framerate.to_string(),
+            "-g".to_string(), gop.to_string(),
+        ]);
+    }
+    args.extend([
+        "-an".to_string(),
+        "-f".to_string(), "rtp".to_string(),
+        // Keep packets under the usual WebRTC MTU
+        format!("rtp://127.0.0.1:{}?pkt_size=1200", rtp_port),
+    ]);
+    args
+}
+
+// Arguments for the recording output. Options placed after the live output's
+// "pipe:1" only apply to this second output.
+fn recording_output_args(recording: &RecordingConfig) -> Vec<String> {
+    let mut args = vec![
+        "-c:v".to_string(), recording.codec.clone(),
+        "-b:v".to_string(), format!("{}k", recording.bitrate_kbps),
+        "-g".to_string(), recording.gop.to_string(),
+        "-pix_fmt".to_string(), "yuv420p".to_string(),
+        "-an".to_string(),
+    ];
+
+    // Fragmented MP4 stays playable even if FFmpeg is killed mid-recording
+    let extension = recording
+        .output_path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase());
+    if matches!(extension.as_deref(), Some("mp4") | Some("mov")) {
+        args.extend([
+            "-movflags".to_string(),
+            "+frag_keyframe+empty_moov+default_base_moof".to_string(),
+        ]);
+    }
+
+    args.push(recording.output_path.to_string_lossy().to_string());
+    args
+}
+
+// Shadow buffer output: fixed-length segments written in a cycle, so the
+// directory never holds more than the window. Network sources are
+// stream-copied (segments then split on the source's keyframes); capture
+// devices deliver raw frames and are encoded.
+fn shadow_output_args(shadow: &ShadowConfig, copy: bool) -> Vec<String> {
+    let mut args = vec!["-map".to_string(), "0:v:0".to_string()];
+    if copy {
+        args.extend(["-c:v", "copy"].map(String::from));
+    } else {
+        args.extend(["-c:v", "libx264", "-preset", "veryfast", "-pix_fmt", "yuv420p"].map(String::from));
+    }
+    let dir = shadow.directory.clone().unwrap_or_default();
+    args.extend([
+        "-an".to_string(),
+        "-f".to_string(), "segment".to_string(),
+        "-segment_time".to_string(), crate::shadow::SEGMENT_SECONDS.to_string(),
+        "-segment_wrap".to_string(), crate::shadow::segment_count(shadow).to_string(),
+        "-segment_list".to_string(), crate::shadow::segment_list(&dir).to_string_lossy().to_string(),
+        "-segment_list_type".to_string(), "flat".to_string(),
+        "-segment_list_size".to_string(), (crate::shadow::segment_count(shadow) - 1).to_string(),
+        "-reset_timestamps".to_string(), "1".to_string(),
+        crate::shadow::segment_pattern(&dir).to_string_lossy().to_string(),
+    ]);
+    args
+}
+
+// Create an FFmpeg command without a console window on Windows
+pub fn ffmpeg_command() -> Command {
+    command(crate::get_ffmpeg_path())
+}
+
+// Same for a given binary, such as ffprobe
+pub fn command(program: impl AsRef<std::ffi::OsStr>) -> Command {
+    #[allow(unused_mut)]
+    let mut cmd = Command::new(program);
+
+    #[cfg(target_os = "windows")]
+    {
+        use std::os::windows::process::CommandExt;
+        const CREATE_NO_WINDOW: u32 = 0x08000000;
+        cmd.creation_flags(CREATE_NO_WINDOW);
+    }
+
+    cmd
+}
+
+// ffprobe is expected next to the FFmpeg binary in use
+pub fn ffprobe_path(ffmpeg: &str) -> String {
+    let path = Path::new(ffmpeg);
+    match path.file_name().map(|name| name.to_string_lossy().to_string()) {
+        Some(name) if name.contains("ffmpeg") => path
+            .with_file_name(name.replacen("ffmpeg", "ffprobe", 1))
+            .to_string_lossy()
+            .to_string(),
+        _ => "ffprobe".to_string(),
+    }
+}
+
+// Check that a user-supplied FFmpeg binary exists and runs
+pub fn validate_ffmpeg_binary(path: &Path) -> Result<(), String> {
+    if !path.is_file() {
+        return Err(format!("FFmpeg not found at {}", path.display()));
+    }
+
+    match command(path).arg("-version").output() {
+        Ok(output) if output.status.success() => Ok(()),
+        Ok(output) => Err(format!("{} -version exited with {}", path.display(), output.status)),
+        Err(e) => Err(format!("Failed to run {}: {}", path.display(), e)),
+    }
+}
+
+// Query the protocols supported by the installed FFmpeg (cached after the
+// first successful call)
+pub fn supported_protocols() -> Result<SupportedProtocols, String> {
+    cached(&PROTOCOLS, || Ok(parse_protocols(&query_ffmpeg("-protocols")?)))
+}
+
+// Query the encoders built into the installed FFmpeg (cached)
+pub fn encoders() -> Result<Vec<Encoder>, String> {
+    cached(&ENCODERS, || Ok(parse_encoders(&query_ffmpeg("-encoders")?)))
+}
+
+// Query the hardware acceleration methods of the installed FFmpeg (cached)
+pub fn hwaccels() -> Result<Vec<String>, String> {
+    cached(&HWACCELS, || Ok(parse_hwaccels(&query_ffmpeg("-hwaccels")?)))
+}
+
+// Version string of the installed FFmpeg, e.g. "6.1.1" (not cached, so it
+// doubles as a presence check)
+pub fn version() -> Result<String, String> {
+    let output = query_ffmpeg("-version")?;
+    output
+        .lines()
+        .next()
+        .and_then(|line| line.strip_prefix("ffmpeg version "))
+        .and_then(|rest| rest.split_whitespace().next())
+        .map(String::from)
+        .ok_or_else(|| "Unrecognized FFmpeg -version output".to_string())
+}
+
+// Forget the cached FFmpeg lists, e.g. after switching binaries
+pub fn clear_capability_cache() {
+    *PROTOCOLS.write().unwrap() = None;
+    *ENCODERS.write().unwrap() = None;
+    *HWACCELS.write().unwrap() = None;
+}
+
+fn cached<T: Clone>(
+    cache: &RwLock<Option<T>>,
+    query: impl FnOnce() -> Result<T, String>,
+) -> Result<T, String> {
+    if let Some(value) = cache.read().unwrap().clone() {
+        return Ok(value);
+    }
+    let value = query()?;
+    *cache.write().unwrap() = Some(value.clone());
+    Ok(value)
+}
+
+// Run `ffmpeg -hide_banner <flag>` and return its stdout
+fn query_ffmpeg(flag: &str) -> Result<String, String> {
+    let output = ffmpeg_command()
+        .args(["-hide_banner", flag])
+        .output()
+        .map_err(|e| format!("Failed to run FFmpeg: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("FFmpeg {} exited with {}", flag, output.status));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+// Parse the table printed by `ffmpeg -encoders`: a legend, a " ------"
+// separator, then one " V....D name  description" line per encoder
+fn parse_encoders(text: &str) -> Vec<Encoder> {
+    text.lines()
+        .skip_while(|line| !line.trim_start().starts_with("---"))
+        .skip(1)
+        .filter_map(|line| {
+            let line = line.trim_start();
+            let (flags, rest) = line.split_once(char::is_whitespace)?;
+            let rest = rest.trim_start();
+            let (name, description) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+            let media_type = match flags.chars().next()? {
+                'V' => "video",
+                'A' => "audio",
+                'S' => "subtitle",
+                _ => return None,
+            };
+            Some(Encoder {
+                name: name.to_string(),
+                media_type: media_type.to_string(),
+                description: description.trim().to_string(),
+            })
+        })
+        .collect()
+}
+
+// Parse `ffmpeg -hwaccels`: a header line, then one method per line
+fn parse_hwaccels(text: &str) -> Vec<String> {
+    text.lines()
+        .skip_while(|line| !line.starts_with("Hardware acceleration methods"))
+        .skip(1)
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+// Parse the "Input:" / "Output:" sections printed by `ffmpeg -protocols`
+fn parse_protocols(text: &str) -> SupportedProtocols {
+    let mut input = Vec::new();
+    let mut output = Vec::new();
+    let mut section = None;
+
+    for line in text.lines() {
+        match line.trim() {
+            "Input:" => section = Some(&mut input),
+            "Output:" => section = Some(&mut output),
+            "" => {}
+            name if line.starts_with(char::is_whitespace) => {
+                if let Some(list) = section.as_mut() {
+                    list.push(name.to_string());
+                }
+            }
+            _ => section = None,
+        }
+    }
+
+    // RTSP is a demuxer rather than a protocol in FFmpeg; it runs over the
+    // tcp/rtp protocols, and rtsps additionally needs tls.
+    let has = |name: &str| input.iter().any(|p| p == name);
+    let source_schemes = SOURCE_SCHEMES
+        .iter()
+        .filter(|scheme| match **scheme {
+            "rtsp" => has("tcp") && has("rtp"),
+            "rtsps" => has("tcp") && has("rtp") && has("tls"),
+            other => has(other),
+        })
+        .map(|scheme| scheme.to_string())
+        .collect();
+
+    SupportedProtocols { input, output, source_schemes }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AvSyncMode, JsmpegTuning, RecordingConfig};
+    use chrono::TimeZone;
+
+    // Value following `flag` in the first output that uses it after `from`
+    fn value_after<'a>(args: &'a [String], flag: &str, from: usize) -> Option<&'a str> {
+        args[from..].iter().position(|arg| arg == flag).map(|i| args[from + i + 1].as_str())
+    }
+
+    #[test]
+    fn recording_maps_the_live_picture() {
+        let options = StreamOptions {
+            sources: vec!["rtsp://cam2/live".to_string()],
+            filter_complex: Some("[1:v]scale=160:-1[pip];[0:v][pip]overlay=W-w-10:10".to_string()),
+            recording_config: Some(RecordingConfig {
+                output_path: "/videos/cam1.mp4".into(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let args = build_ffmpeg_args("rtsp://cam1/live", &options, false, None);
+
+        assert!(args.contains(&"-n".to_string()) && !args.contains(&"-y".to_string()));
+        assert_eq!(
+            value_after(&args, "-filter_complex", 0),
+            Some("[1:v]scale=160:-1[pip];[0:v][pip]overlay=W-w-10:10,split=2[live][rec]")
+        );
+        let live = args.iter().position(|arg| arg == "pipe:1").unwrap();
+        assert_eq!(value_after(&args, "-map", 0), Some("[live]"));
+        assert_eq!(value_after(&args, "-map", live), Some("[rec]"));
+    }
+
+    #[test]
+    fn copy_needs_a_matching_source() {
+        let mut options = StreamOptions::default();
+        options.encode.codec = OutputCodec::H264;
+        options.encode.resolution = "1280x720".to_string();
+        options.encode.framerate = 25;
+        let mut media = MediaInfo {
+            codec: "h264".to_string(),
+            profile: Some("Main".to_string()),
+            width: 1280,
+            height: 720,
+            framerate: Some(25.0),
+        };
+        assert_eq!(can_copy_video(&options, &media), Ok(()));
+
+        media.framerate = Some(30.0);
+        assert!(can_copy_video(&options, &media).unwrap_err().contains("frame rate"));
+        media.framerate = None;
+        assert_eq!(can_copy_video(&options, &media), Ok(()));
+
+        media.profile = Some("High 4:2:2".to_string());
+        assert!(can_copy_video(&options, &media).unwrap_err().contains("profile"));
+        media.profile = Some("Main".to_string());
+        options.webrtc = true;
+        assert!(can_copy_video(&options, &media).unwrap_err().contains("profile"));
+    }
+
+    #[test]
+    fn inputs_are_classified() {
+        let options = StreamOptions::default();
+        assert!(is_file_input("/videos/clip.mp4", &options, true));
+        assert!(is_file_input("file:clip.mp4", &options, true));
+        assert!(!is_file_input("rtsp://cam/live", &options, true));
+        let device = StreamOptions { input_format: Some("v4l2".to_string()), ..Default::default() };
+        assert!(!is_file_input("/dev/video0", &device, true));
+
+        assert!(is_network_source("RTSP://cam/live") && is_network_source("srt://relay:9000"));
+        assert!(!is_network_source("file:///etc/passwd") && !is_network_source("/etc/passwd"));
+        assert!(!is_network_source("gopher://cam"));
+    }
+
+    #[test]
+    fn network_inputs_time_out() {
+        let options = StreamOptions {
+            sources: vec!["http://cam2/live.ts".to_string(), "/videos/logo.mp4".to_string()],
+            filter_complex: Some("[0:v][1:v]hstack".to_string()),
+            ..Default::default()
+        };
+        let args = build_ffmpeg_args("rtsp://cam1/live", &options, false, None);
+        let inputs: Vec<&[String]> = args.split(|arg| arg == "-i").collect();
+        assert_eq!(value_after(inputs[0], "-timeout", 0), Some("10000000"));
+        assert_eq!(value_after(inputs[1], "-rw_timeout", 0), Some("10000000"));
+        assert!(!inputs[2].iter().any(|arg| arg.ends_with("timeout")));
+
+        let options = StreamOptions { input_timeout_ms: 0, ..Default::default() };
+        let args = build_ffmpeg_args("rtsp://cam1/live", &options, false, None);
+        assert!(!args.iter().any(|arg| arg.ends_with("timeout")));
+    }
+
+    #[test]
+    fn clock_text_is_escaped() {
+        assert_eq!(escape_option(r"C:\fonts\a.ttf"), r"C\:\\fonts\\a.ttf");
+        let clock = ClockOverlayConfig {
+            format: "%H:%M:%S".to_string(),
+            ..Default::default()
+        };
+        let time = chrono::Local.with_ymd_and_hms(2024, 5, 1, 12, 34, 56).unwrap();
+        assert_eq!(clock_command(&clock, &time), r"drawtext -1 reinit text=12\:34\:56");
+    }
+
+    #[test]
+    fn sources_are_opened_like_the_stream() {
+        let options = StreamOptions {
+            rtsp_preset: RtspPreset::Udp,
+            rtsp_flags: Some(vec!["filter_src".to_string()]),
+            input_timeout_ms: 5_000,
+            ..Default::default()
+        };
+        assert_eq!(
+            source_input_args("rtsp://cam1/live", &options),
+            ["-rtsp_transport", "udp", "-rtsp_flags", "+filter_src", "-timeout", "5000000"]
+        );
+        assert_eq!(source_input_args("http://cam2/live.ts", &options), ["-rw_timeout", "5000000"]);
+        assert!(source_input_args("/videos/clip.mp4", &options).is_empty());
+    }
+
+    #[test]
+    fn unfiltered_outputs_keep_default_mapping() {
+        let options = StreamOptions {
+            recording_config: Some(RecordingConfig::default()),
+            ..Default::default()
+        };
+        let args = build_ffmpeg_args("rtsp://cam1/live", &options, false, None);
+        assert!(!args.contains(&"-map".to_string()));
+        assert!(!args.contains(&"-filter_complex".to_string()));
+    }
+
+    #[test]
+    fn capped_bitrates_leave_the_fixed_quantizer() {
+        let mut encode = EncodeConfig::default();
+        let args = live_output_args(&encode, false);
+        assert!(args.contains(&"-q:v".to_string()) && !args.contains(&"-maxrate".to_string()));
+
+        // A degraded or adapted MPEG1 stream is rate controlled instead
+        encode.cap_bitrate(400);
+        let args = live_output_args(&encode, false);
+        assert!(!args.contains(&"-q:v".to_string()));
+        assert_eq!(value_after(&args, "-b:v", 0), Some("400k"));
+        assert_eq!(value_after(&args, "-maxrate", 0), Some("400k"));
+        assert_eq!(value_after(&args, "-bufsize", 0), Some("400k"));
+
+        // Tighter limits of a preset or the options are kept
+        let mut encode = EncodeConfig {
+            jsmpeg_tuning: Some(JsmpegTuning::LowLatency),
+            ..Default::default()
+        };
+        encode.cap_bitrate(400);
+        assert_eq!(encode.rate_limits(), Some((400, 200)));
+
+        let mut encode = EncodeConfig {
+            codec: OutputCodec::H264,
+            ..Default::default()
+        };
+        encode.cap_bitrate(400);
+        let args = live_output_args(&encode, false);
+        assert_eq!(value_after(&args, "-maxrate", 0), Some("400k"));
+    }
+
+    #[test]
+    fn av_sync_modes_set_the_mux_delays() {
+        let mux_delays = |codec: OutputCodec, mode: Option<AvSyncMode>| {
+            let encode = EncodeConfig {
+                codec,
+                av_sync_mode: mode,
+                ..Default::default()
+            };
+            let args = live_output_args(&encode, false);
+            (value_after(&args, "-muxdelay", 0).map(String::from), value_after(&args, "-muxpreload", 0).map(String::from))
+        };
+        let set = |delay: &str, preload: &str| (Some(delay.to_string()), Some(preload.to_string()));
+
+        assert_eq!(mux_delays(OutputCodec::Mpeg1, Some(AvSyncMode::LowLatency)), set("0.001", "0"));
+        assert_eq!(mux_delays(OutputCodec::Mpeg1, Some(AvSyncMode::Balanced)), set("0.1", "0.1"));
+        assert_eq!(mux_delays(OutputCodec::Mpeg1, Some(AvSyncMode::Stable)), set("0.5", "0.5"));
+        // FFmpeg's defaults otherwise
+        assert_eq!(mux_delays(OutputCodec::Mpeg1, None), (None, None));
+        assert_eq!(mux_delays(OutputCodec::H264, Some(AvSyncMode::Stable)), (None, None));
+    }
+}
@@ -0,0 +1,213 @@
+// Splits FFmpeg's fragmented MP4 output into relayable messages. MSE clients
+// need the init segment (ftyp + moov) before any media, and each media
+// message must be a whole moof + mdat pair so a client joining mid-stream
+// starts on a fragment boundary.
+
+pub enum Fmp4Message {
+    Init(Vec<u8>),
+    Fragment(Vec<u8>),
+}
+
+#[derive(Default)]
+pub struct Fmp4Splitter {
+    buffer: Vec<u8>,
+    init: Vec<u8>,
+    init_done: bool,
+    fragment: Vec<u8>,
+}
+
+impl Fmp4Splitter {
+    // Feed bytes from FFmpeg and return any completed messages
+    pub fn push(&mut self, data: &[u8]) -> Vec<Fmp4Message> {
+        self.buffer.extend_from_slice(data);
+        let mut messages = Vec::new();
+
+        while let Some(size) = box_size(&self.buffer) {
+            if self.buffer.len() < size {
+                break;
+            }
+            let mp4_box: Vec<u8> = self.buffer.drain(..size).collect();
+            match &mp4_box[4..8] {
+                b"ftyp" | b"moov" if !self.init_done => {
+                    self.init.extend_from_slice(&mp4_box);
+                    if &mp4_box[4..8] == b"moov" {
+                        self.init_done = true;
+                        messages.push(Fmp4Message::Init(self.init.clone()));
+                    }
+                }
+                b"mdat" => {
+                    self.fragment.extend_from_slice(&mp4_box);
+                    messages.push(Fmp4Message::Fragment(std::mem::take(&mut self.fragment)));
+                }
+                // moof, styp, sidx, ... are held until their mdat arrives
+                _ => self.fragment.extend_from_slice(&mp4_box),
+            }
+        }
+
+        messages
+    }
+}
+
+// Number of samples in a fragment, from the trun boxes of its moof. The
+// live output carries video only, so this is its frame count.
+pub fn sample_count(fragment: &[u8]) -> u64 {
+    let mut samples = 0;
+    for (kind, moof) in boxes(fragment) {
+        if kind != b"moof" {
+            continue;
+        }
+        for (kind, traf) in boxes(moof) {
+            if kind != b"traf" {
+                continue;
+            }
+            for (kind, trun) in boxes(traf) {
+                // Version and flags, then the sample count
+                if let (b"trun", Some(count)) = (kind, trun.get(4..8)) {
+                    samples += u32::from_be_bytes([count[0], count[1], count[2], count[3]]) as u64;
+                }
+            }
+        }
+    }
+    samples
+}
+
+// Type and payload of the whole boxes in `data`
+fn boxes(mut data: &[u8]) -> Vec<(&[u8], &[u8])> {
+    let mut boxes = Vec::new();
+    while let Some(size) = box_size(data) {
+        let header = if data.get(0..4) == Some(&[0, 0, 0, 1]) { 16 } else { 8 };
+        if size > data.len() {
+            break;
+        }
+        boxes.push((&data[4..8], &data[header..size]));
+        data = &data[size..];
+    }
+    boxes
+}
+
+// Largest box accepted. A live fragment holds about a second of video, so
+// anything bigger is a corrupt size that would make the buffer grow forever.
+const MAX_BOX_SIZE: u64 = 64 * 1024 * 1024;
+
+// Size of the box at the start of the buffer, if its header is complete
+fn box_size(buffer: &[u8]) -> Option<usize> {
+    if buffer.len() < 8 {
+        return None;
+    }
+    let size = u32::from_be_bytes(buffer[0..4].try_into().ok()?) as u64;
+    let (size, header) = match size {
+        // 64-bit "largesize" follows the type
+        1 => {
+            if buffer.len() < 16 {
+                return None;
+            }
+            (u64::from_be_bytes(buffer[8..16].try_into().ok()?), 16)
+        }
+        size => (size, 8),
+    };
+    // Size 0 (box extends to end of file) never appears in a live stream;
+    // treat it, anything smaller than its header and oversized boxes as
+    // corrupt and skip the header
+    if size < header || size > MAX_BOX_SIZE {
+        return Some(header as usize);
+    }
+    Some(size as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mp4_box(kind: &[u8; 4], payload_len: usize) -> Vec<u8> {
+        let mut data = ((8 + payload_len) as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(kind);
+        data.resize(8 + payload_len, 0xAB);
+        data
+    }
+
+    fn split(messages: Vec<Fmp4Message>) -> (Vec<Vec<u8>>, Vec<Vec<u8>>) {
+        let (mut inits, mut fragments) = (Vec::new(), Vec::new());
+        for message in messages {
+            match message {
+                Fmp4Message::Init(data) => inits.push(data),
+                Fmp4Message::Fragment(data) => fragments.push(data),
+            }
+        }
+        (inits, fragments)
+    }
+
+    #[test]
+    fn init_and_fragments_are_whole() {
+        let init = [mp4_box(b"ftyp", 16), mp4_box(b"moov", 100)].concat();
+        let first = [mp4_box(b"moof", 40), mp4_box(b"mdat", 500)].concat();
+        let second = [mp4_box(b"styp", 12), mp4_box(b"moof", 40), mp4_box(b"mdat", 300)].concat();
+        let stream = [init.clone(), first.clone(), second.clone()].concat();
+
+        // Byte by byte, so every box header and body is split across pushes
+        let mut splitter = Fmp4Splitter::default();
+        let mut messages = Vec::new();
+        for byte in &stream {
+            messages.extend(splitter.push(std::slice::from_ref(byte)));
+        }
+        let (inits, fragments) = split(messages);
+        assert_eq!(inits, [init]);
+        assert_eq!(fragments, [first, second]);
+    }
+
+    #[test]
+    fn init_is_sent_once() {
+        let mut splitter = Fmp4Splitter::default();
+        let (inits, _) = split(splitter.push(&[mp4_box(b"ftyp", 16), mp4_box(b"moov", 8)].concat()));
+        assert_eq!(inits.len(), 1);
+        // A later moov is not a new init segment
+        let (inits, fragments) = split(splitter.push(&[mp4_box(b"moov", 8), mp4_box(b"mdat", 8)].concat()));
+        assert!(inits.is_empty());
+        assert_eq!(fragments[0].len(), 32);
+    }
+
+    #[test]
+    fn fragment_samples_are_counted() {
+        let container = |kind: &[u8; 4], children: &[Vec<u8>]| {
+            let payload = children.concat();
+            let mut data = mp4_box(kind, 0);
+            data[..4].copy_from_slice(&((8 + payload.len()) as u32).to_be_bytes());
+            data.extend(payload);
+            data
+        };
+        let trun = |samples: u32| {
+            let mut data = mp4_box(b"trun", 8);
+            data[8..12].copy_from_slice(&[0, 0, 0x02, 0x01]);
+            data[12..16].copy_from_slice(&samples.to_be_bytes());
+            data
+        };
+        let traf = container(b"traf", &[mp4_box(b"tfhd", 8), trun(12), trun(3)]);
+        let moof = container(b"moof", &[mp4_box(b"mfhd", 8), traf]);
+        let fragment = [mp4_box(b"styp", 12), moof, mp4_box(b"mdat", 500)].concat();
+        assert_eq!(sample_count(&fragment), 15);
+        assert_eq!(sample_count(&mp4_box(b"mdat", 100)), 0);
+        assert_eq!(sample_count(&fragment[..40]), 0);
+    }
+
+    #[test]
+    fn box_sizes_are_read() {
+        assert_eq!(box_size(&mp4_box(b"mdat", 0)[..7]), None);
+        assert_eq!(box_size(&mp4_box(b"mdat", 100)), Some(108));
+
+        let mut large = 1u32.to_be_bytes().to_vec();
+        large.extend_from_slice(b"mdat");
+        assert_eq!(box_size(&large), None);
+        large.extend_from_slice(&(1u64 << 20).to_be_bytes());
+        assert_eq!(box_size(&large), Some(1 << 20));
+
+        // Corrupt sizes still consume a whole header so the splitter moves on
+        assert_eq!(box_size(&[0, 0, 0, 3, b'm', b'd', b'a', b't']), Some(8));
+        assert_eq!(box_size(&[0xff, 0, 0, 0, b'm', b'd', b'a', b't']), Some(8));
+        large[8..16].copy_from_slice(&(1u64 << 32).to_be_bytes());
+        assert_eq!(box_size(&large), Some(16));
+        large[8..16].copy_from_slice(&0u64.to_be_bytes());
+        assert_eq!(box_size(&large), Some(16));
+        let (inits, fragments) = split(Fmp4Splitter::default().push(&large));
+        assert!(inits.is_empty());
+        assert_eq!(fragments, [large]);
+    }
+}
@@ -0,0 +1,165 @@
+use crate::FfmpegRunner;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, oneshot};
+
+pub const PLAYLIST: &str = "index.m3u8";
+pub const SEGMENT_PATTERN: &str = "segment_%05d.ts";
+
+// Largest request head accepted; players only send a request line and a few
+// headers
+const MAX_REQUEST_BYTES: usize = 8 * 1024;
+// How long a client gets to send its request head
+const REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+// Directory the HLS stream on a port writes its playlist and segments to
+pub fn output_dir(port: u16) -> PathBuf {
+    std::env::temp_dir().join(format!("eroxii-hls-{}", port))
+}
+
+// Run FFmpeg with the HLS muxer and serve its output directory over HTTP on
+// the port. FFmpeg is supervised like the WebSocket streams' FFmpeg; the
+// runner writes to output_dir(port). The directory is removed when the
+// stream stops.
+pub async fn run_hls_server(
+    runner: FfmpegRunner,
+    rtsp_url: String,
+    port: u16,
+    mut shutdown_rx: broadcast::Receiver<()>,
+    ready_tx: oneshot::Sender<Result<(), String>>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let listener = match crate::bind_listener(port) {
+        Ok(listener) => listener,
+        Err(e) => {
+            let _ = ready_tx.send(Err(e.to_string()));
+            return Err(e.into());
+        }
+    };
+
+    let dir = output_dir(port);
+    if let Err(e) = std::fs::create_dir_all(&dir) {
+        let _ = ready_tx.send(Err(format!("Failed to create {}: {}", dir.display(), e)));
+        return Err(e.into());
+    }
+    let _ = ready_tx.send(Ok(()));
+    log::info!("HLS server listening on http://127.0.0.1:{}/{}", port, PLAYLIST);
+
+    let process_slot = runner.process_slot.clone();
+    let graceful_stop = runner.state.graceful_stop;
+    let ffmpeg_task = tokio::spawn(crate::supervise_ffmpeg(runner, rtsp_url, shutdown_rx.resubscribe()));
+
+    loop {
+        tokio::select! {
+            _ = shutdown_rx.recv() => {
+                log::info!("Shutting down HLS server on port {}", port);
+                break;
+            }
+            accept_result = listener.accept() => {
+                match accept_result {
+                    Ok((stream, _)) => {
+                        let dir = dir.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = handle_http(stream, &dir).await {
+                                log::debug!("HLS request failed: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) => log::error!("HLS accept error: {}", e),
+                }
+            }
+        }
+    }
+
+    process_slot.stop_gracefully(graceful_stop).await;
+    let _ = ffmpeg_task.await;
+    if let Err(e) = std::fs::remove_dir_all(&dir) {
+        log::warn!("Failed to remove HLS directory {}: {}", dir.display(), e);
+    }
+    Ok(())
+}
+
+// Empty the output directory
+pub fn clear_dir(dir: &Path) {
+    if let Ok(entries) = std::fs::read_dir(dir) {
+        for entry in entries.flatten() {
+            let _ = std::fs::remove_file(entry.path());
+        }
+    }
+}
+
+// Serve one GET/HEAD request for the playlist or a segment. Only plain file
+// names inside the output directory are served.
+async fn handle_http(mut stream: TcpStream, dir: &Path) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 1024];
+    let deadline = tokio::time::Instant::now() + REQUEST_TIMEOUT;
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = match tokio::time::timeout_at(deadline, stream.read(&mut buffer)).await {
+            Ok(read) => read?,
+            Err(_) => return respond(&mut stream, "408 Request Timeout").await,
+        };
+        if n == 0 {
+            return Ok(());
+        }
+        request.extend_from_slice(&buffer[..n]);
+        if request.len() > MAX_REQUEST_BYTES {
+            return respond(&mut stream, "431 Request Header Fields Too Large").await;
+        }
+    }
+
+    let head = String::from_utf8_lossy(&request);
+    let mut parts = head.lines().next().unwrap_or_default().split_whitespace();
+    let (method, target) = (parts.next().unwrap_or_default(), parts.next().unwrap_or_default());
+    match method {
+        "GET" | "HEAD" => {}
+        "OPTIONS" => return respond(&mut stream, "204 No Content").await,
+        _ => return respond(&mut stream, "405 Method Not Allowed").await,
+    }
+
+    let name = target.split('?').next().unwrap_or_default().trim_start_matches('/');
+    let content_type = match Path::new(name).extension().and_then(|ext| ext.to_str()) {
+        Some("m3u8") => "application/vnd.apple.mpegurl",
+        Some("ts") => "video/mp2t",
+        _ => return respond(&mut stream, "404 Not Found").await,
+    };
+    if name.contains(['/', '\\']) || name.starts_with('.') {
+        return respond(&mut stream, "404 Not Found").await;
+    }
+
+    let body = match tokio::fs::read(dir.join(name)).await {
+        Ok(body) => body,
+        Err(_) => return respond(&mut stream, "404 Not Found").await,
+    };
+    // The playlist changes with every segment, and segment names start over
+    // when FFmpeg restarts (see clear_dir), so nothing may be cached
+    let head = format!(
+        "HTTP/1.1 200 OK\r\n\
+         Content-Type: {}\r\n\
+         Content-Length: {}\r\n\
+         Cache-Control: no-cache\r\n\
+         Access-Control-Allow-Origin: *\r\n\
+         Connection: close\r\n\r\n",
+        content_type,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    if method == "GET" {
+        stream.write_all(&body).await?;
+    }
+    stream.shutdown().await
+}
+
+// Bodiless response; OPTIONS answers CORS preflights
+async fn respond(stream: &mut TcpStream, status: &str) -> std::io::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {}\r\n\
+         Content-Length: 0\r\n\
+         Access-Control-Allow-Origin: *\r\n\
+         Access-Control-Allow-Methods: GET, HEAD, OPTIONS\r\n\
+         Connection: close\r\n\r\n",
+        status
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.shutdown().await
+}
@@ -1,17 +1,62 @@
+mod abr;
+mod benchmark;
+mod capabilities;
+mod config;
+mod devices;
+mod disk;
+mod ffmpeg;
+mod fmp4;
+mod hls;
+mod metrics;
+mod mpegts;
+mod pipe;
+mod preemption;
+mod probe;
+mod process;
+mod quality;
+mod relay;
+mod rtsp_url;
+mod sample;
+mod schema;
+mod settings;
+mod shadow;
+mod snapshot;
+mod timestamps;
+#[cfg(feature = "webrtc")]
+mod whep;
+
+use abr::{AbrState, AbrStatus, ClientFeedback};
+use config::{
+    BenchmarkConfig, HlsConfig, OutputCodec, RateControl, RecordingConfig, ShadowConfig, SocketConfig, StreamLimits,
+    StreamOptions,
+};
+use fmp4::{Fmp4Message, Fmp4Splitter};
 use futures_util::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
-use socket2::{Domain, Socket, Type};
-use std::collections::HashMap;
+use socket2::{Domain, SockRef, Socket, TcpKeepalive, Type};
+use std::collections::{HashMap, VecDeque};
 use std::env;
 use std::fs;
 use std::io::Read;
 use std::net::SocketAddr;
-use std::path::PathBuf;
-use std::process::{Command, Stdio};
-use std::sync::Arc;
-use tauri::State;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use metrics::{MetricsHistory, MetricsSample};
+use mpegts::KeyframeScanner;
+use preemption::Load;
+use probe::MediaInfo;
+use process::{ExitKind, FfmpegProcess, ProcessSlot};
+use quality::StreamQuality;
+use relay::{RelayMessage, RelayReceiver, RelayStats, VideoRelay};
+use timestamps::TimestampStats;
+use sample::{SampleFormat, SampleFragment};
+use settings::{Settings, SettingsStore};
+use tauri::{AppHandle, Emitter, Manager, State};
 use tokio::net::TcpListener;
-use tokio::sync::{broadcast, RwLock};
+use tokio::sync::{broadcast, mpsc, oneshot, RwLock, Semaphore};
 use tokio_tungstenite::tungstenite::Message;
 
 // Find FFmpeg executable - searches common Windows locations
@@ -106,22 +151,648 @@ fn search_ffmpeg_in_dir(dir: &std::path::Path, depth: u32) -> Option<PathBuf> {
     None
 }
 
-// Get FFmpeg path (cached)
+// FFmpeg binary pinned in the user settings
+static FFMPEG_OVERRIDE: std::sync::RwLock<Option<String>> = std::sync::RwLock::new(None);
+
+fn set_ffmpeg_override(path: Option<PathBuf>) {
+    if let Some(path) = &path {
+        log::info!("Using FFmpeg from settings: {}", path.display());
+    }
+    *FFMPEG_OVERRIDE.write().unwrap() = path.map(|p| p.to_string_lossy().to_string());
+    // Lists queried from the previous binary no longer apply
+    ffmpeg::clear_capability_cache();
+}
+
+// Get FFmpeg path (settings override, then cached discovery)
 fn get_ffmpeg_path() -> String {
     use std::sync::OnceLock;
     static FFMPEG_PATH: OnceLock<String> = OnceLock::new();
+    if let Some(path) = FFMPEG_OVERRIDE.read().unwrap().clone() {
+        return path;
+    }
     FFMPEG_PATH.get_or_init(find_ffmpeg).clone()
 }
 
+// FFmpeg binary for a stream started with the given ffmpeg_path
+fn stream_ffmpeg(path: Option<&Path>) -> String {
+    path.map_or_else(get_ffmpeg_path, |path| path.to_string_lossy().to_string())
+}
+
 // Stream state management
 #[derive(Default)]
 pub struct StreamManager {
     streams: RwLock<HashMap<u16, StreamInfo>>,
+    // Held for the duration of a swap_ports
+    swapping: tokio::sync::Mutex<()>,
+}
+
+impl StreamManager {
+    // Register a stream, failing if the port is already taken or the stream
+    // limits are reached. When a limit is reached, lower-priority streams are
+    // degraded or stopped as the preemption policy allows; they are returned
+    // for the caller to report. The checks and the insert happen under one
+    // write lock so concurrent start_stream calls cannot both take the port
+    // or the last slot.
+    async fn admit(&self, port: u16, info: StreamInfo, limits: &StreamLimits) -> Result<Preempted, String> {
+        let mut streams = self.streams.write().await;
+        if streams.contains_key(&port) {
+            return Err(format!("Port {} is already in use", port));
+        }
+
+        // One load per FFmpeg, with the ports relaying it
+        let mut shares: Vec<SourceShare> = Vec::new();
+        let mut groups: Vec<(Vec<u16>, Load)> = Vec::new();
+        for (&stream_port, stream) in streams.iter() {
+            match shares.iter().position(|share| share.same(&stream.state.share)) {
+                Some(i) => {
+                    let (ports, load) = &mut groups[i];
+                    ports.push(stream_port);
+                    load.priority = load.priority.max(stream.options.priority);
+                }
+                None => {
+                    shares.push(stream.state.share.clone());
+                    groups.push((vec![stream_port], stream.load()));
+                }
+            }
+        }
+        let loads: Vec<Load> = groups.iter().map(|(_, load)| load.clone()).collect();
+        let plan = preemption::plan(limits, &loads, info.options.priority, info.options.encode.bitrate_kbps)?;
+
+        let mut preempted = Preempted::default();
+        for (i, bitrate_kbps) in plan.degrade {
+            let ports = &groups[i].0;
+            if let Some(stream) = streams.get(&ports[0]) {
+                log::warn!("Lowering the bitrate of stream {} to {} kbps for port {}", ports[0], bitrate_kbps, port);
+                stream
+                    .state
+                    .logs
+                    .push(format!("Bitrate lowered to {} kbps for a higher-priority stream", bitrate_kbps));
+                *stream.state.degraded_bitrate.lock().unwrap() = Some(bitrate_kbps);
+                stream.state.request_restart();
+            }
+            preempted.degraded.extend(ports.iter().map(|&port| (port, bitrate_kbps)));
+        }
+        for i in plan.stop {
+            for stream_port in &groups[i].0 {
+                log::warn!("Stopping stream {} for higher-priority port {}", stream_port, port);
+                if let Some(stream) = streams.remove(stream_port) {
+                    let _ = stream.shutdown_tx.send(());
+                }
+                preempted.stopped.push(*stream_port);
+            }
+        }
+        streams.insert(port, info);
+        Ok(preempted)
+    }
+
+    // Stop every port relaying the FFmpeg of `share`; returns their ports
+    async fn stop_source(&self, share: &SourceShare) -> Vec<u16> {
+        let mut streams = self.streams.write().await;
+        let ports: Vec<u16> = streams
+            .iter()
+            .filter(|(_, info)| info.state.share.same(share))
+            .map(|(port, _)| *port)
+            .collect();
+        for port in &ports {
+            if let Some(info) = streams.remove(port) {
+                let _ = info.shutdown_tx.send(());
+            }
+        }
+        ports
+    }
+
+    // Gracefully stop every stream writing files (recording or shadow buffer),
+    // with any ports sharing their FFmpeg, so the files are finalized instead
+    // of truncated. FFmpeg that hasn't exited after `timeout` (default: the
+    // stream's graceful_stop_timeout_ms) is killed.
+    async fn drain_recordings(&self, timeout: Option<Duration>) -> DrainReport {
+        let draining: Vec<(u16, StreamInfo)> = {
+            let mut streams = self.streams.write().await;
+            let writers: Vec<SourceShare> = streams
+                .values()
+                .filter(|info| info.writes_files())
+                .map(|info| info.state.share.clone())
+                .collect();
+            let ports: Vec<u16> = streams
+                .iter()
+                .filter(|(_, info)| writers.iter().any(|share| share.same(&info.state.share)))
+                .map(|(port, _)| *port)
+                .collect();
+            ports
+                .into_iter()
+                .filter_map(|port| streams.remove(&port).map(|info| (port, info)))
+                .collect()
+        };
+        let stops = draining.iter().map(|(_, info)| {
+            let timeout = timeout.unwrap_or(info.state.graceful_stop);
+            info.state.process_slot.stop_gracefully(timeout)
+        });
+        let exited = futures_util::future::join_all(stops).await;
+
+        let mut report = DrainReport::default();
+        for ((port, info), exited) in draining.into_iter().zip(exited) {
+            if exited {
+                report.finalized.push(port);
+            } else {
+                log::warn!("FFmpeg of stream {} did not finish in time and was killed", port);
+                report.timed_out.push(port);
+            }
+            let _ = info.shutdown_tx.send(());
+        }
+        report
+    }
+
+    // Remove the stream of the given shutdown channel, on whatever port
+    // swap_ports moved it to. An exiting server can't remove a newer stream
+    // on its port.
+    async fn unregister(&self, shutdown_tx: &broadcast::Sender<()>) {
+        self.streams
+            .write()
+            .await
+            .retain(|_, info| !info.shutdown_tx.same_channel(shutdown_tx));
+    }
+
+    // Exchange the ports of two WebSocket streams. Their servers trade
+    // listeners, so both ports stay bound throughout and nothing else can
+    // take them in between. The hand-off runs without the streams lock; swaps
+    // are serialized so each one sees the ports the previous one left.
+    async fn swap_ports(self: &Arc<Self>, port_a: u16, port_b: u16) -> Result<(), String> {
+        if port_a == port_b {
+            return Err(format!("Cannot swap port {} with itself", port_a));
+        }
+        let _swapping = self.swapping.lock().await;
+        let (swapper_a, swapper_b) = {
+            let streams = self.streams.read().await;
+            let swapper = |port: u16| {
+                let info = streams
+                    .get(&port)
+                    .ok_or_else(|| format!("No stream found on port {}", port))?;
+                if info.hls.is_some() {
+                    return Err(format!("Stream on port {} is served over HLS", port));
+                }
+                let sender = info.state
+                    .port_swap
+                    .lock()
+                    .unwrap()
+                    .clone()
+                    .ok_or_else(|| format!("Stream on port {} is not serving clients", port))?;
+                Ok((sender, info.state.port_swap.clone()))
+            };
+            (swapper(port_a)?, swapper(port_b)?)
+        };
+
+        // Spawned so the listeners are handed back and the entries follow
+        // them even if the caller goes away. The entries are moved under one
+        // write lock, and only if they still belong to the servers that
+        // switched: a stream stopped during the exchange is not resurrected,
+        // and nothing else is moved in its place.
+        let manager = Arc::clone(self);
+        let exchange = tokio::spawn(async move {
+            let ((sender_a, server_a), (sender_b, server_b)) = (swapper_a, swapper_b);
+            if !exchange_listeners((sender_a, port_a), (sender_b, port_b)).await {
+                return false;
+            }
+            let mut streams = manager.streams.write().await;
+            let a = take_served_by(&mut streams, port_a, &server_a);
+            let b = take_served_by(&mut streams, port_b, &server_b);
+            if let Some(a) = a {
+                streams.insert(port_b, a);
+            }
+            if let Some(b) = b {
+                streams.insert(port_a, b);
+            }
+            true
+        });
+        if !exchange.await.unwrap_or(false) {
+            return Err(format!("Streams on ports {} and {} did not switch ports", port_a, port_b));
+        }
+        Ok(())
+    }
+}
+
+// Remove the entry of `port` if it is still the stream whose server listens
+// for swaps on `server`
+fn take_served_by(
+    streams: &mut HashMap<u16, StreamInfo>,
+    port: u16,
+    server: &Arc<Mutex<Option<mpsc::UnboundedSender<PortSwap>>>>,
+) -> Option<StreamInfo> {
+    match streams.get(&port) {
+        Some(info) if Arc::ptr_eq(&info.state.port_swap, server) => streams.remove(&port),
+        _ => None,
+    }
+}
+
+// How long swap_ports waits for both servers to hand over their listeners
+const PORT_SWAP_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Sent to a stream's server by swap_ports: hand the listener over through
+// `give` and continue with the listener and port that come back on `take`.
+// That is the other server's, or its own if the swap failed.
+struct PortSwap {
+    give: oneshot::Sender<TcpListener>,
+    take: oneshot::Receiver<(TcpListener, u16)>,
+}
+
+// Collect the listeners of both servers and give each the other's. If either
+// doesn't hand its listener over in time, the one that did gets its own back.
+// Returns whether the servers switched.
+async fn exchange_listeners(
+    (swapper_a, port_a): (mpsc::UnboundedSender<PortSwap>, u16),
+    (swapper_b, port_b): (mpsc::UnboundedSender<PortSwap>, u16),
+) -> bool {
+    let request = |swapper: &mpsc::UnboundedSender<PortSwap>| {
+        let (give_tx, give_rx) = oneshot::channel();
+        let (take_tx, take_rx) = oneshot::channel();
+        let sent = swapper.send(PortSwap { give: give_tx, take: take_rx }).is_ok();
+        (sent.then_some(give_rx), take_tx)
+    };
+    let ((give_a, take_a), (give_b, take_b)) = (request(&swapper_a), request(&swapper_b));
+    let collect = |give: Option<oneshot::Receiver<TcpListener>>| async move {
+        tokio::time::timeout(PORT_SWAP_TIMEOUT, give?).await.ok()?.ok()
+    };
+    let (listener_a, listener_b) = tokio::join!(collect(give_a), collect(give_b));
+
+    match (listener_a, listener_b) {
+        (Some(listener_a), Some(listener_b)) => {
+            // A server only drops `take` if its task died, and then its
+            // stream is gone anyway
+            let _ = take_a.send((listener_b, port_b));
+            let _ = take_b.send((listener_a, port_a));
+            true
+        }
+        (listener_a, listener_b) => {
+            if let Some(listener) = listener_a {
+                let _ = take_a.send((listener, port_a));
+            }
+            if let Some(listener) = listener_b {
+                let _ = take_b.send((listener, port_b));
+            }
+            false
+        }
+    }
 }
 
 struct StreamInfo {
     rtsp_url: String,
+    options: StreamOptions,
     shutdown_tx: broadcast::Sender<()>,
+    state: StreamState,
+    // Set for streams served over HLS instead of WebSocket
+    hls: Option<HlsConfig>,
+}
+
+impl StreamInfo {
+    // Resources of the stream's FFmpeg, for the stream limits
+    fn load(&self) -> Load {
+        Load {
+            priority: self.options.priority,
+            bitrate_kbps: self.state.bitrate_cap().unwrap_or(self.options.encode.bitrate_kbps),
+            degradable: self.hls.is_none() && !self.state.video_copy,
+        }
+    }
+
+    // Whether the stream's FFmpeg writes a recording or shadow segments
+    fn writes_files(&self) -> bool {
+        let recording = self.options.recording_config.is_some() && self.state.recording.load(Ordering::SeqCst);
+        self.hls.is_none() && (recording || self.state.shadow.lock().unwrap().is_some())
+    }
+}
+
+// Ports changed by StreamManager::admit to make room for a new stream
+#[derive(Default)]
+struct Preempted {
+    stopped: Vec<u16>,
+    // Port and its new bitrate in kbps
+    degraded: Vec<(u16, u32)>,
+}
+
+// Payload of the stream://degraded event
+#[derive(Serialize, Clone)]
+struct StreamDegraded {
+    ws_port: u16,
+    bitrate_kbps: u32,
+}
+
+// Report the streams degraded or stopped for a higher-priority one
+fn emit_preempted(app: &AppHandle, preempted: &Preempted) {
+    for &(ws_port, bitrate_kbps) in &preempted.degraded {
+        let _ = app.emit("stream://degraded", StreamDegraded { ws_port, bitrate_kbps });
+    }
+    for &ws_port in &preempted.stopped {
+        let _ = app.emit(
+            "stream://stopped",
+            StreamStopped {
+                ws_port,
+                reason: "preempted".to_string(),
+            },
+        );
+    }
+}
+
+// Result of drain_recordings, by stream port
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct DrainReport {
+    pub finalized: Vec<u16>,
+    pub timed_out: Vec<u16>,
+}
+
+// Runtime state shared between a stream's manager entry and its tasks
+#[derive(Clone, Default)]
+struct StreamState {
+    logs: LogBuffer,
+    quality: Arc<Mutex<StreamQuality>>,
+    // Source details from ffprobe, when the source was probed
+    media: Arc<Mutex<Option<MediaInfo>>>,
+    // Live output stream-copies the source instead of re-encoding
+    video_copy: bool,
+    // FFmpeg binary for this stream only, overriding get_ffmpeg_path
+    ffmpeg_path: Option<PathBuf>,
+    // Pending stream://needs_url request, answered by provide_stream_url
+    url_request: Arc<Mutex<Option<oneshot::Sender<String>>>>,
+    // Video messages buffered for WebSocket clients
+    relay: Arc<VideoRelay>,
+    // fMP4 init segment (H.264 mode), sent to each client before any media
+    init_segment: Arc<Mutex<Option<Vec<u8>>>>,
+    // Recording output enabled; cleared when the disk runs low
+    recording: Arc<AtomicBool>,
+    // File the current FFmpeg run records to (see disk::recording_file)
+    recording_file: Arc<Mutex<Option<PathBuf>>>,
+    // The stream's current FFmpeg, killed to restart it
+    process_slot: ProcessSlot,
+    // Source URL for the next FFmpeg start, set by update_credentials
+    pending_url: Arc<Mutex<Option<String>>>,
+    // Socket options read back from the latest accepted client
+    socket: Arc<Mutex<Option<SocketInfo>>>,
+    // Set to restart FFmpeg without the reconnect delay
+    restart: Arc<AtomicBool>,
+    // How long FFmpeg may take to quit on its own before it is killed
+    graceful_stop: Duration,
+    // Active shadow buffer; applied on the next FFmpeg start
+    shadow: Arc<Mutex<Option<ShadowConfig>>>,
+    // Connected WebSocket clients and the periods the stream was watched
+    watch: WatchHistory,
+    // Ports served by this FFmpeg through share_source
+    share: SourceShare,
+    // Sampled metrics history and the FFmpeg counters it is built from
+    metrics: Arc<Mutex<MetricsHistory>>,
+    // Bitrate lowered by preemption; applied on the next FFmpeg start
+    degraded_bitrate: Arc<Mutex<Option<u32>>>,
+    // When the source was last probed again by reprobe_source
+    last_reprobe: Arc<Mutex<Option<std::time::Instant>>>,
+    // WebSocket port the stream is served on; changed by swap_ports
+    port: Arc<AtomicU16>,
+    // Set while the server accepts clients, to hand its listener to swap_ports
+    port_swap: Arc<Mutex<Option<mpsc::UnboundedSender<PortSwap>>>>,
+    // Unix time in ms of the latest video from FFmpeg, 0 before any
+    last_data_at: Arc<AtomicU64>,
+    // Bitrate chosen from client feedback; applied on the next FFmpeg start
+    abr: Arc<Mutex<AbrState>>,
+    // FFmpeg crashes since the stream started, and how its last run ended
+    crash_count: Arc<AtomicU32>,
+    last_exit: Arc<Mutex<Option<ExitKind>>>,
+    // Live output frames delivered by all FFmpeg runs so far, for max_frames
+    frames_delivered: Arc<AtomicU64>,
+}
+
+impl StreamState {
+    // FFmpeg binary the stream runs: its own ffmpeg_path or the global one
+    fn ffmpeg(&self) -> String {
+        stream_ffmpeg(self.ffmpeg_path.as_deref())
+    }
+
+    // Lowest of the bitrates set by preemption and client feedback, if any
+    fn bitrate_cap(&self) -> Option<u32> {
+        let degraded = *self.degraded_bitrate.lock().unwrap();
+        let adapted = self.abr.lock().unwrap().bitrate_kbps;
+        degraded.into_iter().chain(adapted).min()
+    }
+
+    fn health(&self, now: u64) -> StreamHealth {
+        let last_data_at = Some(self.last_data_at.load(Ordering::SeqCst)).filter(|&at| at > 0);
+        let idle_ms = last_data_at.map(|at| now.saturating_sub(at));
+        let health = match idle_ms {
+            None => Health::NoData,
+            Some(idle) if idle > STALL_THRESHOLD.as_millis() as u64 => Health::Stalled,
+            Some(_) => Health::Healthy,
+        };
+        StreamHealth {
+            health,
+            last_data_at,
+            idle_ms,
+        }
+    }
+
+    // Relay a new fMP4 init segment. It is stored and sent under the init
+    // lock, so a client subscribing at the same time gets it from exactly one
+    // of the two (see subscribe).
+    fn send_init(&self, init: Vec<u8>) {
+        let mut current = self.init_segment.lock().unwrap();
+        *current = Some(init.clone());
+        self.relay.send(init);
+    }
+
+    // Subscribe to the video relay, with the init segment to send first
+    fn subscribe(&self) -> (RelayReceiver, Option<Vec<u8>>) {
+        let init = self.init_segment.lock().unwrap();
+        (self.relay.subscribe(), init.clone())
+    }
+
+    // Restart FFmpeg right away to apply changed settings. WebSocket clients
+    // stay connected through the restart.
+    fn request_restart(&self) {
+        self.restart.store(true, Ordering::SeqCst);
+        let (process_slot, timeout) = (self.process_slot.clone(), self.graceful_stop);
+        tokio::spawn(async move { process_slot.restart_gracefully(timeout).await });
+    }
+
+    // Options for the next FFmpeg run: the stream's options with what
+    // changed since it started (recording dropped or renamed, shadow buffer
+    // toggled, bitrate lowered, frames already delivered)
+    fn run_options(&self, options: &StreamOptions) -> StreamOptions {
+        let mut options = options.clone();
+        if !self.recording.load(Ordering::SeqCst) {
+            options.recording_config = None;
+        }
+        if let Some(recording) = options.recording_config.as_mut() {
+            let file = disk::recording_file(&recording.output_path);
+            if file != recording.output_path {
+                self.logs.push(format!(
+                    "{} exists, recording to {}",
+                    recording.output_path.display(),
+                    file.display()
+                ));
+            }
+            recording.output_path = file;
+        }
+        *self.recording_file.lock().unwrap() =
+            options.recording_config.as_ref().map(|recording| recording.output_path.clone());
+        options.shadow_buffer = self.shadow.lock().unwrap().clone();
+        if let Some(bitrate_kbps) = self.bitrate_cap() {
+            options.encode.cap_bitrate(bitrate_kbps);
+        }
+        // A reconnected or restarted FFmpeg only delivers the frames still missing
+        let delivered = self.frames_delivered.load(Ordering::SeqCst);
+        options.max_frames = options.max_frames.map(|max_frames| max_frames.saturating_sub(delivered).max(1));
+        options
+    }
+}
+
+// Effective options of a client socket, as reported by the OS (which may
+// round or double requested buffer sizes)
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SocketInfo {
+    pub nodelay: bool,
+    pub send_buffer_bytes: usize,
+    pub recv_buffer_bytes: usize,
+    pub keepalive: bool,
+}
+
+// Recent FFmpeg stderr lines for a stream, shared with the stderr reader thread
+#[derive(Clone, Default)]
+struct LogBuffer(Arc<Mutex<VecDeque<String>>>);
+
+impl LogBuffer {
+    const CAPACITY: usize = 200;
+
+    fn push(&self, line: String) {
+        let mut lines = self.0.lock().unwrap();
+        if lines.len() == Self::CAPACITY {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+
+    fn lines(&self) -> Vec<String> {
+        self.0.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+// Number of stream ports relaying one FFmpeg (share_source). The port that
+// started FFmpeg keeps it running until the count drops to zero.
+#[derive(Clone)]
+struct SourceShare(Arc<tokio::sync::watch::Sender<usize>>);
+
+impl Default for SourceShare {
+    fn default() -> Self {
+        Self(Arc::new(tokio::sync::watch::channel(1).0))
+    }
+}
+
+impl SourceShare {
+    // Add a port, unless the last one already left and FFmpeg is stopping
+    fn try_acquire(&self) -> bool {
+        self.0.send_if_modified(|ports| {
+            if *ports == 0 {
+                return false;
+            }
+            *ports += 1;
+            true
+        })
+    }
+
+    // Returns the number of ports still sharing the source
+    fn release(&self) -> usize {
+        self.0.send_modify(|ports| *ports = ports.saturating_sub(1));
+        *self.0.borrow()
+    }
+
+    fn ports(&self) -> usize {
+        *self.0.borrow()
+    }
+
+    fn same(&self, other: &SourceShare) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+
+    async fn wait_released(&self) {
+        let _ = self.0.subscribe().wait_for(|ports| *ports == 0).await;
+    }
+}
+
+// A period during which a stream had at least one WebSocket client. Times
+// are Unix milliseconds; ended_at_ms is None while the stream is watched.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WatchInterval {
+    pub started_at_ms: u64,
+    pub ended_at_ms: Option<u64>,
+}
+
+// A connected WebSocket client and what it negotiated during the handshake
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ClientInfo {
+    pub id: u64,
+    pub address: String,
+    pub connected_at_ms: u64,
+    // Sec-WebSocket-Protocol values the client offered, in its order
+    pub offered_subprotocols: Vec<String>,
+    // Subprotocol echoed back; None if the client offered none
+    pub subprotocol: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+#[derive(Default)]
+struct WatchState {
+    clients: Vec<ClientInfo>,
+    next_id: u64,
+    intervals: VecDeque<WatchInterval>,
+}
+
+// Connected clients of a stream, with an interval opened on every 0->1 client
+// transition and closed on 1->0
+#[derive(Clone, Default)]
+struct WatchHistory(Arc<Mutex<WatchState>>);
+
+impl WatchHistory {
+    const CAPACITY: usize = 100;
+
+    // Register a client; returns the id to pass to disconnected
+    fn connected(&self, mut client: ClientInfo) -> u64 {
+        let mut watch = self.0.lock().unwrap();
+        watch.next_id += 1;
+        client.id = watch.next_id;
+        client.connected_at_ms = unix_millis();
+        watch.clients.push(client);
+        if watch.clients.len() == 1 {
+            if watch.intervals.len() == Self::CAPACITY {
+                watch.intervals.pop_front();
+            }
+            watch.intervals.push_back(WatchInterval {
+                started_at_ms: unix_millis(),
+                ended_at_ms: None,
+            });
+        }
+        watch.next_id
+    }
+
+    fn disconnected(&self, id: u64) {
+        let mut watch = self.0.lock().unwrap();
+        watch.clients.retain(|client| client.id != id);
+        if watch.clients.is_empty() {
+            if let Some(interval) = watch.intervals.back_mut() {
+                interval.ended_at_ms.get_or_insert_with(unix_millis);
+            }
+        }
+    }
+
+    fn clients(&self) -> usize {
+        self.0.lock().unwrap().clients.len()
+    }
+
+    fn client_list(&self) -> Vec<ClientInfo> {
+        self.0.lock().unwrap().clients.clone()
+    }
+
+    fn intervals(&self) -> Vec<WatchInterval> {
+        self.0.lock().unwrap().intervals.iter().cloned().collect()
+    }
+}
+
+fn unix_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or(0)
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -130,6 +801,25 @@ pub struct StreamStatus {
     pub rtsp_url: String,
     pub ws_url: String,
     pub active: bool,
+    pub recording_path: Option<String>,
+    pub rate_control: RateControl,
+    pub source: Option<MediaInfo>,
+    pub video_copy: bool,
+    pub whep_url: Option<String>,
+    pub hls_url: Option<String>,
+    // Ports relaying the same FFmpeg through share_source (1 = not shared)
+    pub source_ports: usize,
+    // Per-stream FFmpeg binary, if start_stream was given one
+    pub ffmpeg_path: Option<String>,
+    pub pipe_path: Option<String>,
+    pub priority: u8,
+    // Bitrate the stream was lowered to for a higher-priority stream
+    pub degraded_bitrate_kbps: Option<u32>,
+    // Bitrate chosen by client_abr, while below the configured one
+    pub abr_bitrate_kbps: Option<u32>,
+    pub crash_count: u32,
+    // How FFmpeg's last run ended: "eof", "source_error" or "crash"
+    pub last_exit: Option<ExitKind>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -138,6 +828,8 @@ pub struct StreamResponse {
     pub message: String,
     pub ws_url: Option<String>,
     pub port: Option<u16>,
+    // Named pipe carrying the stream, with pipe_output
+    pub pipe_path: Option<String>,
 }
 
 // Start RTSP stream and create WebSocket relay
@@ -145,100 +837,814 @@ pub struct StreamResponse {
 async fn start_stream(
     rtsp_url: String,
     ws_port: u16,
+    options: Option<StreamOptions>,
+    ffmpeg_path: Option<PathBuf>,
+    app: AppHandle,
     stream_manager: State<'_, Arc<StreamManager>>,
+    settings_store: State<'_, SettingsStore>,
 ) -> Result<StreamResponse, String> {
-    log::info!("Received start_stream request: rtsp_url={}, ws_port={}", rtsp_url, ws_port);
+    log::info!(
+        "Received start_stream request: rtsp_url={}, ws_port={}",
+        rtsp_url::redact(&rtsp_url),
+        ws_port
+    );
 
-    // Check if port is already in use
-    {
-        let streams = stream_manager.streams.read().await;
-        if streams.contains_key(&ws_port) {
-            log::warn!("Port {} is already in use", ws_port);
+    let mut options = match options {
+        Some(options) => options,
+        None => settings_store.get().await.default_options,
+    };
+    let mut validation = options.validate();
+    if let (Ok(()), Some(path)) = (&validation, &ffmpeg_path) {
+        validation = ffmpeg::validate_ffmpeg_binary(path);
+    }
+    if let (Ok(()), Some(overlay)) = (&validation, &options.image_overlay) {
+        validation = ffmpeg::check_image_decodes(&stream_ffmpeg(ffmpeg_path.as_deref()), &overlay.path).await;
+    }
+    if validation.is_ok() {
+        // Fail early if the host has no address of the requested family
+        validation = rtsp_url::resolve_host(&rtsp_url, options.resolve).await.map(|_| ());
+    }
+    if let Err(e) = validation {
+        log::warn!("Invalid stream options: {}", e);
+        return Ok(StreamResponse {
+            success: false,
+            message: e,
+            ws_url: None,
+            port: None,
+            pipe_path: None,
+        });
+    }
+
+    if options.share_source {
+        let shared = share_stream(&rtsp_url, ws_port, &options, ffmpeg_path.as_deref(), stream_manager.inner()).await;
+        if let Some(response) = shared {
+            return Ok(response);
+        }
+    }
+
+    let verified = match verify_source(&stream_ffmpeg(ffmpeg_path.as_deref()), &rtsp_url, &options).await {
+        Ok(media) => media,
+        Err(e) => {
+            log::warn!("Source verification failed: {}", e);
             return Ok(StreamResponse {
                 success: false,
-                message: format!("Port {} is already in use", ws_port),
+                message: e,
                 ws_url: None,
                 port: None,
+                pipe_path: None,
             });
         }
+    };
+
+    if let Some(shadow) = &options.shadow_buffer {
+        match shadow::prepare(shadow, ws_port) {
+            Ok(shadow) => options.shadow_buffer = Some(shadow),
+            Err(e) => {
+                return Ok(StreamResponse {
+                    success: false,
+                    message: e,
+                    ws_url: None,
+                    port: None,
+                    pipe_path: None,
+                })
+            }
+        }
+    }
+
+    if let Some(pipe) = &options.pipe_output {
+        match pipe::prepare(pipe, ws_port, options.encode.codec) {
+            Ok(pipe) => options.pipe_output = Some(pipe),
+            Err(e) => {
+                return Ok(StreamResponse {
+                    success: false,
+                    message: e,
+                    ws_url: None,
+                    port: None,
+                    pipe_path: None,
+                })
+            }
+        }
     }
 
+    // Make sure the recording has room before starting anything
+    let disk_warnings = match options.recording_config.as_ref().map(disk::preflight) {
+        Some(Err(e)) => {
+            log::warn!("Recording preflight failed: {}", e);
+            return Ok(StreamResponse {
+                success: false,
+                message: e,
+                ws_url: None,
+                port: None,
+                pipe_path: None,
+            });
+        }
+        Some(Ok(warnings)) => warnings,
+        None => Vec::new(),
+    };
+
     let (shutdown_tx, _) = broadcast::channel::<()>(1);
     let shutdown_rx = shutdown_tx.subscribe();
-
-    // Store stream info
+    let mut state = StreamState {
+        relay: Arc::new(VideoRelay::new(options.buffer.capacity)),
+        metrics: Arc::new(Mutex::new(MetricsHistory::new(options.metrics.history))),
+        ffmpeg_path,
+        graceful_stop: Duration::from_millis(options.graceful_stop_timeout_ms),
+        ..StreamState::default()
+    };
+    state.port.store(ws_port, Ordering::SeqCst);
     {
-        let mut streams = stream_manager.streams.write().await;
-        streams.insert(
+        let mut quality = state.quality.lock().unwrap();
+        quality.monitoring = options.quality_monitor.is_some();
+        quality.timestamps = options.debug_timestamps.then(TimestampStats::default);
+    }
+    state.recording.store(options.recording_config.is_some(), Ordering::SeqCst);
+    *state.shadow.lock().unwrap() = options.shadow_buffer.clone();
+    *state.media.lock().unwrap() = verified.clone();
+    for warning in &disk_warnings {
+        log::warn!("{}", warning);
+        state.logs.push(warning.clone());
+    }
+
+    // Reserve the port before spawning anything
+    let limits = settings_store.get().await.stream_limits;
+    let admitted = stream_manager
+        .admit(
             ws_port,
             StreamInfo {
                 rtsp_url: rtsp_url.clone(),
+                options: options.clone(),
                 shutdown_tx: shutdown_tx.clone(),
+                state: state.clone(),
+                hls: None,
             },
-        );
+            &limits,
+        )
+        .await;
+    match admitted {
+        Ok(preempted) => emit_preempted(&app, &preempted),
+        Err(e) => {
+            log::warn!("Not starting stream {}: {}", ws_port, e);
+            return Ok(StreamResponse {
+                success: false,
+                message: e,
+                ws_url: None,
+                port: None,
+                pipe_path: None,
+            });
+        }
+    }
+
+    if options.encode.copy_when_compatible {
+        state.video_copy = resolve_video_copy(&rtsp_url, &options, &state, verified).await;
+        if let Some(info) = stream_manager.streams.write().await.get_mut(&ws_port) {
+            info.state.video_copy = state.video_copy;
+        }
     }
 
     let rtsp_url_clone = rtsp_url.clone();
-    let stream_manager_clone = Arc::clone(&stream_manager.inner());
+    let stream_manager_clone = Arc::clone(stream_manager.inner());
+    let server_shutdown_tx = shutdown_tx.clone();
+    let (ready_tx, ready_rx) = oneshot::channel();
+    let pipe_output = options.pipe_output.clone().map(|pipe| (pipe, options.encode.codec));
+    let pipe_path = pipe_output
+        .as_ref()
+        .and_then(|(pipe, _)| pipe.path.as_ref())
+        .map(|path| path.to_string_lossy().to_string());
 
     // Spawn the stream handler
     tokio::spawn(async move {
-        if let Err(e) = run_stream_server(rtsp_url_clone, ws_port, shutdown_rx).await {
+        if let Err(e) =
+            run_stream_server(app, rtsp_url_clone, ws_port, options, state, shutdown_rx, ready_tx).await
+        {
             log::error!("Stream server error: {}", e);
         }
 
         // Clean up on exit
-        let mut streams = stream_manager_clone.streams.write().await;
-        streams.remove(&ws_port);
+        stream_manager_clone.unregister(&server_shutdown_tx).await;
+        if let Some((pipe, codec)) = pipe_output {
+            pipe::remove(&pipe, ws_port, codec);
+        }
     });
 
+    // Only report success once the listener is actually bound
+    let bind_result = ready_rx
+        .await
+        .unwrap_or_else(|_| Err("Stream server exited before binding".to_string()));
+    if let Err(e) = bind_result {
+        stream_manager.unregister(&shutdown_tx).await;
+        return Ok(StreamResponse {
+            success: false,
+            message: format!("Failed to bind port {}: {}", ws_port, e),
+            ws_url: None,
+            port: None,
+            pipe_path: None,
+        });
+    }
+
+    let mut message = format!("Stream started on port {}", ws_port);
+    if !disk_warnings.is_empty() {
+        message = format!("{} (warning: {})", message, disk_warnings.join("; "));
+    }
     Ok(StreamResponse {
         success: true,
-        message: format!("Stream started on port {}", ws_port),
+        message,
         ws_url: Some(format!("ws://127.0.0.1:{}", ws_port)),
         port: Some(ws_port),
+        pipe_path,
     })
 }
 
-// Stop a running stream
-#[tauri::command]
-async fn stop_stream(
+// Whether a share_source request can attach to an existing stream: same
+// source URL (after normalization), FFmpeg binary and options, apart from the
+// per-port client settings and the shadow buffer, and FFmpeg not stopping
+fn can_share(info: &StreamInfo, rtsp_url: &str, options: &StreamOptions, ffmpeg_path: Option<&Path>) -> bool {
+    let normalize = |url: &str| {
+        rtsp_url::normalize_rtsp_url(url)
+            .map(|normalized| normalized.url)
+            .unwrap_or_else(|_| url.to_string())
+    };
+    let comparable = |options: &StreamOptions| {
+        let mut value = serde_json::to_value(options).unwrap_or_default();
+        if let Some(fields) = value.as_object_mut() {
+            for per_port in ["socket", "subprotocols", "shadow_buffer", "priority"] {
+                fields.remove(per_port);
+            }
+        }
+        value
+    };
+    info.hls.is_none()
+        && info.options.share_source
+        && normalize(&info.rtsp_url) == normalize(rtsp_url)
+        && comparable(&info.options) == comparable(options)
+        && info.state.ffmpeg_path.as_deref() == ffmpeg_path
+        && info.state.share.ports() > 0
+}
+
+// Serve an existing stream of the same source on a new port, without another
+// FFmpeg or camera session. None if there is no stream to share.
+async fn share_stream(
+    rtsp_url: &str,
     ws_port: u16,
-    stream_manager: State<'_, Arc<StreamManager>>,
-) -> Result<StreamResponse, String> {
+    options: &StreamOptions,
+    ffmpeg_path: Option<&Path>,
+    stream_manager: &Arc<StreamManager>,
+) -> Option<StreamResponse> {
     let mut streams = stream_manager.streams.write().await;
-
-    if let Some(info) = streams.remove(&ws_port) {
-        let _ = info.shutdown_tx.send(());
-        Ok(StreamResponse {
-            success: true,
-            message: format!("Stream on port {} stopped", ws_port),
+    let (&source_port, source) = streams
+        .iter()
+        .find(|(_, info)| can_share(info, rtsp_url, options, ffmpeg_path))?;
+    if streams.contains_key(&ws_port) {
+        log::warn!("Port {} is already in use", ws_port);
+        return Some(StreamResponse {
+            success: false,
+            message: format!("Port {} is already in use", ws_port),
             ws_url: None,
-            port: Some(ws_port),
-        })
-    } else {
-        Ok(StreamResponse {
+            port: None,
+            pipe_path: None,
+        });
+    }
+    // The last port may have left since the check
+    if !source.state.share.try_acquire() {
+        return None;
+    }
+
+    // Shares the relay and FFmpeg; client sockets and viewers are per port
+    let state = StreamState {
+        socket: Arc::default(),
+        watch: WatchHistory::default(),
+        port: Arc::new(AtomicU16::new(ws_port)),
+        port_swap: Arc::default(),
+        ..source.state.clone()
+    };
+    let info = StreamInfo {
+        rtsp_url: source.rtsp_url.clone(),
+        options: StreamOptions {
+            socket: options.socket.clone(),
+            subprotocols: options.subprotocols.clone(),
+            priority: options.priority,
+            pipe_output: None,
+            ..source.options.clone()
+        },
+        shutdown_tx: broadcast::channel::<()>(1).0,
+        state: state.clone(),
+        hls: None,
+    };
+    let shutdown_tx = info.shutdown_tx.clone();
+    let clients = ClientConfig {
+        subprotocols: Arc::new(info.options.subprotocols.clone()),
+        socket: info.options.socket.clone(),
+        state,
+    };
+    streams.insert(ws_port, info);
+    drop(streams);
+
+    let stream_manager_clone = Arc::clone(stream_manager);
+    let server_shutdown_tx = shutdown_tx.clone();
+    let shutdown_rx = shutdown_tx.subscribe();
+    let (ready_tx, ready_rx) = oneshot::channel();
+    tokio::spawn(async move {
+        run_shared_server(ws_port, clients, shutdown_rx, ready_tx).await;
+        stream_manager_clone.unregister(&server_shutdown_tx).await;
+    });
+
+    let bind_result = ready_rx
+        .await
+        .unwrap_or_else(|_| Err("Stream server exited before binding".to_string()));
+    if let Err(e) = bind_result {
+        stream_manager.unregister(&shutdown_tx).await;
+        return Some(StreamResponse {
             success: false,
-            message: format!("No stream found on port {}", ws_port),
+            message: format!("Failed to bind port {}: {}", ws_port, e),
             ws_url: None,
             port: None,
-        })
+            pipe_path: None,
+        });
     }
+
+    log::info!("Stream on port {} shares the source of port {}", ws_port, source_port);
+    Some(StreamResponse {
+        success: true,
+        message: format!("Stream started on port {} (sharing the source of port {})", ws_port, source_port),
+        ws_url: Some(format!("ws://127.0.0.1:{}", ws_port)),
+        port: Some(ws_port),
+        pipe_path: None,
+    })
 }
 
-// Get all active streams
-#[tauri::command]
-async fn get_active_streams(
-    stream_manager: State<'_, Arc<StreamManager>>,
-) -> Result<Vec<StreamStatus>, String> {
-    let streams = stream_manager.streams.read().await;
-    let statuses: Vec<StreamStatus> = streams
-        .iter()
-        .map(|(port, info)| StreamStatus {
+// Serve WebSocket clients of a shared source on another port. The FFmpeg
+// belongs to the stream that started it.
+async fn run_shared_server(
+    ws_port: u16,
+    clients: ClientConfig,
+    shutdown_rx: broadcast::Receiver<()>,
+    ready_tx: oneshot::Sender<Result<(), String>>,
+) {
+    let share = clients.state.share.clone();
+    let listener = match bind_listener(ws_port) {
+        Ok(listener) => listener,
+        Err(e) => {
+            let _ = ready_tx.send(Err(e.to_string()));
+            share.release();
+            return;
+        }
+    };
+    let _ = ready_tx.send(Ok(()));
+
+    serve_clients(
+        listener,
+        ws_port,
+        clients,
+        #[cfg(feature = "webrtc")]
+        None,
+        shutdown_rx,
+    )
+    .await;
+    share.release();
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct HlsStreamResponse {
+    pub success: bool,
+    pub message: String,
+    pub hls_url: Option<String>,
+    pub port: Option<u16>,
+}
+
+fn hls_url(port: u16) -> String {
+    format!("http://127.0.0.1:{}/{}", port, hls::PLAYLIST)
+}
+
+// Start an RTSP stream served as HLS (H.264 segments and a playlist over HTTP)
+// for players without MSE or jsmpeg support, e.g. iOS Safari. Stopped with
+// stop_stream like any other stream.
+#[tauri::command]
+async fn start_hls_stream(
+    rtsp_url: String,
+    port: u16,
+    options: Option<StreamOptions>,
+    hls: Option<HlsConfig>,
+    app: AppHandle,
+    stream_manager: State<'_, Arc<StreamManager>>,
+    settings_store: State<'_, SettingsStore>,
+) -> Result<HlsStreamResponse, String> {
+    log::info!(
+        "Received start_hls_stream request: rtsp_url={}, port={}",
+        rtsp_url::redact(&rtsp_url),
+        port
+    );
+
+    let options = match options {
+        Some(options) => options,
+        None => settings_store.get().await.default_options,
+    };
+    let hls = hls.unwrap_or_default();
+    let mut validation = options.validate().and_then(|_| hls.validate(&options));
+    if validation.is_ok() {
+        validation = rtsp_url::resolve_host(&rtsp_url, options.resolve).await.map(|_| ());
+    }
+    if validation.is_ok() {
+        validation = verify_source(&get_ffmpeg_path(), &rtsp_url, &options).await.map(|_| ());
+    }
+    if let Err(e) = validation {
+        log::warn!("Invalid HLS stream options: {}", e);
+        return Ok(HlsStreamResponse {
+            success: false,
+            message: e,
+            hls_url: None,
+            port: None,
+        });
+    }
+
+    let (shutdown_tx, _) = broadcast::channel::<()>(1);
+    let shutdown_rx = shutdown_tx.subscribe();
+    let state = StreamState {
+        graceful_stop: Duration::from_millis(options.graceful_stop_timeout_ms),
+        ..StreamState::default()
+    };
+    state.port.store(port, Ordering::SeqCst);
+
+    let limits = settings_store.get().await.stream_limits;
+    let admitted = stream_manager
+        .admit(
+            port,
+            StreamInfo {
+                rtsp_url: rtsp_url.clone(),
+                options: options.clone(),
+                shutdown_tx: shutdown_tx.clone(),
+                state: state.clone(),
+                hls: Some(hls.clone()),
+            },
+            &limits,
+        )
+        .await;
+    match admitted {
+        Ok(preempted) => emit_preempted(&app, &preempted),
+        Err(e) => {
+            log::warn!("Not starting HLS stream {}: {}", port, e);
+            return Ok(HlsStreamResponse {
+                success: false,
+                message: e,
+                hls_url: None,
+                port: None,
+            });
+        }
+    }
+
+    let runner = FfmpegRunner {
+        app,
+        options,
+        process_slot: state.process_slot.clone(),
+        state,
+        rtp_port: None,
+        hls: Some((hls, hls::output_dir(port))),
+    };
+    let manager = Arc::clone(stream_manager.inner());
+    let server_shutdown_tx = shutdown_tx.clone();
+    let (ready_tx, ready_rx) = oneshot::channel();
+    tokio::spawn(async move {
+        if let Err(e) = hls::run_hls_server(runner, rtsp_url, port, shutdown_rx, ready_tx).await {
+            log::error!("HLS server error: {}", e);
+        }
+        manager.unregister(&server_shutdown_tx).await;
+    });
+
+    let bind_result = ready_rx
+        .await
+        .unwrap_or_else(|_| Err("HLS server exited before binding".to_string()));
+    if let Err(e) = bind_result {
+        stream_manager.unregister(&shutdown_tx).await;
+        return Ok(HlsStreamResponse {
+            success: false,
+            message: format!("Failed to start HLS on port {}: {}", port, e),
+            hls_url: None,
+            port: None,
+        });
+    }
+
+    Ok(HlsStreamResponse {
+        success: true,
+        message: format!("HLS stream started on port {}", port),
+        hls_url: Some(hls_url(port)),
+        port: Some(port),
+    })
+}
+
+// Probe the source and decide whether the live output can stream-copy it.
+// Any reason to fall back to transcoding is noted in the stream log.
+async fn resolve_video_copy(
+    rtsp_url: &str,
+    options: &StreamOptions,
+    state: &StreamState,
+    probed: Option<MediaInfo>,
+) -> bool {
+    let media = match probed {
+        Some(media) => Ok(media),
+        None => probe::probe_source(&state.ffmpeg(), rtsp_url, options, Duration::from_secs(10)).await,
+    };
+    let decision = match media {
+        Ok(media) => {
+            let decision = ffmpeg::can_copy_video(options, &media);
+            *state.media.lock().unwrap() = Some(media);
+            decision
+        }
+        Err(e) => Err(format!("probe failed: {}", e)),
+    };
+
+    match decision {
+        Ok(()) => {
+            log::info!("Source is already {}, stream-copying", options.encode.codec.codec_name());
+            state.logs.push("Stream copy enabled: source matches the output codec".to_string());
+            true
+        }
+        Err(reason) => {
+            log::info!("Stream copy not possible, transcoding: {}", reason);
+            state.logs.push(format!("Stream copy not possible, transcoding: {}", reason));
+            false
+        }
+    }
+}
+
+// Minimum time between two re-probes of a stream's source
+const REPROBE_MIN_INTERVAL: Duration = Duration::from_secs(30);
+const REPROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Payload of the stream://source_changed event
+#[derive(Serialize, Clone)]
+struct SourceChanged {
+    ws_port: u16,
+    source: MediaInfo,
+}
+
+// Probe a running stream's source again and store the result as its source
+// info, emitting stream://source_changed if it differs. At most one probe runs
+// per REPROBE_MIN_INTERVAL, so frequent triggers don't load the camera.
+async fn reprobe_source(
+    app: &AppHandle,
+    ws_port: u16,
+    rtsp_url: &str,
+    options: &StreamOptions,
+    state: &StreamState,
+) -> Result<MediaInfo, String> {
+    if options.input_format.is_some() {
+        return Err("Capture devices cannot be probed".to_string());
+    }
+    {
+        let mut last_reprobe = state.last_reprobe.lock().unwrap();
+        if let Some(elapsed) = last_reprobe.map(|at| at.elapsed()).filter(|elapsed| *elapsed < REPROBE_MIN_INTERVAL) {
+            return Err(format!(
+                "Source was probed {}s ago, try again in {}s",
+                elapsed.as_secs(),
+                (REPROBE_MIN_INTERVAL - elapsed).as_secs() + 1
+            ));
+        }
+        *last_reprobe = Some(std::time::Instant::now());
+    }
+
+    let url = rtsp_url::resolve_host(rtsp_url, options.resolve).await?;
+    let media = probe::probe_source(&state.ffmpeg(), &url, options, REPROBE_TIMEOUT).await?;
+    let previous = state.media.lock().unwrap().replace(media.clone());
+    if previous.as_ref() != Some(&media) {
+        log::info!(
+            "Stream {} source is now {} {}x{}",
+            ws_port,
+            media.codec,
+            media.width,
+            media.height
+        );
+        state.logs.push(format!("Source changed: {} {}x{}", media.codec, media.width, media.height));
+        let _ = app.emit(
+            "stream://source_changed",
+            SourceChanged {
+                ws_port,
+                source: media.clone(),
+            },
+        );
+    }
+    Ok(media)
+}
+
+// With verify_before_start, probe the source (through the resolve setting)
+// so a stream is only started for a reachable source. Returns the probe
+// result, or None when verification is off or the source is a capture device.
+async fn verify_source(ffmpeg: &str, rtsp_url: &str, options: &StreamOptions) -> Result<Option<MediaInfo>, String> {
+    if !options.verify_before_start || options.input_format.is_some() {
+        return Ok(None);
+    }
+    let url = rtsp_url::resolve_host(rtsp_url, options.resolve).await?;
+    let timeout = Duration::from_millis(options.verify_timeout_ms);
+    probe::probe_source(ffmpeg, &url, options, timeout)
+        .await
+        .map(Some)
+        .map_err(|e| format!("Source is not reachable: {}", e))
+}
+
+// Probe the source of a running stream again, e.g. after the camera changed
+// modes, so get_active_streams reports what it sends now
+#[tauri::command]
+async fn reprobe_stream(
+    ws_port: u16,
+    app: AppHandle,
+    stream_manager: State<'_, Arc<StreamManager>>,
+) -> Result<MediaInfo, String> {
+    let (rtsp_url, options, state) = {
+        let streams = stream_manager.streams.read().await;
+        let info = streams
+            .get(&ws_port)
+            .ok_or_else(|| format!("No stream found on port {}", ws_port))?;
+        (info.rtsp_url.clone(), info.options.clone(), info.state.clone())
+    };
+    reprobe_source(&app, ws_port, &rtsp_url, &options, &state).await
+}
+
+// Stop a running stream
+#[tauri::command]
+async fn stop_stream(
+    ws_port: u16,
+    stream_manager: State<'_, Arc<StreamManager>>,
+) -> Result<StreamResponse, String> {
+    let mut streams = stream_manager.streams.write().await;
+
+    if let Some(info) = streams.remove(&ws_port) {
+        let _ = info.shutdown_tx.send(());
+        Ok(StreamResponse {
+            success: true,
+            message: format!("Stream on port {} stopped", ws_port),
+            ws_url: None,
+            port: Some(ws_port),
+            pipe_path: None,
+        })
+    } else {
+        Ok(StreamResponse {
+            success: false,
+            message: format!("No stream found on port {}", ws_port),
+            ws_url: None,
+            port: None,
+            pipe_path: None,
+        })
+    }
+}
+
+// Payload of the stream://ports_swapped event
+#[derive(Serialize, Clone)]
+struct PortsSwapped {
+    port_a: u16,
+    port_b: u16,
+}
+
+// Exchange the ports of two running streams. Clients are disconnected and
+// reconnect to whatever stream now serves their port.
+#[tauri::command]
+async fn swap_ports(
+    port_a: u16,
+    port_b: u16,
+    app: AppHandle,
+    stream_manager: State<'_, Arc<StreamManager>>,
+) -> Result<(), String> {
+    stream_manager.swap_ports(port_a, port_b).await?;
+    log::info!("Swapped the streams on ports {} and {}", port_a, port_b);
+    let _ = app.emit("stream://ports_swapped", PortsSwapped { port_a, port_b });
+    Ok(())
+}
+
+// A stream is stalled once FFmpeg has delivered no video for this long
+const STALL_THRESHOLD: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Health {
+    Healthy,
+    // Video arrived before but not within STALL_THRESHOLD
+    Stalled,
+    // No video since the stream started
+    NoData,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StreamHealth {
+    pub health: Health,
+    // Unix time in ms of the latest video
+    pub last_data_at: Option<u64>,
+    pub idle_ms: Option<u64>,
+}
+
+// Health of every stream, from when each last received video. Only reads
+// atomics, so a wedged stream can't hold up the answer for the others.
+#[tauri::command]
+async fn probe_all_streams(
+    stream_manager: State<'_, Arc<StreamManager>>,
+) -> Result<HashMap<u16, StreamHealth>, String> {
+    let now = unix_millis();
+    let streams = stream_manager.streams.read().await;
+    Ok(streams
+        .iter()
+        .map(|(&port, info)| (port, info.state.health(now)))
+        .collect())
+}
+
+// Payload of the stream://bitrate_adapted event
+#[derive(Serialize, Clone)]
+struct BitrateAdapted {
+    ws_port: u16,
+    bitrate_kbps: u32,
+}
+
+// Report a client's playback health to a stream started with client_abr.
+// The bitrate steps down while the client lags or drops frames, and back up
+// once it has kept up for a while. Each change restarts FFmpeg.
+#[tauri::command]
+async fn push_client_feedback(
+    ws_port: u16,
+    feedback: ClientFeedback,
+    app: AppHandle,
+    stream_manager: State<'_, Arc<StreamManager>>,
+) -> Result<AbrStatus, String> {
+    let streams = stream_manager.streams.read().await;
+    let info = streams
+        .get(&ws_port)
+        .ok_or_else(|| format!("No stream found on port {}", ws_port))?;
+    let status = apply_client_feedback(ws_port, info, &feedback, std::time::Instant::now())?;
+    if status.changed {
+        let bitrate_kbps = status.bitrate_kbps;
+        let _ = app.emit("stream://bitrate_adapted", BitrateAdapted { ws_port, bitrate_kbps });
+    }
+    Ok(status)
+}
+
+// Feed a report to the stream's adaptation state and restart FFmpeg if the
+// bitrate changed; the next run picks it up through StreamState::run_options
+fn apply_client_feedback(
+    ws_port: u16,
+    info: &StreamInfo,
+    feedback: &ClientFeedback,
+    now: std::time::Instant,
+) -> Result<AbrStatus, String> {
+    let config = info
+        .options
+        .client_abr
+        .as_ref()
+        .ok_or_else(|| format!("Stream on port {} was not started with client_abr", ws_port))?;
+    if info.state.video_copy {
+        return Err(format!("Stream on port {} is stream-copied; its bitrate can't change", ws_port));
+    }
+
+    let ceiling = info.options.encode.bitrate_kbps;
+    let changed = info.state.abr.lock().unwrap().observe(config, ceiling, feedback, now);
+    if let Some(bitrate_kbps) = changed {
+        log::info!(
+            "Stream {}: client reported {} ms lag, {} dropped frames; bitrate now {} kbps",
+            ws_port,
+            feedback.lag_ms,
+            feedback.dropped_frames,
+            bitrate_kbps
+        );
+        info.state.logs.push(format!("Bitrate adapted to {} kbps from client feedback", bitrate_kbps));
+        info.state.request_restart();
+    }
+    Ok(AbrStatus {
+        bitrate_kbps: info.state.bitrate_cap().unwrap_or(ceiling),
+        changed: changed.is_some(),
+    })
+}
+
+// Get all active streams
+#[tauri::command]
+async fn get_active_streams(
+    stream_manager: State<'_, Arc<StreamManager>>,
+) -> Result<Vec<StreamStatus>, String> {
+    let streams = stream_manager.streams.read().await;
+    let statuses: Vec<StreamStatus> = streams
+        .iter()
+        .map(|(port, info)| StreamStatus {
             port: *port,
-            rtsp_url: info.rtsp_url.clone(),
+            rtsp_url: rtsp_url::redact(&info.rtsp_url),
             ws_url: format!("ws://127.0.0.1:{}", port),
             active: true,
+            recording_path: info
+                .state
+                .recording_file
+                .lock()
+                .unwrap()
+                .as_ref()
+                .filter(|_| info.state.recording.load(Ordering::SeqCst))
+                .map(|path| path.to_string_lossy().to_string()),
+            rate_control: info.options.encode.rate_control(),
+            source: info.state.media.lock().unwrap().clone(),
+            video_copy: info.state.video_copy,
+            whep_url: info.options.webrtc.then(|| format!("http://127.0.0.1:{}/whep", port)),
+            hls_url: info.hls.as_ref().map(|_| hls_url(*port)),
+            source_ports: info.state.share.ports(),
+            ffmpeg_path: info.state.ffmpeg_path.as_ref().map(|path| path.to_string_lossy().to_string()),
+            pipe_path: info
+                .options
+                .pipe_output
+                .as_ref()
+                .and_then(|pipe| pipe.path.as_ref())
+                .map(|path| path.to_string_lossy().to_string()),
+            priority: info.options.priority,
+            degraded_bitrate_kbps: *info.state.degraded_bitrate.lock().unwrap(),
+            abr_bitrate_kbps: info.state.abr.lock().unwrap().bitrate_kbps,
+            crash_count: info.state.crash_count.load(Ordering::SeqCst),
+            last_exit: *info.state.last_exit.lock().unwrap(),
         })
         .collect();
     Ok(statuses)
@@ -261,105 +1667,1148 @@ async fn check_ffmpeg() -> Result<bool, String> {
     }
 }
 
-// Run the WebSocket server that relays FFmpeg output
-async fn run_stream_server(
-    rtsp_url: String,
-    ws_port: u16,
-    mut shutdown_rx: broadcast::Receiver<()>,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    log::info!("Attempting to bind WebSocket server on port {}", ws_port);
-
-    // Create socket with SO_REUSEADDR to allow quick rebinding
-    let addr: SocketAddr = format!("127.0.0.1:{}", ws_port).parse().unwrap();
-    let socket = Socket::new(Domain::IPV4, Type::STREAM, None)?;
-    socket.set_reuse_address(true)?;
-    socket.bind(&addr.into())?;
-    socket.listen(128)?;
-    socket.set_nonblocking(true)?;
-
-    let listener = TcpListener::from_std(socket.into())?;
-    log::info!("Successfully bound WebSocket server on port {}", ws_port);
+// Get recent FFmpeg output for a stream
+#[tauri::command]
+async fn get_stream_logs(
+    ws_port: u16,
+    stream_manager: State<'_, Arc<StreamManager>>,
+) -> Result<Vec<String>, String> {
+    let streams = stream_manager.streams.read().await;
+    streams
+        .get(&ws_port)
+        .map(|info| info.state.logs.lines())
+        .ok_or_else(|| format!("No stream found on port {}", ws_port))
+}
+
+// Buffer and delivery metrics for a stream
+#[derive(Serialize, Deserialize, Clone)]
+pub struct StreamMetrics {
+    pub port: u16,
+    // Effective video buffer capacity in messages (changes in adaptive mode)
+    pub buffer_capacity: usize,
+    pub adaptive_buffer: bool,
+    pub clients: usize,
+    #[serde(flatten)]
+    pub relay: RelayStats,
+}
+
+// Get buffer and delivery metrics for a stream
+#[tauri::command]
+async fn get_stream_metrics(
+    ws_port: u16,
+    stream_manager: State<'_, Arc<StreamManager>>,
+) -> Result<StreamMetrics, String> {
+    let streams = stream_manager.streams.read().await;
+    let info = streams
+        .get(&ws_port)
+        .ok_or_else(|| format!("No stream found on port {}", ws_port))?;
+    let relay = &info.state.relay;
+    Ok(StreamMetrics {
+        port: ws_port,
+        buffer_capacity: relay.capacity(),
+        adaptive_buffer: info.options.buffer.adaptive,
+        clients: relay.receiver_count(),
+        relay: relay.stats(),
+    })
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MetricsExport {
+    pub path: String,
+    pub samples: usize,
+}
+
+// Write a stream's metrics history (see MetricsConfig) to a CSV file
+#[tauri::command]
+async fn export_metrics_csv(
+    ws_port: u16,
+    output_path: PathBuf,
+    stream_manager: State<'_, Arc<StreamManager>>,
+) -> Result<MetricsExport, String> {
+    let samples = {
+        let streams = stream_manager.streams.read().await;
+        let info = streams
+            .get(&ws_port)
+            .ok_or_else(|| format!("No stream found on port {}", ws_port))?;
+        if info.hls.is_some() {
+            return Err("Metrics history is only kept for WebSocket streams".to_string());
+        }
+        let samples = info.state.metrics.lock().unwrap().samples();
+        samples
+    };
+
+    tokio::fs::write(&output_path, metrics::to_csv(&samples))
+        .await
+        .map_err(|e| format!("Failed to write {}: {}", output_path.display(), e))?;
+    log::info!("Exported {} metrics samples of stream {}", samples.len(), ws_port);
+    Ok(MetricsExport {
+        path: output_path.to_string_lossy().to_string(),
+        samples: samples.len(),
+    })
+}
+
+// Whether a stream currently has any WebSocket client
+#[tauri::command]
+async fn is_stream_watched(
+    ws_port: u16,
+    stream_manager: State<'_, Arc<StreamManager>>,
+) -> Result<bool, String> {
+    let streams = stream_manager.streams.read().await;
+    streams
+        .get(&ws_port)
+        .map(|info| info.state.watch.clients() > 0)
+        .ok_or_else(|| format!("No stream found on port {}", ws_port))
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct WatchReport {
+    pub port: u16,
+    pub watched: bool,
+    pub clients: usize,
+    // Oldest first, at most the last 100 intervals
+    pub intervals: Vec<WatchInterval>,
+    // Sum of the listed intervals, counting an open one up to now
+    pub watched_ms: u64,
+}
+
+// Get the periods a stream has been watched since it started
+#[tauri::command]
+async fn get_watch_history(
+    ws_port: u16,
+    stream_manager: State<'_, Arc<StreamManager>>,
+) -> Result<WatchReport, String> {
+    let streams = stream_manager.streams.read().await;
+    let info = streams
+        .get(&ws_port)
+        .ok_or_else(|| format!("No stream found on port {}", ws_port))?;
+    let clients = info.state.watch.clients();
+    let intervals = info.state.watch.intervals();
+    let now = unix_millis();
+    let watched_ms = intervals
+        .iter()
+        .map(|interval| interval.ended_at_ms.unwrap_or(now).saturating_sub(interval.started_at_ms))
+        .sum();
+    Ok(WatchReport {
+        port: ws_port,
+        watched: clients > 0,
+        clients,
+        intervals,
+        watched_ms,
+    })
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct StreamClients {
+    pub port: u16,
+    // Codec of the live output and the subprotocols the port accepts, to
+    // compare against what each client offered
+    pub codec: OutputCodec,
+    pub subprotocols: Vec<String>,
+    pub clients: Vec<ClientInfo>,
+}
+
+// Get the WebSocket clients connected to a stream, with the subprotocol and
+// User-Agent of each handshake
+#[tauri::command]
+async fn get_stream_clients(
+    ws_port: u16,
+    stream_manager: State<'_, Arc<StreamManager>>,
+) -> Result<StreamClients, String> {
+    let streams = stream_manager.streams.read().await;
+    let info = streams
+        .get(&ws_port)
+        .ok_or_else(|| format!("No stream found on port {}", ws_port))?;
+    Ok(StreamClients {
+        port: ws_port,
+        codec: info.options.encode.codec,
+        subprotocols: info.options.subprotocols.clone(),
+        clients: info.state.watch.client_list(),
+    })
+}
+
+// Player options for jsmpeg, matched to the stream's encoder settings. Can be
+// passed straight to `new JSMpeg.Player(url, options)`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct JsmpegConfig {
+    pub url: String,
+    pub video_buffer_size: usize,
+    pub protocols: Vec<String>,
+    pub audio: bool,
+    // Only set with an av_sync_mode
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audio_buffer_size: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_audio_lag: Option<f64>,
+}
+
+// Get the jsmpeg player options for an MPEG1 stream
+#[tauri::command]
+async fn get_jsmpeg_config(
+    ws_port: u16,
+    stream_manager: State<'_, Arc<StreamManager>>,
+) -> Result<JsmpegConfig, String> {
+    let streams = stream_manager.streams.read().await;
+    let info = streams
+        .get(&ws_port)
+        .ok_or_else(|| format!("No stream found on port {}", ws_port))?;
+    if info.options.encode.codec != OutputCodec::Mpeg1 {
+        return Err(format!("Stream on port {} is not an MPEG1 (jsmpeg) stream", ws_port));
+    }
+    let audio_hints = info.options.encode.av_sync_mode.map(|mode| mode.jsmpeg_audio_hints());
+    Ok(JsmpegConfig {
+        url: format!("ws://127.0.0.1:{}", ws_port),
+        video_buffer_size: info.options.encode.jsmpeg_video_buffer_size(),
+        protocols: info.options.subprotocols.clone(),
+        audio: false,
+        audio_buffer_size: audio_hints.map(|(size, _)| size),
+        max_audio_lag: audio_hints.map(|(_, lag)| lag),
+    })
+}
+
+// Get black/frozen picture state for a stream
+#[tauri::command]
+async fn get_stream_quality(
+    ws_port: u16,
+    stream_manager: State<'_, Arc<StreamManager>>,
+) -> Result<StreamQuality, String> {
+    let streams = stream_manager.streams.read().await;
+    streams
+        .get(&ws_port)
+        .map(|info| info.state.quality.lock().unwrap().clone())
+        .ok_or_else(|| format!("No stream found on port {}", ws_port))
+}
+
+// Gracefully stop all streams that write files so recordings are finalized,
+// e.g. before closing the app. The same runs automatically on app exit.
+#[tauri::command]
+async fn drain_recordings(
+    timeout_ms: Option<u64>,
+    stream_manager: State<'_, Arc<StreamManager>>,
+) -> Result<DrainReport, String> {
+    Ok(stream_manager.drain_recordings(timeout_ms.map(Duration::from_millis)).await)
+}
+
+// Mark a source as dead (e.g. a decommissioned camera): streams of it stop on
+// their next failure instead of reconnecting. Persisted in the settings, keyed
+// by the normalized URL without credentials. Returns the dead sources.
+#[tauri::command]
+async fn mark_source_dead(
+    rtsp_url: String,
+    settings_store: State<'_, SettingsStore>,
+) -> Result<Vec<String>, String> {
+    let key = rtsp_url::source_key(&rtsp_url);
+    log::info!("Marking source dead: {}", key);
+    settings_store.set_source_dead(key, true).await
+}
+
+// Re-enable reconnects for a source marked dead. Returns the dead sources.
+#[tauri::command]
+async fn unmark_source_dead(
+    rtsp_url: String,
+    settings_store: State<'_, SettingsStore>,
+) -> Result<Vec<String>, String> {
+    let key = rtsp_url::source_key(&rtsp_url);
+    log::info!("Unmarking dead source: {}", key);
+    settings_store.set_source_dead(key, false).await
+}
+
+// Get the persisted user settings
+#[tauri::command]
+async fn get_settings(settings_store: State<'_, SettingsStore>) -> Result<Settings, String> {
+    Ok(settings_store.get().await)
+}
+
+// Validate, persist and apply new user settings
+#[tauri::command]
+async fn set_settings(
+    settings: Settings,
+    settings_store: State<'_, SettingsStore>,
+) -> Result<Settings, String> {
+    settings_store.set(settings).await?;
+    Ok(settings_store.get().await)
+}
+
+// Supply a fresh source URL for a dynamic_url stream that is waiting to
+// reconnect (see stream://needs_url)
+#[tauri::command]
+async fn provide_stream_url(
+    ws_port: u16,
+    new_url: String,
+    stream_manager: State<'_, Arc<StreamManager>>,
+) -> Result<(), String> {
+    if new_url.trim().is_empty() {
+        return Err("URL must not be empty".to_string());
+    }
+    if !ffmpeg::is_network_source(&new_url) {
+        return Err(format!(
+            "'{}' is not a network source URL (rtsp, rtsps, http, https, srt or rtmp)",
+            rtsp_url::redact(&new_url)
+        ));
+    }
+
+    let mut streams = stream_manager.streams.write().await;
+    let info = streams
+        .get_mut(&ws_port)
+        .ok_or_else(|| format!("No stream found on port {}", ws_port))?;
+    let url_tx = info
+        .state
+        .url_request
+        .lock()
+        .unwrap()
+        .take()
+        .ok_or_else(|| format!("Stream on port {} is not waiting for a URL", ws_port))?;
+    url_tx
+        .send(new_url.clone())
+        .map_err(|_| format!("Stream on port {} is no longer waiting for a URL", ws_port))?;
+
+    log::info!("New source URL provided for stream {}", ws_port);
+    info.rtsp_url = new_url;
+    Ok(())
+}
+
+// Replace the credentials in a stream's source URL. The next reconnect uses
+// them; with `restart`, FFmpeg is restarted right away to apply them while
+// WebSocket clients stay connected.
+#[tauri::command]
+async fn update_credentials(
+    ws_port: u16,
+    username: String,
+    password: String,
+    restart: Option<bool>,
+    stream_manager: State<'_, Arc<StreamManager>>,
+) -> Result<(), String> {
+    if username.is_empty() {
+        return Err("Username must not be empty".to_string());
+    }
+
+    let mut streams = stream_manager.streams.write().await;
+    let info = streams
+        .get_mut(&ws_port)
+        .ok_or_else(|| format!("No stream found on port {}", ws_port))?;
+    let url = rtsp_url::with_credentials(&info.rtsp_url, &username, &password)?;
+    info.rtsp_url = url.clone();
+    *info.state.pending_url.lock().unwrap() = Some(url);
+
+    log::info!("Credentials updated for stream {}", ws_port);
+    info.state.logs.push("Source credentials updated".to_string());
+    if restart.unwrap_or(false) {
+        info.state.request_restart();
+    }
+    Ok(())
+}
+
+// Start (or resize) a stream's shadow buffer: a rolling recording of the
+// last `minutes` of the source, kept on disk for promote_shadow. FFmpeg is
+// restarted to add the output; WebSocket clients stay connected.
+#[tauri::command]
+async fn enable_shadow_buffer(
+    ws_port: u16,
+    minutes: u32,
+    directory: Option<PathBuf>,
+    stream_manager: State<'_, Arc<StreamManager>>,
+) -> Result<ShadowConfig, String> {
+    let config = ShadowConfig { minutes, directory };
+    config.validate()?;
+
+    let mut streams = stream_manager.streams.write().await;
+    let info = streams
+        .get_mut(&ws_port)
+        .ok_or_else(|| format!("No stream found on port {}", ws_port))?;
+    if info.hls.is_some() {
+        return Err("Shadow buffers are not supported on HLS streams".to_string());
+    }
+    let config = shadow::prepare(&config, ws_port)?;
+
+    let previous = info.state.shadow.lock().unwrap().replace(config.clone());
+    if let Some(dir) = previous.and_then(|previous| previous.directory) {
+        if Some(&dir) != config.directory.as_ref() {
+            shadow::clear(&dir, ws_port);
+        }
+    }
+    info.options.shadow_buffer = Some(config.clone());
+
+    log::info!("Shadow buffer of {} minutes enabled for stream {}", minutes, ws_port);
+    info.state.logs.push(format!("Shadow buffer enabled ({} minutes)", minutes));
+    info.state.request_restart();
+    Ok(config)
+}
+
+// Stop a stream's shadow buffer and delete its segments
+#[tauri::command]
+async fn disable_shadow_buffer(
+    ws_port: u16,
+    stream_manager: State<'_, Arc<StreamManager>>,
+) -> Result<(), String> {
+    let mut streams = stream_manager.streams.write().await;
+    let info = streams
+        .get_mut(&ws_port)
+        .ok_or_else(|| format!("No stream found on port {}", ws_port))?;
+    let previous = info.state.shadow.lock().unwrap().take();
+    let Some(dir) = previous.and_then(|previous| previous.directory) else {
+        return Err(format!("Stream on port {} has no shadow buffer", ws_port));
+    };
+    info.options.shadow_buffer = None;
+    info.state.request_restart();
+
+    // The segment being written stays open until the old FFmpeg has exited.
+    // Skipped if the buffer was re-enabled on the same directory meanwhile.
+    let current = Arc::clone(&info.state.shadow);
+    let exit_delay = info.state.graceful_stop + Duration::from_secs(2);
+    tokio::spawn(async move {
+        tokio::time::sleep(exit_delay).await;
+        let reused = current.lock().unwrap().as_ref().and_then(|s| s.directory.as_ref()) == Some(&dir);
+        if !reused {
+            shadow::clear(&dir, ws_port);
+        }
+    });
+    log::info!("Shadow buffer disabled for stream {}", ws_port);
+    Ok(())
+}
+
+// Copy the current shadow window of a stream into one permanent file. The
+// container follows the extension of output_path (.ts, .mp4, .mkv).
+#[tauri::command]
+async fn promote_shadow(
+    ws_port: u16,
+    output_path: PathBuf,
+    stream_manager: State<'_, Arc<StreamManager>>,
+) -> Result<shadow::PromotedClip, String> {
+    let (dir, ffmpeg) = {
+        let streams = stream_manager.streams.read().await;
+        let info = streams
+            .get(&ws_port)
+            .ok_or_else(|| format!("No stream found on port {}", ws_port))?;
+        let shadow = info.state.shadow.lock().unwrap().clone();
+        let dir = shadow
+            .and_then(|shadow| shadow.directory)
+            .ok_or_else(|| format!("Stream on port {} has no shadow buffer", ws_port))?;
+        (dir, info.state.ffmpeg())
+    };
+
+    let clip = shadow::promote(&ffmpeg, &dir, &output_path).await?;
+    log::info!(
+        "Promoted {} shadow segments of stream {} to {}",
+        clip.segments,
+        ws_port,
+        clip.path
+    );
+    Ok(clip)
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SnapshotFile {
+    pub port: u16,
+    pub path: String,
+    // Decoded from the live buffer rather than a new source connection
+    pub from_buffer: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SnapshotError {
+    pub port: u16,
+    pub error: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct SnapshotReport {
+    pub files: Vec<SnapshotFile>,
+    pub errors: Vec<SnapshotError>,
+}
+
+// Save a still of every active stream to output_dir as
+// <port>_<timestamp>.jpg. Frames come from each stream's live buffer when it
+// holds data, so no extra camera connection is opened.
+#[tauri::command]
+async fn capture_all_snapshots(
+    output_dir: PathBuf,
+    stream_manager: State<'_, Arc<StreamManager>>,
+) -> Result<SnapshotReport, String> {
+    fs::create_dir_all(&output_dir)
+        .map_err(|e| format!("Failed to create {}: {}", output_dir.display(), e))?;
+    let timestamp = chrono::Local::now().format("%Y%m%d-%H%M%S").to_string();
+
+    // Collect buffered data under the lock, then decode without holding it
+    let jobs: Vec<_> = {
+        let streams = stream_manager.streams.read().await;
+        streams
+            .iter()
+            .map(|(port, info)| {
+                let init = info.state.init_segment.lock().unwrap().clone();
+                let container = match (info.options.encode.codec, init) {
+                    (OutputCodec::H264, Some(init)) => Some(("mp4", init)),
+                    (OutputCodec::H264, None) => None,
+                    (OutputCodec::Mpeg1, _) => Some(("mpegts", Vec::new())),
+                };
+                let buffered = container.and_then(|(format, mut data)| {
+                    let mut recent = info.state.relay.recent();
+                    // The init segment is also relayed; keep only what follows it
+                    if let Some(at) = recent.iter().rposition(|message| format == "mp4" && *message == data) {
+                        recent.drain(..=at);
+                    }
+                    if recent.is_empty() {
+                        return None;
+                    }
+                    recent.iter().for_each(|message| data.extend_from_slice(message));
+                    Some((format, data))
+                });
+                let source = (info.rtsp_url.clone(), info.options.clone(), info.state.ffmpeg());
+                (*port, source, buffered)
+            })
+            .collect()
+    };
+
+    let tasks = jobs.into_iter().map(|(port, (rtsp_url, options, ffmpeg), buffered)| {
+        let path = output_dir.join(format!("{}_{}.jpg", port, timestamp));
+        async move {
+            let from_buffer = buffered.is_some();
+            let result = match buffered {
+                Some((format, data)) => snapshot::from_buffer(&ffmpeg, data, format, &path).await,
+                None => snapshot::from_source(&ffmpeg, &rtsp_url, &options, &path).await,
+            };
+            (port, path, from_buffer, result)
+        }
+    });
+
+    let mut report = SnapshotReport::default();
+    for (port, path, from_buffer, result) in futures_util::future::join_all(tasks).await {
+        match result {
+            Ok(()) => report.files.push(SnapshotFile {
+                port,
+                path: path.to_string_lossy().to_string(),
+                from_buffer,
+            }),
+            Err(error) => {
+                log::warn!("Snapshot of stream {} failed: {}", port, error);
+                report.errors.push(SnapshotError { port, error });
+            }
+        }
+    }
+    report.files.sort_by_key(|file| file.port);
+    report.errors.sort_by_key(|error| error.port);
+    Ok(report)
+}
+
+// Produce a short sample of a source in the codec and container of an output
+// mode, so the frontend can check that the browser decodes it before starting
+// a live session. `options` default to the saved default options;
+// `ffmpeg_path` picks the FFmpeg build as for start_stream.
+#[tauri::command]
+async fn get_sample_fragment(
+    rtsp_url: String,
+    codec: SampleFormat,
+    options: Option<StreamOptions>,
+    ffmpeg_path: Option<PathBuf>,
+    settings_store: State<'_, SettingsStore>,
+) -> Result<SampleFragment, String> {
+    let options = match options {
+        Some(options) => options,
+        None => settings_store.get().await.default_options,
+    };
+    options.validate()?;
+    if let Some(path) = &ffmpeg_path {
+        ffmpeg::validate_ffmpeg_binary(path)?;
+    }
+    let ffmpeg = stream_ffmpeg(ffmpeg_path.as_deref());
+    let url = rtsp_url::resolve_host(&rtsp_url, options.resolve).await?;
+
+    // Like the live output, copy a compatible source so the sample carries
+    // the camera's own profile and level
+    let mut copy_video = false;
+    if codec != SampleFormat::Hls && options.encode.copy_when_compatible {
+        let mut copy_options = options.clone();
+        copy_options.encode.codec = if codec == SampleFormat::H264 { OutputCodec::H264 } else { OutputCodec::Mpeg1 };
+        if let Ok(media) = probe::probe_source(&ffmpeg, &url, &options, Duration::from_secs(10)).await {
+            copy_video = ffmpeg::can_copy_video(&copy_options, &media).is_ok();
+        }
+    }
+    sample::fragment(&ffmpeg, &url, &options, codec, copy_video).await
+}
+
+// Fix common mistakes in a user-entered RTSP URL before passing it to
+// start_stream
+#[tauri::command]
+fn normalize_rtsp_url(url: String) -> Result<rtsp_url::NormalizedRtspUrl, String> {
+    rtsp_url::normalize_rtsp_url(&url)
+}
+
+// Measure how many streams with the given options this machine sustains,
+// by starting them one by one (see benchmark.rs). `source` is a camera URL
+// or "testsrc" for a synthetic 1080p source.
+#[tauri::command]
+async fn benchmark_capacity(
+    source: String,
+    options: Option<StreamOptions>,
+    benchmark: Option<BenchmarkConfig>,
+    app: AppHandle,
+    settings_store: State<'_, SettingsStore>,
+) -> Result<benchmark::BenchmarkReport, String> {
+    let options = match options {
+        Some(options) => options,
+        None => settings_store.get().await.default_options,
+    };
+    let benchmark = benchmark.unwrap_or_default();
+    options.validate()?;
+    benchmark.validate()?;
+    if source != benchmark::TEST_SOURCE {
+        rtsp_url::resolve_host(&source, options.resolve).await?;
+    }
+    log::info!("Starting capacity benchmark with {}", rtsp_url::redact(&source));
+    benchmark::run(&source, &options, &benchmark, |step| {
+        let _ = app.emit("benchmark://step", step);
+    })
+    .await
+}
+
+// Stop a running benchmark_capacity and its streams
+#[tauri::command]
+async fn cancel_benchmark() -> Result<bool, String> {
+    Ok(benchmark::cancel().await)
+}
+
+// Describe every command and its parameters as JSON schemas, so frontends
+// can generate bindings or validate options before invoking
+#[tauri::command]
+fn get_api_schema() -> schema::ApiSchema {
+    schema::api_schema()
+}
+
+// List the input protocols the installed FFmpeg supports
+#[tauri::command]
+async fn list_supported_protocols() -> Result<ffmpeg::SupportedProtocols, String> {
+    ffmpeg::supported_protocols()
+}
+
+// Report what the environment supports in one call. The FFmpeg lists are
+// cached; pass refresh to query them again.
+#[tauri::command]
+async fn get_capabilities(refresh: Option<bool>) -> Result<capabilities::Capabilities, String> {
+    if refresh.unwrap_or(false) {
+        ffmpeg::clear_capability_cache();
+    }
+    Ok(capabilities::collect())
+}
+
+// FFmpeg reports filtergraph problems from the parser or a filter instance
+fn is_filter_error(line: &str) -> bool {
+    line.contains("Error initializing complex filters")
+        || line.contains("No such filter")
+        || line.contains("[AVFilterGraph @")
+        || (line.contains("filter") && line.contains("Invalid argument"))
+}
+
+// FFmpeg echoes every filter command sent through stdin (the clock overlay
+// sends one per second); only failed commands are worth logging
+fn is_command_echo(line: &str) -> bool {
+    line.starts_with("Enter command:") || (line.starts_with("Command reply") && line.contains("ret:0 "))
+}
+
+// Pick the first subprotocol offered by the client that the stream supports.
+// Ok(None) if the client offered none; an error if it offered only
+// unsupported ones, since the client would fail the connection anyway.
+fn negotiate_subprotocol(offered: &[&str], supported: &[String]) -> Result<Option<String>, String> {
+    if offered.is_empty() {
+        return Ok(None);
+    }
+    offered
+        .iter()
+        .find(|protocol| supported.iter().any(|s| s == *protocol))
+        .map(|protocol| Some(protocol.to_string()))
+        .ok_or_else(|| {
+            format!(
+                "No supported subprotocol in [{}] (supported: [{}])",
+                offered.join(", "),
+                supported.join(", ")
+            )
+        })
+}
+
+// Apply the stream's TCP options to an accepted socket and read them back
+fn apply_socket_options(stream: &tokio::net::TcpStream, config: &SocketConfig) -> std::io::Result<SocketInfo> {
+    stream.set_nodelay(config.nodelay)?;
+    let socket = SockRef::from(stream);
+    if let Some(size) = config.send_buffer_bytes {
+        socket.set_send_buffer_size(size)?;
+    }
+    if let Some(size) = config.recv_buffer_bytes {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if let Some(secs) = config.keepalive_secs {
+        socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(Duration::from_secs(secs)))?;
+    }
+
+    Ok(SocketInfo {
+        nodelay: stream.nodelay()?,
+        send_buffer_bytes: socket.send_buffer_size()?,
+        recv_buffer_bytes: socket.recv_buffer_size()?,
+        keepalive: socket.keepalive()?,
+    })
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SocketOptionsReport {
+    pub port: u16,
+    pub configured: SocketConfig,
+    // None until a client has connected
+    pub effective: Option<SocketInfo>,
+}
+
+// Get the configured and effective TCP options of a stream's client sockets
+#[tauri::command]
+async fn get_socket_options(
+    ws_port: u16,
+    stream_manager: State<'_, Arc<StreamManager>>,
+) -> Result<SocketOptionsReport, String> {
+    let streams = stream_manager.streams.read().await;
+    let info = streams
+        .get(&ws_port)
+        .ok_or_else(|| format!("No stream found on port {}", ws_port))?;
+    let effective = info.state.socket.lock().unwrap().clone();
+    Ok(SocketOptionsReport {
+        port: ws_port,
+        configured: info.options.socket.clone(),
+        effective,
+    })
+}
+
+// Bind the WebSocket listener with SO_REUSEADDR to allow quick rebinding
+fn bind_listener(ws_port: u16) -> std::io::Result<TcpListener> {
+    let addr: SocketAddr = format!("127.0.0.1:{}", ws_port).parse().unwrap();
+    let socket = Socket::new(Domain::IPV4, Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(128)?;
+    socket.set_nonblocking(true)?;
+
+    TcpListener::from_std(socket.into())
+}
+
+// List local webcams and capture cards
+#[tauri::command]
+async fn list_capture_devices() -> Result<Vec<devices::CaptureDevice>, String> {
+    devices::list_capture_devices()
+}
+
+// Run the WebSocket server that relays FFmpeg output
+async fn run_stream_server(
+    app: AppHandle,
+    rtsp_url: String,
+    ws_port: u16,
+    options: StreamOptions,
+    state: StreamState,
+    shutdown_rx: broadcast::Receiver<()>,
+    ready_tx: oneshot::Sender<Result<(), String>>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    log::info!("Attempting to bind WebSocket server on port {}", ws_port);
+
+    // Report the bind outcome to start_stream before doing anything else
+    let listener = match bind_listener(ws_port) {
+        Ok(listener) => listener,
+        Err(e) => {
+            let _ = ready_tx.send(Err(e.to_string()));
+            return Err(e.into());
+        }
+    };
+
+    // WHEP endpoint on the same port, fed by an extra RTP output of FFmpeg
+    #[cfg(feature = "webrtc")]
+    let whep = if options.webrtc {
+        match whep::WhepServer::start().await {
+            Ok(server) => Some(Arc::new(server)),
+            Err(e) => {
+                let _ = ready_tx.send(Err(e.clone()));
+                return Err(e.into());
+            }
+        }
+    } else {
+        None
+    };
+    #[cfg(feature = "webrtc")]
+    let rtp_port = whep.as_ref().map(|server| server.rtp_port());
+    #[cfg(not(feature = "webrtc"))]
+    let rtp_port = None;
+
+    let _ = ready_tx.send(Ok(()));
+    log::info!("Successfully bound WebSocket server on port {}", ws_port);
+
+    let relay = Arc::clone(&state.relay);
+    let adapt_task = options
+        .buffer
+        .adaptive
+        .then(|| tokio::spawn(relay::adapt_capacity(Arc::clone(&relay), options.buffer.clone())));
+    let metrics_task = tokio::spawn(sample_metrics(state.clone(), options.metrics.interval_secs));
+    let pipe_task = options
+        .pipe_output
+        .as_ref()
+        .and_then(|pipe| pipe.path.clone())
+        .map(|path| tokio::spawn(pipe::tee(path, Arc::clone(&relay), Arc::clone(&state.init_segment))));
+
+    let clients = ClientConfig {
+        subprotocols: Arc::new(options.subprotocols.clone()),
+        socket: options.socket.clone(),
+        state: state.clone(),
+    };
+    let shadow = Arc::clone(&state.shadow);
+    let share = state.share.clone();
+
+    let process_slot = state.process_slot.clone();
+    let graceful_stop = state.graceful_stop;
+    let disk_task = options.recording_config.clone().map(|recording| {
+        tokio::spawn(monitor_disk_space(
+            app.clone(),
+            recording,
+            state.clone(),
+        ))
+    });
+    let runner = FfmpegRunner {
+        app,
+        options,
+        state,
+        rtp_port,
+        process_slot: process_slot.clone(),
+        hls: None,
+    };
+    // FFmpeg outlives this port's shutdown while its source is shared
+    let (ffmpeg_stop_tx, ffmpeg_stop_rx) = broadcast::channel::<()>(1);
+    let ffmpeg_task = tokio::spawn(supervise_ffmpeg(runner, rtsp_url, ffmpeg_stop_rx));
+
+    serve_clients(
+        listener,
+        ws_port,
+        clients,
+        #[cfg(feature = "webrtc")]
+        whep.clone(),
+        shutdown_rx,
+    )
+    .await;
+
+    // Ports sharing this source keep FFmpeg running until they stop too
+    if share.release() > 0 {
+        log::info!("Stream on port {} stopped; its source is still shared", ws_port);
+        share.wait_released().await;
+    }
+
+    // Cleanup - FFmpeg's exit (quitting, or killed after the graceful stop
+    // timeout) unblocks the reader, which then reaps the process and joins
+    // its stderr thread
+    process_slot.stop_gracefully(graceful_stop).await;
+    let _ = ffmpeg_stop_tx.send(());
+    let _ = ffmpeg_task.await;
+    metrics_task.abort();
+    for task in [adapt_task, disk_task, pipe_task].into_iter().flatten() {
+        task.abort();
+    }
+    if let Some(dir) = shadow.lock().unwrap().take().and_then(|shadow| shadow.directory) {
+        shadow::clear(&dir, ws_port);
+    }
+    relay.close();
+    #[cfg(feature = "webrtc")]
+    if let Some(whep) = whep {
+        whep.close().await;
+    }
+
+    Ok(())
+}
+
+// Per-port settings and state for accepting WebSocket clients
+struct ClientConfig {
+    subprotocols: Arc<Vec<String>>,
+    socket: SocketConfig,
+    state: StreamState,
+}
+
+// Accept WebSocket clients (and WHEP requests) on a stream port until it is
+// shut down, relaying the video of `clients.state` to each of them. When
+// swap_ports moves the stream, the server continues on the other port and
+// drops its clients so they reconnect.
+async fn serve_clients(
+    mut listener: TcpListener,
+    mut ws_port: u16,
+    clients: ClientConfig,
+    #[cfg(feature = "webrtc")] whep: Option<Arc<whep::WhepServer>>,
+    mut shutdown_rx: broadcast::Receiver<()>,
+) {
+    let handshakes = Arc::new(Semaphore::new(clients.socket.max_handshakes));
+    let handshake_timeout = Duration::from_millis(clients.socket.handshake_timeout_ms);
+    let idle_timeout = clients.socket.idle_timeout_secs.map(Duration::from_secs);
+    let (swap_tx, mut swap_rx) = mpsc::unbounded_channel::<PortSwap>();
+    *clients.state.port_swap.lock().unwrap() = Some(swap_tx);
+    let (reconnect_tx, _) = broadcast::channel::<()>(1);
+    loop {
+        tokio::select! {
+            _ = shutdown_rx.recv() => {
+                log::info!("Shutting down stream server on port {}", ws_port);
+                break;
+            }
+            Some(swap) = swap_rx.recv() => {
+                // Keep the listener if swap_ports gave up already
+                if let Err(own) = swap.give.send(listener) {
+                    listener = own;
+                    continue;
+                }
+                let Ok((next, port)) = swap.take.await else {
+                    log::error!("Lost the listener of port {} while swapping ports", ws_port);
+                    break;
+                };
+                listener = next;
+                if port != ws_port {
+                    log::info!("Stream on port {} moved to port {}", ws_port, port);
+                    ws_port = port;
+                    clients.state.port.store(port, Ordering::SeqCst);
+                    let _ = reconnect_tx.send(());
+                }
+            }
+            accept_result = listener.accept() => {
+                match accept_result {
+                    Ok((stream, addr)) => {
+                        // Under a connection flood, drop connections instead of
+                        // queueing handshakes
+                        let Ok(permit) = Arc::clone(&handshakes).try_acquire_owned() else {
+                            log::warn!("Too many pending handshakes on port {}, dropping {}", ws_port, addr);
+                            continue;
+                        };
+                        log::info!("New WebSocket connection from {}", addr);
+                        match apply_socket_options(&stream, &clients.socket) {
+                            Ok(info) => *clients.state.socket.lock().unwrap() = Some(info),
+                            Err(e) => log::warn!("Failed to set socket options for {}: {}", addr, e),
+                        }
+                        let (video_rx, init) = clients.state.subscribe();
+                        let subprotocols = Arc::clone(&clients.subprotocols);
+                        let watch = clients.state.watch.clone();
+                        let mut client_shutdown_rx = shutdown_rx.resubscribe();
+                        let mut reconnect_rx = reconnect_tx.subscribe();
+                        #[cfg(feature = "webrtc")]
+                        let whep = whep.clone();
+
+                        tokio::spawn(async move {
+                            // Anything but a WebSocket upgrade goes to the WHEP endpoint
+                            #[cfg(feature = "webrtc")]
+                            if let Some(whep) = whep {
+                                match tokio::time::timeout(handshake_timeout, whep::is_websocket_request(&stream)).await {
+                                    Ok(true) => {}
+                                    Ok(false) => {
+                                        // The request counts as a pending handshake until answered
+                                        whep.handle_http(stream, handshake_timeout).await;
+                                        drop(permit);
+                                        return;
+                                    }
+                                    Err(_) => {
+                                        log::warn!("Connection from {} sent no request in time", addr);
+                                        return;
+                                    }
+                                }
+                            }
+
+                            // Custom callback to negotiate the subprotocol (jsmpeg by default).
+                            // What the client offered is kept for get_stream_clients.
+                            let mut client = ClientInfo {
+                                address: addr.to_string(),
+                                ..ClientInfo::default()
+                            };
+                            // tungstenite's handshake callback returns its large ErrorResponse as is
+                            #[allow(clippy::result_large_err)]
+                            let callback = |req: &tokio_tungstenite::tungstenite::handshake::server::Request,
+                                           mut response: tokio_tungstenite::tungstenite::handshake::server::Response| {
+                                client.user_agent = req
+                                    .headers()
+                                    .get("User-Agent")
+                                    .and_then(|value| value.to_str().ok())
+                                    .map(str::to_string);
+                                let offered: Vec<&str> = req
+                                    .headers()
+                                    .get_all("Sec-WebSocket-Protocol")
+                                    .iter()
+                                    .filter_map(|value| value.to_str().ok())
+                                    .flat_map(|value| value.split(','))
+                                    .map(str::trim)
+                                    .filter(|protocol| !protocol.is_empty())
+                                    .collect();
+                                client.offered_subprotocols = offered.iter().map(|protocol| protocol.to_string()).collect();
+                                match negotiate_subprotocol(&offered, &subprotocols) {
+                                    Ok(Some(protocol)) => {
+                                        client.subprotocol = Some(protocol.clone());
+                                        // Echo back the selected protocol
+                                        response.headers_mut().insert(
+                                            "Sec-WebSocket-Protocol",
+                                            protocol.parse().unwrap(),
+                                        );
+                                        Ok(response)
+                                    }
+                                    Ok(None) => Ok(response),
+                                    Err(e) => {
+                                        log::warn!("Rejecting WebSocket upgrade: {}", e);
+                                        let mut error = tokio_tungstenite::tungstenite::handshake::server::ErrorResponse::new(Some(e));
+                                        *error.status_mut() = tokio_tungstenite::tungstenite::http::StatusCode::BAD_REQUEST;
+                                        Err(error)
+                                    }
+                                }
+                            };
+
+                            let handshake =
+                                tokio::time::timeout(handshake_timeout, tokio_tungstenite::accept_hdr_async(stream, callback))
+                                    .await;
+                            drop(permit);
+                            match handshake {
+                                Ok(Ok(ws_stream)) => {
+                                    log::info!("WebSocket handshake successful");
+                                    let client_id = watch.connected(client);
+                                    // Clients of this port leave when it stops or
+                                    // moves, even if a port sharing its source stays
+                                    tokio::select! {
+                                        _ = handle_ws_connection(ws_stream, video_rx, init, idle_timeout) => {}
+                                        _ = client_shutdown_rx.recv() => {}
+                                        _ = reconnect_rx.recv() => {}
+                                    }
+                                    watch.disconnected(client_id);
+                                }
+                                Ok(Err(e)) => {
+                                    log::error!("WebSocket handshake failed: {}", e);
+                                }
+                                Err(_) => {
+                                    log::warn!("WebSocket handshake from {} timed out", addr);
+                                }
+                            }
+                        });
+                    }
+                    Err(e) => {
+                        log::error!("Accept error: {}", e);
+                    }
+                }
+            }
+        }
+    }
+    clients.state.port_swap.lock().unwrap().take();
+}
+
+// Everything a single FFmpeg run needs, cloned into each blocking run
+#[derive(Clone)]
+struct FfmpegRunner {
+    app: AppHandle,
+    options: StreamOptions,
+    state: StreamState,
+    rtp_port: Option<u16>,
+    process_slot: ProcessSlot,
+    // Set for HLS streams: FFmpeg writes the playlist and segments to the
+    // directory instead of stdout
+    hls: Option<(HlsConfig, PathBuf)>,
+}
 
-    // Create a broadcast channel for video data
-    let (video_tx, _) = broadcast::channel::<Vec<u8>>(100);
-    let video_tx = Arc::new(video_tx);
+impl FfmpegRunner {
+    fn ws_port(&self) -> u16 {
+        self.state.port.load(Ordering::SeqCst)
+    }
 
-    // Spawn FFmpeg process
-    let video_tx_clone = Arc::clone(&video_tx);
-    let rtsp_url_clone = rtsp_url.clone();
+    // Run FFmpeg on the source and relay its output until it exits or is
+    // stopped. Blocks on FFmpeg's stdout; returns whether it delivered video
+    // and how FFmpeg exited, if it exited by itself or was killed by us.
+    fn run(&self, rtsp_url: &str) -> (bool, Option<(ExitKind, std::process::ExitStatus)>) {
+        let ffmpeg_path = self.state.ffmpeg();
+        log::info!("Starting FFmpeg ({}) for RTSP URL: {}", ffmpeg_path, rtsp_url::redact(rtsp_url));
 
-    // FFmpeg runner task - use spawn_blocking for blocking I/O
-    let ffmpeg_path = get_ffmpeg_path();
-    let ffmpeg_task = tokio::task::spawn_blocking(move || {
-        log::info!("Starting FFmpeg ({}) for RTSP URL: {}", ffmpeg_path, rtsp_url_clone);
-
-        let mut cmd = Command::new(&ffmpeg_path);
-        cmd.args([
-            "-rtsp_transport", "tcp",      // Use TCP for RTSP (more reliable)
-            "-fflags", "nobuffer",         // Reduce buffering
-            "-flags", "low_delay",         // Low delay mode
-            "-i", &rtsp_url_clone,          // Input RTSP URL
-            "-f", "mpegts",                 // Output format: MPEG-TS
-            "-codec:v", "mpeg1video",       // Video codec for jsmpeg
-            "-s", "640x480",                // Resolution
-            "-b:v", "1000k",                // Video bitrate
-            "-bf", "0",                     // No B-frames (lower latency)
-            "-q:v", "5",                    // Quality level
-            "-r", "25",                     // Frame rate
-            "-an",                          // No audio
-            "-flush_packets", "1",          // Flush packets immediately
-            "pipe:1",                       // Output to stdout
-        ])
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped());
+        let options = self.state.run_options(&self.options);
 
-        // Hide console window on Windows
-        #[cfg(target_os = "windows")]
-        {
-            use std::os::windows::process::CommandExt;
-            const CREATE_NO_WINDOW: u32 = 0x08000000;
-            cmd.creation_flags(CREATE_NO_WINDOW);
+        if let Some(timestamps) = self.state.quality.lock().unwrap().timestamps.as_mut() {
+            timestamps.restart();
         }
 
-        let mut child = match cmd.spawn()
-        {
-            Ok(child) => {
-                log::info!("FFmpeg process started with PID: {:?}", child.id());
-                child
+        let mut cmd = ffmpeg::command(&ffmpeg_path);
+        match &self.hls {
+            // Each run starts from an empty directory so no stale segments
+            // outlive a restart
+            Some((hls, dir)) => {
+                hls::clear_dir(dir);
+                cmd.args(ffmpeg::build_hls_args(rtsp_url, &options, hls, dir));
             }
-            Err(e) => {
-                log::error!("Failed to start FFmpeg: {}", e);
-                return;
+            None => {
+                cmd.args(ffmpeg::build_ffmpeg_args(rtsp_url, &options, self.state.video_copy, self.rtp_port));
             }
-        };
+        }
 
-        let stdout = match child.stdout.take() {
-            Some(out) => out,
-            None => {
-                log::error!("Failed to get FFmpeg stdout");
+        // stderr is read on a thread owned by the process, joined when it ends
+        let stderr_state = self.state.clone();
+        let app = self.app.clone();
+        let (source_url, source_options) = (rtsp_url.to_string(), self.options.clone());
+        let hls_output = self.hls.is_some();
+        let (process, mut stdout) = match FfmpegProcess::spawn(cmd, move |line| {
+            // Progress updates feed the metrics instead of the logs
+            if let Some(progress) = metrics::parse_progress(&line) {
+                let mut metrics = stderr_state.metrics.lock().unwrap();
+                // No HLS video passes through the backend; frames encoded
+                // stand in for it
+                if hls_output && progress.frame > metrics.progress.as_ref().map_or(0, |last| last.frame) {
+                    stderr_state.last_data_at.store(unix_millis(), Ordering::SeqCst);
+                }
+                metrics.progress = Some(progress);
+                return;
+            }
+            if is_command_echo(&line) {
                 return;
             }
+            let line = rtsp_url::redact(&line);
+            if is_filter_error(&line) {
+                log::error!("FFmpeg filtergraph error: {}", line);
+            } else if timestamps::is_debug_line(&line) {
+                log::trace!("FFmpeg: {}", line);
+            } else {
+                log::info!("FFmpeg: {}", line);
+            }
+            let ws_port = stderr_state.port.load(Ordering::SeqCst);
+            if probe::is_source_change(&line) {
+                let (app, url, options, state) =
+                    (app.clone(), source_url.clone(), source_options.clone(), stderr_state.clone());
+                tauri::async_runtime::spawn(async move {
+                    if let Err(e) = reprobe_source(&app, ws_port, &url, &options, &state).await {
+                        log::debug!("Stream {}: not re-probing the source: {}", ws_port, e);
+                    }
+                });
+            }
+            if let Some(detection) = quality::parse_detection(&line) {
+                let (event, payload) =
+                    stderr_state.quality.lock().unwrap().apply(ws_port, detection);
+                log::warn!("Stream {}: {} active={}", ws_port, event, payload.active);
+                let _ = app.emit(event, payload);
+            }
+            let timestamp_issue =
+                stderr_state.quality.lock().unwrap().timestamps.as_mut().and_then(|stats| stats.observe(&line));
+            stderr_state.logs.push(line);
+            if let Some(issue) = timestamp_issue {
+                log::warn!("Stream {}: {}", ws_port, issue);
+                stderr_state.logs.push(issue);
+            }
+        }) {
+            Ok(spawned) => {
+                log::info!("FFmpeg process started with PID: {:?}", spawned.0.id());
+                spawned
+            }
+            Err(e) => {
+                log::error!("Failed to start FFmpeg: {}", e);
+                self.state.logs.push(format!("Failed to start FFmpeg: {}", e));
+                return (false, None);
+            }
         };
 
-        let stderr = child.stderr.take();
+        // Let the server kill FFmpeg on shutdown; it may already have stopped
+        if !self.process_slot.attach(process.kill_handle()) {
+            process.finish();
+            return (false, None);
+        }
 
-        // Spawn a thread to read stderr
-        if let Some(stderr) = stderr {
-            std::thread::spawn(move || {
-                use std::io::BufRead;
-                let stderr_reader = std::io::BufReader::new(stderr);
-                for line in stderr_reader.lines() {
-                    if let Ok(line) = line {
-                        log::info!("FFmpeg: {}", line);
-                    }
-                }
-            });
+        // HLS FFmpeg writes files, not stdout; its stdout closing marks the exit
+        if let Some((_, dir)) = &self.hls {
+            let _ = std::io::copy(&mut stdout, &mut std::io::sink());
+            let exit = process.wait_exit(EXIT_WAIT).map(|status| (process.exit_kind(status), status));
+            process.finish();
+            return (dir.join(hls::PLAYLIST).is_file(), exit);
         }
 
         let mut reader = std::io::BufReader::with_capacity(32768, stdout);
         let mut buffer = [0u8; 32768];
         let mut total_bytes: u64 = 0;
         let mut last_log_bytes: u64 = 0;
+        let mut splitter = (options.encode.codec == OutputCodec::H264).then(Fmp4Splitter::default);
+        // The scanner also counts the frames relayed, for max_frames
+        let mut keyframes =
+            (options.keyframe_markers || options.max_frames.is_some()).then(KeyframeScanner::default);
 
         log::info!("Starting to read FFmpeg output...");
 
@@ -371,15 +2820,48 @@ async fn run_stream_server(
                 }
                 Ok(n) => {
                     total_bytes += n as u64;
+                    self.state.last_data_at.store(unix_millis(), Ordering::SeqCst);
 
                     // Log every 100KB
                     if total_bytes - last_log_bytes >= 100000 {
-                        log::info!("FFmpeg: Streamed {} bytes, receivers: {}", total_bytes, video_tx_clone.receiver_count());
+                        log::info!("FFmpeg: Streamed {} bytes, receivers: {}", total_bytes, self.state.relay.receiver_count());
                         last_log_bytes = total_bytes;
                     }
 
-                    // Always send data - receivers will get it when they connect
-                    let _ = video_tx_clone.send(buffer[..n].to_vec());
+                    // Always send data - receivers will get it when they connect.
+                    // fMP4 output is relayed as whole fragments instead.
+                    match splitter.as_mut() {
+                        Some(splitter) => {
+                            for message in splitter.push(&buffer[..n]) {
+                                match message {
+                                    Fmp4Message::Init(init) => self.state.send_init(init),
+                                    Fmp4Message::Fragment(fragment) => {
+                                        let frames = fmp4::sample_count(&fragment);
+                                        self.state.frames_delivered.fetch_add(frames, Ordering::SeqCst);
+                                        self.state.relay.send(fragment);
+                                    }
+                                }
+                            }
+                        }
+                        None => match keyframes.as_mut() {
+                            // Relay whole TS packets, a message holding
+                            // keyframes together with their markers
+                            Some(scanner) => {
+                                let frames = scanner.frames;
+                                let (packets, found) = scanner.push(&buffer[..n]);
+                                self.state.frames_delivered.fetch_add(scanner.frames - frames, Ordering::SeqCst);
+                                if found.is_empty() || !options.keyframe_markers {
+                                    if !packets.is_empty() {
+                                        self.state.relay.send(packets);
+                                    }
+                                } else {
+                                    let markers = found.iter().map(|keyframe| keyframe.marker()).collect();
+                                    self.state.relay.send_marked(markers, packets);
+                                }
+                            }
+                            None => self.state.relay.send(buffer[..n].to_vec()),
+                        },
+                    }
                 }
                 Err(e) => {
                     log::error!("FFmpeg read error: {}", e);
@@ -389,85 +2871,415 @@ async fn run_stream_server(
         }
 
         log::info!("Cleaning up FFmpeg process...");
-        let _ = child.kill();
-        let _ = child.wait();
-    });
+        // FFmpeg closes stdout as it exits; one that is still running after
+        // that is killed, which is not a crash
+        let exit = process.wait_exit(EXIT_WAIT).map(|status| (process.exit_kind(status), status));
+        process.finish();
+        (total_bytes > 0, exit)
+    }
+}
+
+const RECONNECT_MIN_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+
+// How long FFmpeg may take to exit after closing its output
+const EXIT_WAIT: Duration = Duration::from_millis(500);
+
+// Payload of the stream://ffmpeg_crashed event
+#[derive(Serialize, Clone)]
+struct FfmpegCrashed {
+    ws_port: u16,
+    crash_count: u32,
+    // Exit status as printed by the OS, e.g. "signal: 11 (SIGSEGV)"
+    status: String,
+}
+
+// How long a stream that reached max_frames waits for its clients to read the
+// rest of the relay buffer
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Payload of the stream://needs_url event
+#[derive(Serialize, Clone)]
+struct UrlRequestEvent {
+    ws_port: u16,
+    // Reconnect attempts since the stream last delivered video
+    attempt: u32,
+}
+
+// Restart FFmpeg whenever it exits until the stream is stopped, backing off
+// while the source keeps failing. Streams with `dynamic_url` ask the frontend
+// for a fresh source URL before each reconnect. Crashes of FFmpeg itself are
+// restarted separately under the crash_restart policy.
+async fn supervise_ffmpeg(
+    runner: FfmpegRunner,
+    mut rtsp_url: String,
+    mut stop_rx: broadcast::Receiver<()>,
+) {
+    let mut delay = RECONNECT_MIN_DELAY;
+    let mut attempt = 0;
+    let policy = &runner.options.crash_restart;
+    let mut crashes: VecDeque<std::time::Instant> = VecDeque::new();
 
-    // Accept WebSocket connections
     loop {
-        tokio::select! {
-            _ = shutdown_rx.recv() => {
-                log::info!("Shutting down stream server on port {}", ws_port);
+        if let Some(url) = runner.state.pending_url.lock().unwrap().take() {
+            rtsp_url = url;
+        }
+        let (delivered_video, exit) = match rtsp_url::resolve_host(&rtsp_url, runner.options.resolve).await {
+            Ok(url) => {
+                let run = runner.clone();
+                tokio::task::spawn_blocking(move || run.run(&url)).await.unwrap_or((false, None))
+            }
+            Err(e) => {
+                log::warn!("Stream {}: {}", runner.ws_port(), e);
+                runner.state.logs.push(e);
+                (false, None)
+            }
+        };
+        if runner.process_slot.is_stopped() {
+            break;
+        }
+
+        // FFmpeg we killed ourselves (a restart whose graceful stop timed
+        // out) is restarted like any other requested restart
+        let killed = matches!(exit, Some((ExitKind::Requested, _)));
+        let restarted = runner.state.restart.swap(false, Ordering::SeqCst) || killed;
+        {
+            let mut metrics = runner.state.metrics.lock().unwrap();
+            if !restarted {
+                metrics.ffmpeg_exits += 1;
+            }
+            metrics.progress = None;
+        }
+        // Counted from the relayed output as it passed through
+        let delivered = runner.state.frames_delivered.load(Ordering::SeqCst);
+
+        if runner.options.max_frames.is_some_and(|max_frames| delivered >= max_frames) {
+            log::info!("Stream {} delivered {} frames, stopping", runner.ws_port(), delivered);
+            runner.state.logs.push(format!("Frame limit reached after {} frames", delivered));
+            finish_stream(&runner).await;
+            let stream_manager = runner.app.state::<Arc<StreamManager>>();
+            for port in stream_manager.stop_source(&runner.state.share).await {
+                let _ = runner.app.emit(
+                    "stream://stopped",
+                    StreamStopped {
+                        ws_port: port,
+                        reason: "frame_limit".to_string(),
+                    },
+                );
+            }
+            break;
+        }
+        if restarted {
+            continue;
+        }
+
+        if let Some((kind, _)) = exit {
+            *runner.state.last_exit.lock().unwrap() = Some(kind);
+        }
+        if let Some((ExitKind::Crash, status)) = exit {
+            let crash_count = runner.state.crash_count.fetch_add(1, Ordering::SeqCst) + 1;
+            let now = std::time::Instant::now();
+            crashes.push_back(now);
+            let window = Duration::from_secs(policy.window_secs);
+            while crashes.front().is_some_and(|&at| now.duration_since(at) > window) {
+                crashes.pop_front();
+            }
+            log::error!("FFmpeg for stream {} crashed ({})", runner.ws_port(), status);
+            runner.state.logs.push(format!("FFmpeg crashed ({})", status));
+            let _ = runner.app.emit(
+                "stream://ffmpeg_crashed",
+                FfmpegCrashed {
+                    ws_port: runner.ws_port(),
+                    crash_count,
+                    status: status.to_string(),
+                },
+            );
+
+            if crashes.len() >= policy.max_crashes as usize {
+                log::error!(
+                    "FFmpeg for stream {} crashed {} times in {}s, stopping",
+                    runner.ws_port(),
+                    crashes.len(),
+                    policy.window_secs
+                );
+                runner.state.logs.push(format!("Stopped after {} crashes", crashes.len()));
+                let stream_manager = runner.app.state::<Arc<StreamManager>>();
+                for port in stream_manager.stop_source(&runner.state.share).await {
+                    let _ = runner.app.emit(
+                        "stream://stopped",
+                        StreamStopped {
+                            ws_port: port,
+                            reason: "ffmpeg_crashed".to_string(),
+                        },
+                    );
+                }
                 break;
             }
-            accept_result = listener.accept() => {
-                match accept_result {
-                    Ok((stream, addr)) => {
-                        log::info!("New WebSocket connection from {}", addr);
-                        let video_rx = video_tx.subscribe();
+            // The source is fine, so the reconnect backoff is left alone
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_millis(policy.restart_delay_ms)) => {}
+                _ = stop_rx.recv() => break,
+            }
+            continue;
+        }
 
-                        tokio::spawn(async move {
-                            // Custom callback to handle the jsmpeg protocol
-                            let callback = |req: &tokio_tungstenite::tungstenite::handshake::server::Request,
-                                           mut response: tokio_tungstenite::tungstenite::handshake::server::Response| {
-                                // Check if client requested the jsmpeg protocol
-                                if let Some(protocols) = req.headers().get("Sec-WebSocket-Protocol") {
-                                    if let Ok(protocols_str) = protocols.to_str() {
-                                        if protocols_str.contains("jsmpeg") {
-                                            // Echo back the jsmpeg protocol
-                                            response.headers_mut().insert(
-                                                "Sec-WebSocket-Protocol",
-                                                "jsmpeg".parse().unwrap(),
-                                            );
-                                        }
-                                    }
-                                }
-                                Ok(response)
-                            };
+        // A local file that played to its end is done; reconnecting would
+        // replay it from the start
+        if matches!(exit, Some((ExitKind::Eof, _))) && ffmpeg::is_file_input(&rtsp_url, &runner.options, true) {
+            log::info!("Source file of stream {} ended, stopping", runner.ws_port());
+            runner.state.logs.push("Source file ended".to_string());
+            finish_stream(&runner).await;
+            let stream_manager = runner.app.state::<Arc<StreamManager>>();
+            for port in stream_manager.stop_source(&runner.state.share).await {
+                let _ = runner.app.emit(
+                    "stream://stopped",
+                    StreamStopped {
+                        ws_port: port,
+                        reason: "source_ended".to_string(),
+                    },
+                );
+            }
+            break;
+        }
 
-                            match tokio_tungstenite::accept_hdr_async(stream, callback).await {
-                                Ok(ws_stream) => {
-                                    log::info!("WebSocket handshake successful");
-                                    handle_ws_connection(ws_stream, video_rx).await;
-                                }
-                                Err(e) => {
-                                    log::error!("WebSocket handshake failed: {}", e);
-                                }
-                            }
-                        });
-                    }
-                    Err(e) => {
-                        log::error!("Accept error: {}", e);
+        // Operator-marked dead sources are not retried
+        let settings = runner.app.state::<SettingsStore>();
+        if settings.is_source_dead(&rtsp_url::source_key(&rtsp_url)).await {
+            log::warn!("Source of stream {} is marked dead, stopping instead of reconnecting", runner.ws_port());
+            runner.state.logs.push("Source is marked dead, not reconnecting".to_string());
+            let stream_manager = runner.app.state::<Arc<StreamManager>>();
+            for port in stream_manager.stop_source(&runner.state.share).await {
+                let _ = runner.app.emit(
+                    "stream://stopped",
+                    StreamStopped {
+                        ws_port: port,
+                        reason: "source_dead".to_string(),
+                    },
+                );
+            }
+            break;
+        }
+
+        // A run that delivered video resets the backoff
+        if delivered_video {
+            delay = RECONNECT_MIN_DELAY;
+            attempt = 0;
+        }
+        attempt += 1;
+        log::warn!("FFmpeg for stream {} exited, reconnecting in {}s", runner.ws_port(), delay.as_secs());
+        runner.state.logs.push(format!("FFmpeg exited, reconnecting in {}s", delay.as_secs()));
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = stop_rx.recv() => break,
+        }
+        delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+
+        if runner.options.dynamic_url {
+            tokio::select! {
+                url = request_stream_url(&runner, attempt) => {
+                    if let Some(url) = url {
+                        rtsp_url = url;
                     }
                 }
+                _ = stop_rx.recv() => break,
             }
         }
     }
+}
+
+// Let connected clients receive the video still buffered in the relay before
+// the stream is stopped. Clients leave once they have read everything.
+async fn finish_stream(runner: &FfmpegRunner) {
+    runner.state.relay.close();
+    let deadline = tokio::time::Instant::now() + FLUSH_TIMEOUT;
+    while runner.state.relay.receiver_count() > 0 && tokio::time::Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
 
-    // Cleanup - abort the blocking FFmpeg task
-    ffmpeg_task.abort();
+// Emit stream://needs_url and wait for provide_stream_url. Returns None if no
+// URL arrives in time, in which case the last URL is reused.
+async fn request_stream_url(runner: &FfmpegRunner, attempt: u32) -> Option<String> {
+    let (url_tx, url_rx) = oneshot::channel();
+    *runner.state.url_request.lock().unwrap() = Some(url_tx);
+    let _ = runner.app.emit(
+        "stream://needs_url",
+        UrlRequestEvent { ws_port: runner.ws_port(), attempt },
+    );
 
-    Ok(())
+    let timeout = Duration::from_millis(runner.options.dynamic_url_timeout_ms);
+    let url = tokio::time::timeout(timeout, url_rx).await.ok().and_then(Result::ok);
+    runner.state.url_request.lock().unwrap().take();
+    if url.is_none() {
+        log::warn!("No new URL for stream {}, reconnecting with the last one", runner.ws_port());
+        runner.state.logs.push("No new source URL provided, reconnecting with the last URL".to_string());
+    }
+    url
+}
+
+// Payload of the stream://stopped event, sent when the backend stops a stream
+// on its own
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StreamStopped {
+    pub ws_port: u16,
+    pub reason: String,
+}
+
+// Payload of the stream://recording_stopped event
+#[derive(Serialize, Clone)]
+struct RecordingStoppedEvent {
+    ws_port: u16,
+    reason: String,
+    available_bytes: u64,
+}
+
+// Stop the recording output before the disk fills. FFmpeg is restarted
+// without it, so live viewers only see a short reconnect.
+async fn monitor_disk_space(
+    app: AppHandle,
+    recording: RecordingConfig,
+    state: StreamState,
+) {
+    loop {
+        tokio::time::sleep(disk::CHECK_INTERVAL).await;
+        let available = match disk::available_space(&recording) {
+            Ok(available) => available,
+            Err(e) => {
+                log::warn!("{}", e);
+                continue;
+            }
+        };
+        if disk::is_low(&recording, available) {
+            let ws_port = state.port.load(Ordering::SeqCst);
+            log::warn!("Stream {}: disk almost full ({} bytes free), stopping recording", ws_port, available);
+            state.logs.push(format!("Recording stopped: disk almost full ({} bytes free)", available));
+            state.recording.store(false, Ordering::SeqCst);
+            let _ = app.emit(
+                "stream://recording_stopped",
+                RecordingStoppedEvent {
+                    ws_port,
+                    reason: "low_disk_space".to_string(),
+                    available_bytes: available,
+                },
+            );
+            // Quitting lets FFmpeg finalize the recording file; the restart
+            // is requested so it isn't taken for a source failure
+            state.request_restart();
+            break;
+        }
+    }
+}
+
+// Add a metrics sample to the stream's history every interval
+async fn sample_metrics(state: StreamState, interval_secs: u64) {
+    let period = Duration::from_secs(interval_secs);
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    let mut last_bytes = state.relay.stats().video_bytes;
+    loop {
+        ticker.tick().await;
+        let stats = state.relay.stats();
+        let bytes = stats.video_bytes - last_bytes;
+        last_bytes = stats.video_bytes;
+
+        let mut metrics = state.metrics.lock().unwrap();
+        let progress = metrics.progress.clone().unwrap_or_default();
+        let sample = MetricsSample {
+            timestamp: chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+            throughput_kbps: bytes as f64 * 8.0 / 1000.0 / interval_secs as f64,
+            fps: progress.fps,
+            clients: state.relay.receiver_count(),
+            lag_events: stats.lag_events,
+            lagged_messages: stats.lagged_messages,
+            ffmpeg_exits: metrics.ffmpeg_exits,
+            dropped_frames: progress.drop,
+            duplicated_frames: progress.dup,
+        };
+        metrics.push(sample);
+    }
+}
+
+// Set the clock_overlay text of every stream from the app's clock at the
+// start of each second, so all cameras of a video wall show the same second
+async fn sync_clock_overlays(stream_manager: Arc<StreamManager>) {
+    loop {
+        let now = chrono::Local::now();
+        let to_next_second = 1_000_000_000 - now.timestamp_subsec_nanos().min(999_999_999);
+        let second = now + chrono::Duration::nanoseconds(to_next_second.into());
+        tokio::time::sleep(Duration::from_nanos(to_next_second.into())).await;
+
+        // Commands are collected under the lock and sent after releasing it
+        let commands: Vec<(ProcessSlot, String)> = {
+            let streams = stream_manager.streams.read().await;
+            let mut synced: Vec<&SourceShare> = Vec::new();
+            let mut commands = Vec::new();
+            for info in streams.values() {
+                let Some(clock) = &info.options.clock_overlay else {
+                    continue;
+                };
+                // Ports sharing a source share its FFmpeg
+                if info.hls.is_some() || synced.iter().any(|share| share.same(&info.state.share)) {
+                    continue;
+                }
+                synced.push(&info.state.share);
+                commands.push((info.state.process_slot.clone(), ffmpeg::clock_command(clock, &second)));
+            }
+            commands
+        };
+        for (process_slot, command) in commands {
+            process_slot.send_command(&command);
+        }
+    }
 }
 
-// Handle individual WebSocket connection
+// Handle individual WebSocket connection. With an idle timeout, the client is
+// pinged every third of it and disconnected once no pong arrived for the
+// whole timeout, e.g. a frozen tab that stopped reading.
 async fn handle_ws_connection(
     ws_stream: tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>,
-    mut video_rx: broadcast::Receiver<Vec<u8>>,
+    mut video_rx: RelayReceiver,
+    init_segment: Option<Vec<u8>>,
+    idle_timeout: Option<Duration>,
 ) {
     let (mut ws_sender, mut ws_receiver) = ws_stream.split();
+    let last_pong = Arc::new(Mutex::new(std::time::Instant::now()));
+    let ping_interval = idle_timeout.map(|timeout| timeout / 3);
 
     // Send video data to client
-    let send_task = tokio::spawn(async move {
-        while let Ok(data) = video_rx.recv().await {
-            if ws_sender.send(Message::Binary(data.into())).await.is_err() {
-                break;
+    let mut send_task = tokio::spawn(async move {
+        if let Some(init) = init_segment {
+            if ws_sender.send(Message::Binary(init)).await.is_err() {
+                return;
+            }
+        }
+        let mut ping = ping_interval.map(|period| tokio::time::interval_at(tokio::time::Instant::now() + period, period));
+        loop {
+            let messages = tokio::select! {
+                message = video_rx.recv() => match message {
+                    Ok(RelayMessage::Video(data)) => vec![Message::Binary(data)],
+                    // Markers go right before the video they describe
+                    Ok(RelayMessage::Marked(markers, data)) => {
+                        markers.into_iter().map(Message::Text).chain([Message::Binary(data)]).collect()
+                    }
+                    Err(_) => break,
+                },
+                _ = async {
+                    match ping.as_mut() {
+                        Some(ping) => ping.tick().await,
+                        None => std::future::pending().await,
+                    }
+                } => vec![Message::Ping(Vec::new())],
+            };
+            for message in messages {
+                if ws_sender.send(message).await.is_err() {
+                    return;
+                }
             }
         }
     });
 
     // Handle incoming messages (for keep-alive/control)
-    let recv_task = tokio::spawn(async move {
+    let pong = Arc::clone(&last_pong);
+    let mut recv_task = tokio::spawn(async move {
         while let Some(msg) = ws_receiver.next().await {
             match msg {
                 Ok(Message::Close(_)) => break,
@@ -475,17 +3287,35 @@ async fn handle_ws_connection(
                     // Pong is handled automatically by tungstenite
                     log::debug!("Received ping: {:?}", data);
                 }
+                Ok(Message::Pong(_)) => *pong.lock().unwrap() = std::time::Instant::now(),
                 Err(_) => break,
                 _ => {}
             }
         }
     });
 
-    // Wait for either task to complete
+    let idle = async {
+        let Some((timeout, period)) = idle_timeout.zip(ping_interval) else {
+            return std::future::pending().await;
+        };
+        loop {
+            tokio::time::sleep(period).await;
+            if last_pong.lock().unwrap().elapsed() > timeout {
+                log::info!("WebSocket client sent no pong for {}s, disconnecting", timeout.as_secs());
+                return;
+            }
+        }
+    };
+
+    // Wait for either task to complete (or the client to go idle); the other
+    // one is stopped so the client's relay slot is freed right away
     tokio::select! {
-        _ = send_task => {}
-        _ = recv_task => {}
+        _ = &mut send_task => {}
+        _ = &mut recv_task => {}
+        _ = idle => {}
     }
+    send_task.abort();
+    recv_task.abort();
 }
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
@@ -496,12 +3326,377 @@ pub fn run() {
         .plugin(tauri_plugin_opener::init())
         .plugin(tauri_plugin_shell::init())
         .manage(Arc::new(StreamManager::default()))
+        .setup(|app| {
+            let config_dir = app.path().app_config_dir().ok();
+            app.manage(SettingsStore::load(config_dir));
+            let stream_manager = Arc::clone(app.state::<Arc<StreamManager>>().inner());
+            tauri::async_runtime::spawn(sync_clock_overlays(stream_manager));
+            Ok(())
+        })
         .invoke_handler(tauri::generate_handler![
             start_stream,
+            start_hls_stream,
             stop_stream,
+            swap_ports,
             get_active_streams,
-            check_ffmpeg
+            probe_all_streams,
+            push_client_feedback,
+            check_ffmpeg,
+            get_stream_logs,
+            get_stream_quality,
+            get_stream_metrics,
+            export_metrics_csv,
+            is_stream_watched,
+            get_watch_history,
+            get_stream_clients,
+            get_socket_options,
+            get_jsmpeg_config,
+            get_settings,
+            set_settings,
+            mark_source_dead,
+            unmark_source_dead,
+            provide_stream_url,
+            update_credentials,
+            enable_shadow_buffer,
+            disable_shadow_buffer,
+            promote_shadow,
+            normalize_rtsp_url,
+            capture_all_snapshots,
+            get_sample_fragment,
+            list_supported_protocols,
+            list_capture_devices,
+            get_capabilities,
+            drain_recordings,
+            get_api_schema,
+            reprobe_stream,
+            benchmark_capacity,
+            cancel_benchmark
         ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|app, event| {
+            // Finalize recordings before the process exits. The exit is held
+            // while the drain runs on the async runtime, then done for real.
+            static DRAIN_STARTED: AtomicBool = AtomicBool::new(false);
+            static DRAINED: AtomicBool = AtomicBool::new(false);
+            if let tauri::RunEvent::ExitRequested { code, api, .. } = event {
+                if DRAINED.load(Ordering::SeqCst) {
+                    return;
+                }
+                api.prevent_exit();
+                if DRAIN_STARTED.swap(true, Ordering::SeqCst) {
+                    return;
+                }
+                let app = app.clone();
+                tauri::async_runtime::spawn(async move {
+                    let stream_manager = Arc::clone(app.state::<Arc<StreamManager>>().inner());
+                    let report = stream_manager.drain_recordings(None).await;
+                    if !report.finalized.is_empty() || !report.timed_out.is_empty() {
+                        log::info!(
+                            "Recordings drained on exit: {:?} finalized, {:?} timed out",
+                            report.finalized,
+                            report.timed_out
+                        );
+                    }
+                    // FFmpeg of a running benchmark would outlive the app
+                    if benchmark::cancel().await {
+                        log::info!("Benchmark cancelled on exit");
+                    }
+                    DRAINED.store(true, Ordering::SeqCst);
+                    app.exit(code.unwrap_or(0));
+                });
+            }
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_stream_info() -> StreamInfo {
+        let (shutdown_tx, _) = broadcast::channel::<()>(1);
+        StreamInfo {
+            rtsp_url: "rtsp://127.0.0.1/test".to_string(),
+            options: StreamOptions::default(),
+            shutdown_tx,
+            state: StreamState::default(),
+            hls: None,
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_registration_on_same_port_admits_one() {
+        let manager = Arc::new(StreamManager::default());
+        let barrier = Arc::new(tokio::sync::Barrier::new(64));
+
+        let tasks: Vec<_> = (0..64)
+            .map(|_| {
+                let manager = Arc::clone(&manager);
+                let barrier = Arc::clone(&barrier);
+                tokio::spawn(async move {
+                    barrier.wait().await;
+                    manager.admit(9999, test_stream_info(), &StreamLimits::default()).await.is_ok()
+                })
+            })
+            .collect();
+
+        let mut successes = 0;
+        for task in tasks {
+            if task.await.unwrap() {
+                successes += 1;
+            }
+        }
+
+        assert_eq!(successes, 1);
+        assert_eq!(manager.streams.read().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn unregister_ignores_newer_stream_on_same_port() {
+        let manager = StreamManager::default();
+        let old = test_stream_info();
+        let old_tx = old.shutdown_tx.clone();
+        assert!(manager.admit(9999, old, &StreamLimits::default()).await.is_ok());
+
+        // Stream stopped and a new one started on the same port
+        manager.streams.write().await.remove(&9999);
+        assert!(manager.admit(9999, test_stream_info(), &StreamLimits::default()).await.is_ok());
+
+        manager.unregister(&old_tx).await;
+        assert!(manager.streams.read().await.contains_key(&9999));
+    }
+
+    #[tokio::test]
+    async fn higher_priority_stream_preempts_the_lowest() {
+        let manager = StreamManager::default();
+        let limits = StreamLimits {
+            max_streams: Some(2),
+            preemption: config::PreemptionPolicy::Stop,
+            ..StreamLimits::default()
+        };
+        let with_priority = |priority| {
+            let mut info = test_stream_info();
+            info.options.priority = priority;
+            info
+        };
+        assert!(manager.admit(9001, with_priority(1), &limits).await.is_ok());
+        assert!(manager.admit(9002, with_priority(2), &limits).await.is_ok());
+
+        // Equal priority doesn't preempt
+        assert!(manager.admit(9003, with_priority(1), &limits).await.is_err());
+
+        let preempted = manager.admit(9003, with_priority(5), &limits).await.unwrap();
+        assert_eq!(preempted.stopped, [9001]);
+        let mut ports: Vec<u16> = manager.streams.read().await.keys().copied().collect();
+        ports.sort();
+        assert_eq!(ports, [9002, 9003]);
+    }
+
+    #[test]
+    fn client_abr_steps_down_then_recovers() {
+        let config = config::ClientAbrConfig::default();
+        let mut abr = AbrState::default();
+        let start = std::time::Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let lagging = ClientFeedback { lag_ms: 3000, dropped_frames: 0 };
+        let healthy = ClientFeedback::default();
+
+        assert_eq!(abr.observe(&config, 1000, &lagging, at(0)), Some(700));
+        // Within the cooldown
+        assert_eq!(abr.observe(&config, 1000, &lagging, at(5)), None);
+        assert_eq!(abr.observe(&config, 1000, &lagging, at(10)), Some(490));
+
+        assert_eq!(abr.observe(&config, 1000, &healthy, at(20)), None);
+        assert_eq!(abr.observe(&config, 1000, &healthy, at(50)), Some(588));
+        assert_eq!(abr.bitrate_kbps, Some(588));
+    }
+
+    #[test]
+    fn health_follows_the_latest_video() {
+        let state = StreamState::default();
+        assert_eq!(state.health(10_000).health, Health::NoData);
+
+        state.last_data_at.store(9_000, Ordering::SeqCst);
+        let health = state.health(10_000);
+        assert_eq!(health.health, Health::Healthy);
+        assert_eq!(health.idle_ms, Some(1_000));
+
+        assert_eq!(state.health(20_000).health, Health::Stalled);
+    }
+
+    #[tokio::test]
+    async fn client_feedback_lowers_the_next_runs_bitrate() {
+        let mut info = test_stream_info();
+        info.options.client_abr = Some(config::ClientAbrConfig::default());
+        let ceiling = info.options.encode.bitrate_kbps;
+        let lagging = ClientFeedback {
+            lag_ms: 5_000,
+            dropped_frames: 30,
+        };
+
+        let status = apply_client_feedback(9999, &info, &lagging, std::time::Instant::now()).unwrap();
+        assert!(status.changed && status.bitrate_kbps < ceiling);
+        assert!(info.state.restart.load(Ordering::SeqCst));
+
+        // The restarted FFmpeg encodes MPEG1 under the adapted ceiling rather
+        // than at the fixed quantizer, which would ignore it
+        let options = info.state.run_options(&info.options);
+        let args = ffmpeg::build_ffmpeg_args("rtsp://127.0.0.1/test", &options, false, None);
+        let value = |flag: &str| args.iter().position(|arg| arg == flag).map(|i| args[i + 1].clone());
+        let bitrate = format!("{}k", status.bitrate_kbps);
+        assert_eq!(value("-b:v"), Some(bitrate.clone()));
+        assert_eq!(value("-maxrate"), Some(bitrate));
+        assert_eq!(value("-q:v"), None);
+
+        info.options.client_abr = None;
+        assert!(apply_client_feedback(9999, &info, &lagging, std::time::Instant::now()).is_err());
+    }
+
+    #[tokio::test]
+    async fn shared_sources_stop_with_the_last_port() {
+        let share = SourceShare::default();
+        assert!(share.try_acquire());
+        assert_eq!(share.ports(), 2);
+        assert_eq!(share.release(), 1);
+
+        let waiter = share.clone();
+        let released = tokio::spawn(async move { waiter.wait_released().await });
+        assert_eq!(share.release(), 0);
+        released.await.unwrap();
+        // No port can join a source that is being torn down
+        assert!(!share.try_acquire());
+        assert_eq!(share.ports(), 0);
+    }
+
+    #[test]
+    fn sharing_needs_the_same_source_and_options() {
+        let mut info = test_stream_info();
+        info.rtsp_url = "rtsp://cam/live".to_string();
+        info.options.share_source = true;
+        let mut options = info.options.clone();
+        options.priority = 9;
+        options.socket.send_buffer_bytes = Some(1 << 20);
+        assert!(can_share(&info, "rtsp://cam:554/live", &options, None));
+
+        assert!(!can_share(&info, "rtsp://cam/other", &options, None));
+        assert!(!can_share(&info, "rtsp://cam/live", &options, Some(Path::new("/opt/ffmpeg"))));
+        options.encode.bitrate_kbps += 1;
+        assert!(!can_share(&info, "rtsp://cam/live", &options, None));
+        options.encode.bitrate_kbps -= 1;
+
+        info.state.share.release();
+        assert!(!can_share(&info, "rtsp://cam/live", &options, None));
+    }
+
+    #[test]
+    fn watch_intervals_span_the_connected_clients() {
+        let watch = WatchHistory::default();
+        let first = watch.connected(ClientInfo::default());
+        let second = watch.connected(ClientInfo::default());
+        assert_ne!(first, second);
+        assert_eq!(watch.clients(), 2);
+        assert_eq!(watch.intervals().len(), 1);
+
+        watch.disconnected(first);
+        assert!(watch.intervals()[0].ended_at_ms.is_none());
+        watch.disconnected(second);
+        let interval = watch.intervals()[0].clone();
+        assert!(interval.ended_at_ms.is_some_and(|ended| ended >= interval.started_at_ms));
+        assert!(watch.client_list().is_empty());
+
+        // Unknown ids don't close the next interval
+        watch.connected(ClientInfo::default());
+        watch.disconnected(first);
+        assert_eq!(watch.intervals().len(), 2);
+        assert!(watch.intervals()[1].ended_at_ms.is_none());
+
+        for _ in 0..WatchHistory::CAPACITY {
+            let id = watch.client_list()[0].id;
+            watch.disconnected(id);
+            watch.connected(ClientInfo::default());
+        }
+        assert_eq!(watch.intervals().len(), WatchHistory::CAPACITY);
+    }
+
+    #[tokio::test]
+    async fn swap_ports_exchanges_listeners_and_entries() {
+        let manager = Arc::new(StreamManager::default());
+        let mut ports = Vec::new();
+        for url in ["rtsp://127.0.0.1/a", "rtsp://127.0.0.1/b"] {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let mut info = test_stream_info();
+            info.rtsp_url = url.to_string();
+            info.state.port.store(port, Ordering::SeqCst);
+            let clients = ClientConfig {
+                subprotocols: Arc::default(),
+                socket: SocketConfig::default(),
+                state: info.state.clone(),
+            };
+            tokio::spawn(serve_clients(
+                listener,
+                port,
+                clients,
+                #[cfg(feature = "webrtc")]
+                None,
+                info.shutdown_tx.subscribe(),
+            ));
+            while info.state.port_swap.lock().unwrap().is_none() {
+                tokio::task::yield_now().await;
+            }
+            assert!(manager.admit(port, info, &StreamLimits::default()).await.is_ok());
+            ports.push(port);
+        }
+        let (port_a, port_b) = (ports[0], ports[1]);
+
+        manager.swap_ports(port_a, port_b).await.unwrap();
+        let streams = manager.streams.read().await;
+        assert_eq!(streams[&port_a].rtsp_url, "rtsp://127.0.0.1/b");
+        assert_eq!(streams[&port_b].rtsp_url, "rtsp://127.0.0.1/a");
+        assert_eq!(streams[&port_a].state.port.load(Ordering::SeqCst), port_a);
+        assert_eq!(streams[&port_b].state.port.load(Ordering::SeqCst), port_b);
+        drop(streams);
+
+        assert!(manager.swap_ports(port_a, port_a).await.is_err());
+        assert!(manager.swap_ports(port_a, 1).await.is_err());
+        manager.swap_ports(port_b, port_a).await.unwrap();
+        assert_eq!(manager.streams.read().await[&port_a].rtsp_url, "rtsp://127.0.0.1/a");
+
+        // A server that exits with the swap still queued drops it; the other
+        // server gets its own listener back and keeps serving
+        let reserved = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port_c = reserved.local_addr().unwrap().port();
+        let info = test_stream_info();
+        let (swap_tx, mut swap_rx) = mpsc::unbounded_channel::<PortSwap>();
+        *info.state.port_swap.lock().unwrap() = Some(swap_tx);
+        tokio::spawn(async move { while swap_rx.recv().await.is_some() {} });
+        assert!(manager.admit(port_c, info, &StreamLimits::default()).await.is_ok());
+
+        assert!(manager.swap_ports(port_a, port_c).await.is_err());
+        assert_eq!(manager.streams.read().await[&port_a].rtsp_url, "rtsp://127.0.0.1/a");
+        assert!(tokio::net::TcpStream::connect(("127.0.0.1", port_a)).await.is_ok());
+        manager.swap_ports(port_a, port_b).await.unwrap();
+        assert_eq!(manager.streams.read().await[&port_b].rtsp_url, "rtsp://127.0.0.1/a");
+
+        // A stream stopped while the listeners are exchanged stays stopped;
+        // only the other entry follows its listener
+        let listener_d = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port_d = listener_d.local_addr().unwrap().port();
+        let info = test_stream_info();
+        let (swap_tx, mut swap_rx) = mpsc::unbounded_channel::<PortSwap>();
+        *info.state.port_swap.lock().unwrap() = Some(swap_tx);
+        let stopping = Arc::clone(&manager);
+        tokio::spawn(async move {
+            let swap = swap_rx.recv().await.unwrap();
+            stopping.streams.write().await.remove(&port_d);
+            let _ = swap.give.send(listener_d);
+            let _ = swap.take.await;
+        });
+        assert!(manager.admit(port_d, info, &StreamLimits::default()).await.is_ok());
+
+        manager.swap_ports(port_b, port_d).await.unwrap();
+        let streams = manager.streams.read().await;
+        assert_eq!(streams[&port_d].rtsp_url, "rtsp://127.0.0.1/a");
+        assert!(!streams.contains_key(&port_b));
+    }
 }
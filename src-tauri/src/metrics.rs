@@ -0,0 +1,151 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+
+// Counters from FFmpeg's progress line, e.g.
+// "frame=  250 fps= 25 q=5.0 size=  1024KiB time=00:00:10.00 ... dup=0 drop=3 speed=1x".
+// Frame, dup and drop counts start over with each FFmpeg run.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Progress {
+    pub frame: u64,
+    pub fps: f64,
+    pub dup: u64,
+    pub drop: u64,
+}
+
+pub fn parse_progress(line: &str) -> Option<Progress> {
+    if !line.starts_with("frame=") {
+        return None;
+    }
+    let value = |key: &str| -> Option<&str> {
+        let rest = &line[line.find(key)? + key.len()..];
+        rest.split_whitespace().next()
+    };
+    let count = |key: &str| value(key).and_then(|v| v.parse().ok()).unwrap_or(0);
+    Some(Progress {
+        frame: value("frame=")?.parse().ok()?,
+        fps: value("fps=").and_then(|v| v.parse().ok()).unwrap_or(0.0),
+        dup: count(" dup="),
+        drop: count(" drop="),
+    })
+}
+
+// One point of a stream's metrics time series
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MetricsSample {
+    // Local time, "YYYY-MM-DD HH:MM:SS"
+    pub timestamp: String,
+    // Video relayed to clients during the sample interval
+    pub throughput_kbps: f64,
+    pub fps: f64,
+    pub clients: usize,
+    // Cumulative since the stream started
+    pub lag_events: u64,
+    pub lagged_messages: u64,
+    pub ffmpeg_exits: u64,
+    // Cumulative since the last FFmpeg start
+    pub dropped_frames: u64,
+    pub duplicated_frames: u64,
+}
+
+// Bounded history of samples, plus the live counters they are taken from
+pub struct MetricsHistory {
+    samples: VecDeque<MetricsSample>,
+    capacity: usize,
+    // Latest progress of the running FFmpeg; None while it is down
+    pub progress: Option<Progress>,
+    // Unrequested FFmpeg exits (crashes, lost sources)
+    pub ffmpeg_exits: u64,
+}
+
+impl Default for MetricsHistory {
+    fn default() -> Self {
+        Self::new(crate::config::MetricsConfig::default().history)
+    }
+}
+
+impl MetricsHistory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            samples: VecDeque::new(),
+            capacity,
+            progress: None,
+            ffmpeg_exits: 0,
+        }
+    }
+
+    pub fn push(&mut self, sample: MetricsSample) {
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    pub fn samples(&self) -> Vec<MetricsSample> {
+        self.samples.iter().cloned().collect()
+    }
+}
+
+// Samples as CSV with a header row
+pub fn to_csv(samples: &[MetricsSample]) -> String {
+    let mut csv = String::from(
+        "timestamp,throughput_kbps,fps,clients,lag_events,lagged_messages,ffmpeg_exits,dropped_frames,duplicated_frames\n",
+    );
+    for s in samples {
+        let _ = writeln!(
+            csv,
+            "{},{:.1},{:.1},{},{},{},{},{},{}",
+            s.timestamp,
+            s.throughput_kbps,
+            s.fps,
+            s.clients,
+            s.lag_events,
+            s.lagged_messages,
+            s.ffmpeg_exits,
+            s.dropped_frames,
+            s.duplicated_frames
+        );
+    }
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress_lines_are_parsed() {
+        let line = "frame=  250 fps= 25 q=5.0 size=    1024KiB time=00:00:10.00 bitrate= 838.9kbits/s dup=2 drop=3 speed=1x";
+        assert_eq!(
+            parse_progress(line),
+            Some(Progress { frame: 250, fps: 25.0, dup: 2, drop: 3 })
+        );
+        // Without dup/drop counts, and FFmpeg's other output
+        assert_eq!(
+            parse_progress("frame=10 fps=0.0 q=0.0 size=0KiB time=00:00:00.40 speed=0.8x"),
+            Some(Progress { frame: 10, fps: 0.0, dup: 0, drop: 0 })
+        );
+        assert_eq!(parse_progress("Input #0, rtsp, from 'rtsp://cam/live':"), None);
+        assert_eq!(parse_progress("frame=N/A fps=0"), None);
+    }
+
+    #[test]
+    fn samples_are_exported_with_a_header() {
+        let sample = MetricsSample {
+            timestamp: "2024-05-01 12:00:00".to_string(),
+            throughput_kbps: 812.345,
+            fps: 24.96,
+            clients: 2,
+            lag_events: 1,
+            lagged_messages: 4,
+            ffmpeg_exits: 0,
+            dropped_frames: 3,
+            duplicated_frames: 5,
+        };
+        let csv = to_csv(&[sample]);
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("timestamp,throughput_kbps,fps,"));
+        assert_eq!(lines[1], "2024-05-01 12:00:00,812.3,25.0,2,1,4,0,3,5");
+    }
+}
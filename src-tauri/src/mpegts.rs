@@ -0,0 +1,212 @@
+// Finds video keyframes in FFmpeg's MPEG-TS output. FFmpeg's muxer sets the
+// random_access_indicator on the TS packet that starts each keyframe's PES,
+// so no video parsing is needed. Output is regrouped into whole 188-byte
+// packets so a keyframe's offset always falls inside one relayed message.
+// The muxer writes one PES per video frame, so counting PES starts counts
+// the frames relayed.
+
+const PACKET_SIZE: usize = 188;
+const SYNC_BYTE: u8 = 0x47;
+// PTS clock of MPEG-TS
+const PTS_HZ: f64 = 90_000.0;
+
+pub struct Keyframe {
+    // Offset of the keyframe's first TS packet in the returned data
+    pub offset: usize,
+    // Presentation time in seconds, if the PES header carries one
+    pub pts: Option<f64>,
+}
+
+impl Keyframe {
+    // JSON marker sent to clients right before the message holding the keyframe
+    pub fn marker(&self) -> String {
+        serde_json::json!({
+            "type": "keyframe",
+            "byte_offset": self.offset,
+            "pts": self.pts,
+        })
+        .to_string()
+    }
+}
+
+#[derive(Default)]
+pub struct KeyframeScanner {
+    // Trailing bytes of a packet split across reads
+    partial: Vec<u8>,
+    // Video frames started in the packets returned so far
+    pub frames: u64,
+}
+
+impl KeyframeScanner {
+    // Feed bytes from FFmpeg. Returns the whole packets received so far and
+    // the keyframes that start in them; an incomplete packet is kept for the
+    // next call. Bytes that don't line up with packets are dropped until the
+    // sync byte pattern is found again.
+    pub fn push(&mut self, data: &[u8]) -> (Vec<u8>, Vec<Keyframe>) {
+        let mut input = std::mem::take(&mut self.partial);
+        input.extend_from_slice(data);
+
+        let mut packets = Vec::with_capacity(input.len());
+        let mut keyframes = Vec::new();
+        let mut pos = 0;
+        while input.len() - pos >= PACKET_SIZE {
+            if input[pos] != SYNC_BYTE {
+                let skip = next_sync(&input[pos..]);
+                log::warn!("MPEG-TS output lost packet alignment, skipping {} bytes", skip);
+                pos += skip;
+                continue;
+            }
+            let packet = &input[pos..pos + PACKET_SIZE];
+            if video_pes(packet).is_some() {
+                self.frames += 1;
+            }
+            if let Some(pts) = keyframe_pts(packet) {
+                keyframes.push(Keyframe {
+                    offset: packets.len(),
+                    pts: pts.map(|pts| pts as f64 / PTS_HZ),
+                });
+            }
+            packets.extend_from_slice(packet);
+            pos += PACKET_SIZE;
+        }
+        self.partial = input.split_off(pos);
+        (packets, keyframes)
+    }
+}
+
+// Offset of the next likely packet start: a sync byte with another one a
+// packet later, or with the data ending before that (checked on the next
+// packet instead)
+fn next_sync(data: &[u8]) -> usize {
+    (1..data.len())
+        .find(|&i| data[i] == SYNC_BYTE && !matches!(data.get(i + PACKET_SIZE), Some(&byte) if byte != SYNC_BYTE))
+        .unwrap_or(data.len())
+}
+
+// Header of the video PES (stream id 0xE0-0xEF) the packet starts, if any
+fn video_pes(packet: &[u8]) -> Option<&[u8]> {
+    if packet[0] != SYNC_BYTE || packet[1] & 0x40 == 0 {
+        return None;
+    }
+    let payload_start = match (packet[3] >> 4) & 0x3 {
+        0x1 => 4,
+        0x3 => 5 + packet[4] as usize,
+        _ => return None, // No payload
+    };
+    let pes = packet.get(payload_start..)?;
+    (pes.len() >= 9 && pes[..3] == [0, 0, 1] && (0xE0..=0xEF).contains(&pes[3])).then_some(pes)
+}
+
+// Some(pts) if the packet starts a video keyframe: random access indicator
+// set, payload unit start, and a video PES (stream id 0xE0-0xEF)
+fn keyframe_pts(packet: &[u8]) -> Option<Option<u64>> {
+    let adaptation_field_control = (packet[3] >> 4) & 0x3;
+    if adaptation_field_control != 0x3 {
+        return None; // Needs both an adaptation field and a payload
+    }
+    if packet[4] == 0 || packet[5] & 0x40 == 0 {
+        return None;
+    }
+
+    let pes = video_pes(packet)?;
+    let has_pts = pes[7] & 0x80 != 0;
+    Some(match pes.get(9..14) {
+        Some(pts) if has_pts => Some(
+            ((pts[0] as u64 >> 1) & 0x7) << 30
+                | (pts[1] as u64) << 22
+                | ((pts[2] as u64) >> 1) << 15
+                | (pts[3] as u64) << 7
+                | (pts[4] as u64) >> 1,
+        ),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // TS packet starting a PES of `stream_id`, with the random access
+    // indicator set as requested and an optional PTS (90 kHz)
+    fn pes_packet(stream_id: u8, random_access: bool, pts: Option<u64>) -> Vec<u8> {
+        let mut packet = vec![SYNC_BYTE, 0x40, 0x00, 0x30, 1, if random_access { 0x40 } else { 0 }];
+        packet.extend([0, 0, 1, stream_id, 0, 0, 0x80]);
+        match pts {
+            Some(pts) => packet.extend([
+                0x80,
+                5,
+                0x21 | ((pts >> 29) & 0x0E) as u8,
+                (pts >> 22) as u8,
+                ((pts >> 14) & 0xFE) as u8 | 1,
+                (pts >> 7) as u8,
+                ((pts << 1) & 0xFE) as u8 | 1,
+            ]),
+            None => packet.extend([0, 0]),
+        }
+        packet.resize(PACKET_SIZE, 0xFF);
+        packet
+    }
+
+    #[test]
+    fn keyframes_are_found_across_reads() {
+        let mut data = pes_packet(0xE0, false, Some(0));
+        data.extend(pes_packet(0xE0, true, Some(2 * 90_000)));
+        let mut scanner = KeyframeScanner::default();
+
+        let (packets, keyframes) = scanner.push(&data[..100]);
+        assert!(packets.is_empty() && keyframes.is_empty());
+        let (packets, keyframes) = scanner.push(&data[100..300]);
+        assert_eq!(packets.len(), PACKET_SIZE);
+        assert!(keyframes.is_empty());
+        let (packets, keyframes) = scanner.push(&data[300..]);
+        assert_eq!(packets, data[PACKET_SIZE..]);
+        assert_eq!(keyframes.len(), 1);
+        assert_eq!(keyframes[0].offset, 0);
+        assert_eq!(keyframes[0].pts, Some(2.0));
+    }
+
+    #[test]
+    fn only_video_random_access_points_count() {
+        let mut data = pes_packet(0xC0, true, Some(90_000)); // Audio
+        data.extend(pes_packet(0xE0, false, Some(90_000)));
+        data.extend(pes_packet(0xE1, true, None));
+        let mut corrupt = pes_packet(0xE0, true, Some(90_000));
+        corrupt[0] = 0;
+        data.extend(corrupt);
+
+        let mut scanner = KeyframeScanner::default();
+        let (_, keyframes) = scanner.push(&data);
+        assert_eq!(keyframes.len(), 1);
+        assert_eq!(keyframes[0].offset, 2 * PACKET_SIZE);
+        assert_eq!(keyframes[0].pts, None);
+        // Every video PES start is a frame, keyframe or not
+        assert_eq!(scanner.frames, 2);
+    }
+
+    #[test]
+    fn alignment_is_recovered() {
+        let mut data = vec![0xAB; 5]; // Stray bytes before the first packet
+        data.extend(pes_packet(0xE0, false, Some(0)));
+        data.extend(pes_packet(0xE0, true, Some(90_000)));
+        data.extend([0x00, 0x47]); // Including a sync byte that doesn't start a packet
+        data.extend(pes_packet(0xE0, false, Some(90_000)));
+
+        let (packets, keyframes) = KeyframeScanner::default().push(&data);
+        assert_eq!(packets.len(), 3 * PACKET_SIZE);
+        assert!(packets.chunks(PACKET_SIZE).all(|packet| packet[0] == SYNC_BYTE));
+        assert_eq!(keyframes.len(), 1);
+        assert_eq!(keyframes[0].offset, PACKET_SIZE);
+    }
+
+    #[test]
+    fn large_timestamps_are_decoded() {
+        // 33-bit PTS, just below the wrap
+        let pts = (1u64 << 33) - 1;
+        let (_, keyframes) = KeyframeScanner::default().push(&pes_packet(0xE0, true, Some(pts)));
+        assert_eq!(keyframes[0].pts, Some(pts as f64 / PTS_HZ));
+
+        let marker: serde_json::Value = serde_json::from_str(&keyframes[0].marker()).unwrap();
+        assert_eq!(marker["type"], "keyframe");
+        assert_eq!(marker["byte_offset"], 0);
+    }
+}
@@ -0,0 +1,280 @@
+use crate::config::{OutputCodec, PipeConfig};
+use crate::relay::{RelayMessage, VideoRelay};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast::error::RecvError;
+
+#[cfg(unix)]
+type PipeWriter = tokio::net::unix::pipe::Sender;
+#[cfg(windows)]
+type PipeWriter = tokio::net::windows::named_pipe::NamedPipeServer;
+
+// How often to look for a reader while none has the FIFO open
+#[cfg(unix)]
+const READER_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(500);
+
+// Per-port pipe used when no path is given: a FIFO in a private directory
+// on Unix (see default_dir), \\.\pipe\eroxii-<port> on Windows
+fn default_path(port: u16, codec: OutputCodec) -> PathBuf {
+    #[cfg(windows)]
+    {
+        let _ = codec;
+        PathBuf::from(format!(r"\\.\pipe\eroxii-{}", port))
+    }
+    #[cfg(not(windows))]
+    {
+        let extension = match codec {
+            OutputCodec::Mpeg1 => "ts",
+            OutputCodec::H264 => "mp4",
+        };
+        default_dir().join(format!("eroxii-{}.{}", port, extension))
+    }
+}
+
+// $XDG_RUNTIME_DIR, which only its user can enter, else a per-user
+// directory in the temp dir. The shared temp dir itself would let another
+// user plant a FIFO or symlink at the predictable name first.
+#[cfg(unix)]
+fn default_dir() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => std::env::temp_dir().join(format!("eroxii-{}", nix::unistd::geteuid())),
+    }
+}
+
+// Create the default directory with owner-only access, or check that an
+// existing one has it
+#[cfg(unix)]
+fn create_private_dir(dir: &Path) -> Result<(), String> {
+    use std::os::unix::fs::DirBuilderExt;
+    match std::fs::DirBuilder::new().mode(0o700).create(dir) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            let metadata = std::fs::symlink_metadata(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+            if !metadata.is_dir() {
+                return Err(format!("{} exists and is not a directory", dir.display()));
+            }
+            check_private(dir, &metadata)
+        }
+        Err(e) => Err(format!("Failed to create {}: {}", dir.display(), e)),
+    }
+}
+
+// Refuse files another user owns or can open; they may have been planted
+// to read the stream or to feed it elsewhere
+#[cfg(unix)]
+fn check_private(path: &Path, metadata: &std::fs::Metadata) -> Result<(), String> {
+    use std::os::unix::fs::MetadataExt;
+    if metadata.uid() != nix::unistd::geteuid().as_raw() {
+        return Err(format!("{} belongs to another user", path.display()));
+    }
+    if metadata.mode() & 0o077 != 0 {
+        return Err(format!("{} is accessible to other users (mode {:o})", path.display(), metadata.mode() & 0o777));
+    }
+    Ok(())
+}
+
+// Resolve the pipe path and create the FIFO on Unix. An existing FIFO is
+// reused only if it is ours and owner-only; symlinks are never followed.
+// Windows pipes are created when the stream starts serving them.
+pub fn prepare(config: &PipeConfig, port: u16, codec: OutputCodec) -> Result<PipeConfig, String> {
+    let path = config.path.clone().unwrap_or_else(|| default_path(port, codec));
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        if config.path.is_none() {
+            create_private_dir(&default_dir())?;
+        }
+        match std::fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_fifo() => check_private(&path, &metadata)?,
+            Ok(_) => return Err(format!("{} exists and is not a FIFO", path.display())),
+            Err(_) => {
+                // Readable by the user only
+                let mode = nix::sys::stat::Mode::S_IRUSR | nix::sys::stat::Mode::S_IWUSR;
+                nix::unistd::mkfifo(&path, mode)
+                    .map_err(|e| format!("Failed to create FIFO {}: {}", path.display(), e))?;
+            }
+        }
+    }
+    Ok(PipeConfig { path: Some(path) })
+}
+
+// Delete a FIFO created under the default path; pipes given by the user are
+// left for them to reuse
+pub fn remove(config: &PipeConfig, port: u16, codec: OutputCodec) {
+    #[cfg(unix)]
+    if let Some(path) = config.path.as_ref().filter(|path| **path == default_path(port, codec)) {
+        let _ = std::fs::remove_file(path);
+    }
+    #[cfg(not(unix))]
+    let _ = (config, port, codec);
+}
+
+// Copy the stream's video to the pipe whenever a reader has it open. Writes
+// never block FFmpeg or the WebSocket clients: video that doesn't fit into
+// the pipe is dropped, and only a message already partly written is finished
+// first, so the reader never gets a torn message. H.264 readers get the init
+// segment before any fragment. Runs until the relay closes or it is aborted.
+pub async fn tee(path: PathBuf, relay: Arc<VideoRelay>, init_segment: Arc<Mutex<Option<Vec<u8>>>>) {
+    let mut first = true;
+    loop {
+        let writer = match connect(&path, first).await {
+            Ok(writer) => writer,
+            Err(e) => {
+                log::warn!("Pipe output {} stopped: {}", path.display(), e);
+                return;
+            }
+        };
+        first = false;
+        log::info!("Pipe reader connected to {}", path.display());
+
+        // Subscribed only while a reader is connected, so an idle pipe never
+        // shows up as a lagging client
+        // Under the init lock, like StreamState::subscribe, so the init
+        // segment isn't also received from the relay
+        let (mut receiver, mut pending) = {
+            let init = init_segment.lock().unwrap();
+            (relay.subscribe(), init.clone().unwrap_or_default())
+        };
+        let mut dropped = 0u64;
+        loop {
+            match receiver.recv().await {
+                Ok(RelayMessage::Video(data) | RelayMessage::Marked(_, data)) if pending.is_empty() => pending = data,
+                Ok(_) => dropped += 1,
+                Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return,
+            }
+            if let Err(e) = write_available(&writer, &mut pending) {
+                log::info!(
+                    "Pipe reader of {} disconnected ({}), {} messages dropped while it was full",
+                    path.display(),
+                    e,
+                    dropped
+                );
+                break;
+            }
+        }
+    }
+}
+
+// Write as much of `pending` as the pipe takes without blocking
+fn write_available(writer: &PipeWriter, pending: &mut Vec<u8>) -> io::Result<()> {
+    while !pending.is_empty() {
+        match writer.try_write(pending) {
+            Ok(written) => {
+                pending.drain(..written);
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+// Wait until a reader opens the FIFO. Opening the write end fails with ENXIO
+// while nobody reads, instead of blocking like a plain open would. The path
+// was checked by prepare; the opened file is checked again in case it was
+// replaced since, and a symlink put in its place fails to open.
+#[cfg(unix)]
+async fn connect(path: &Path, _first: bool) -> io::Result<PipeWriter> {
+    use nix::fcntl::OFlag;
+    use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+    loop {
+        let opened = std::fs::OpenOptions::new()
+            .write(true)
+            .custom_flags((OFlag::O_NONBLOCK | OFlag::O_NOFOLLOW).bits())
+            .open(path);
+        match opened {
+            Ok(file) => {
+                let metadata = file.metadata()?;
+                if !metadata.file_type().is_fifo() {
+                    return Err(io::Error::other(format!("{} is not a FIFO", path.display())));
+                }
+                check_private(path, &metadata).map_err(io::Error::other)?;
+                let writer = PipeWriter::from_file(file)?;
+                writer.writable().await?;
+                return Ok(writer);
+            }
+            Err(e) if e.raw_os_error() == Some(nix::errno::Errno::ENXIO as i32) => {
+                tokio::time::sleep(READER_POLL_INTERVAL).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Create a pipe instance and wait for a client. The first instance claims
+// the name, so another program can't own the pipe.
+#[cfg(windows)]
+async fn connect(path: &Path, first: bool) -> io::Result<PipeWriter> {
+    let server = tokio::net::windows::named_pipe::ServerOptions::new()
+        .first_pipe_instance(first)
+        .access_inbound(false)
+        .create(path)?;
+    server.connect().await?;
+    server.writable().await?;
+    Ok(server)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[tokio::test]
+    async fn a_full_pipe_keeps_the_rest_pending() {
+        let (writer, reader) = tokio::net::unix::pipe::pipe().unwrap();
+        let message: Vec<u8> = (0..1024 * 1024).map(|i| i as u8).collect();
+
+        // More than the pipe holds: the write stops instead of blocking
+        writer.writable().await.unwrap();
+        let mut pending = message.clone();
+        write_available(&writer, &mut pending).unwrap();
+        assert!(!pending.is_empty() && pending.len() < message.len());
+        assert_eq!(pending[..], message[message.len() - pending.len()..]);
+
+        // Once the reader catches up, the rest follows in order
+        let mut received = Vec::new();
+        while received.len() < message.len() {
+            reader.readable().await.unwrap();
+            let mut buffer = vec![0; 64 * 1024];
+            match reader.try_read(&mut buffer) {
+                Ok(read) => received.extend_from_slice(&buffer[..read]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => panic!("{}", e),
+            }
+            if !pending.is_empty() {
+                writer.writable().await.unwrap();
+                write_available(&writer, &mut pending).unwrap();
+            }
+        }
+        assert_eq!(received, message);
+
+        drop(reader);
+        assert!(write_available(&writer, &mut vec![0; 16]).is_err());
+    }
+
+    #[test]
+    fn only_private_fifos_are_used() {
+        let dir = std::env::temp_dir().join(format!("eroxii-pipe-test-{}", std::process::id()));
+        create_private_dir(&dir).unwrap();
+        let config = |name: &str| PipeConfig { path: Some(dir.join(name)) };
+
+        // Created owner-only, and reused as such
+        let created = prepare(&config("live.ts"), 9999, OutputCodec::Mpeg1).unwrap();
+        let mode = std::fs::metadata(created.path.unwrap()).unwrap().permissions().mode();
+        assert_eq!(mode & 0o077, 0);
+        assert!(prepare(&config("live.ts"), 9999, OutputCodec::Mpeg1).is_ok());
+
+        std::fs::set_permissions(dir.join("live.ts"), std::fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(prepare(&config("live.ts"), 9999, OutputCodec::Mpeg1).unwrap_err().contains("other users"));
+
+        std::os::unix::fs::symlink(dir.join("live.ts"), dir.join("link.ts")).unwrap();
+        assert!(prepare(&config("link.ts"), 9999, OutputCodec::Mpeg1).unwrap_err().contains("not a FIFO"));
+
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(create_private_dir(&dir).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,165 @@
+use crate::config::{PreemptionPolicy, StreamLimits};
+
+// Resources used by one running FFmpeg (all ports sharing it)
+#[derive(Clone, Debug)]
+pub struct Load {
+    pub priority: u8,
+    pub bitrate_kbps: u32,
+    // Transcoded WebSocket streams can be restarted at a lower bitrate
+    pub degradable: bool,
+}
+
+// What to do to the running loads, by index, to admit a new stream
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Plan {
+    pub degrade: Vec<(usize, u32)>,
+    pub stop: Vec<usize>,
+}
+
+// Decide how a new stream of `priority` and `bitrate_kbps` fits within the
+// limits. Only loads of lower priority are touched, lowest first; equal
+// priorities keep first-come-first-served. Nothing is planned unless the new
+// stream fits afterwards.
+pub fn plan(limits: &StreamLimits, running: &[Load], priority: u8, bitrate_kbps: u32) -> Result<Plan, String> {
+    let mut count = running.len() + 1;
+    let mut total: u64 = running.iter().map(|load| load.bitrate_kbps as u64).sum::<u64>() + bitrate_kbps as u64;
+    let count_fits = |count: usize| limits.max_streams.is_none_or(|max| count <= max);
+    let bitrate_fits = |total: u64| limits.max_total_bitrate_kbps.is_none_or(|max| total <= max as u64);
+
+    let mut plan = Plan::default();
+    if count_fits(count) && bitrate_fits(total) {
+        return Ok(plan);
+    }
+    let limit_error = || {
+        format!(
+            "Stream limits reached ({} streams, {} kbps)",
+            limits.max_streams.map_or("unlimited".to_string(), |max| max.to_string()),
+            limits.max_total_bitrate_kbps.map_or("unlimited".to_string(), |max| max.to_string()),
+        )
+    };
+    if limits.preemption == PreemptionPolicy::None {
+        return Err(limit_error());
+    }
+
+    let mut victims: Vec<usize> = (0..running.len()).filter(|&i| running[i].priority < priority).collect();
+    victims.sort_by_key(|&i| running[i].priority);
+
+    // Degrading lowers the bitrate only, so it helps when the count fits
+    if limits.preemption == PreemptionPolicy::DegradeThenStop && count_fits(count) {
+        for &i in &victims {
+            if bitrate_fits(total) {
+                break;
+            }
+            let current = running[i].bitrate_kbps;
+            let degraded = (current / 2).max(limits.min_bitrate_kbps);
+            if running[i].degradable && degraded < current {
+                total -= (current - degraded) as u64;
+                plan.degrade.push((i, degraded));
+            }
+        }
+    }
+
+    for &i in &victims {
+        if count_fits(count) && bitrate_fits(total) {
+            break;
+        }
+        let bitrate = match plan.degrade.iter().position(|&(degraded, _)| degraded == i) {
+            Some(position) => plan.degrade.remove(position).1,
+            None => running[i].bitrate_kbps,
+        };
+        count -= 1;
+        total -= bitrate as u64;
+        plan.stop.push(i);
+    }
+
+    if count_fits(count) && bitrate_fits(total) {
+        Ok(plan)
+    } else {
+        Err(format!("{}: not enough lower-priority streams to preempt", limit_error()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(priority: u8, bitrate_kbps: u32, degradable: bool) -> Load {
+        Load {
+            priority,
+            bitrate_kbps,
+            degradable,
+        }
+    }
+
+    fn stream_limits(max_streams: Option<usize>, max_total_bitrate_kbps: Option<u32>, preemption: PreemptionPolicy) -> StreamLimits {
+        StreamLimits {
+            max_streams,
+            max_total_bitrate_kbps,
+            preemption,
+            min_bitrate_kbps: 250,
+        }
+    }
+
+    #[test]
+    fn count_limit_stops_the_lowest_priority() {
+        let limits = stream_limits(Some(2), None, PreemptionPolicy::Stop);
+        let running = [load(5, 1000, true), load(1, 1000, true)];
+
+        assert_eq!(plan(&limits, &running, 3, 1000).unwrap(), Plan { degrade: vec![], stop: vec![1] });
+        // Equal priorities keep first-come-first-served
+        assert!(plan(&limits, &running, 1, 1000).is_err());
+        // Degrading frees no slot, so a count limit stops right away
+        let limits = StreamLimits {
+            preemption: PreemptionPolicy::DegradeThenStop,
+            ..limits
+        };
+        assert_eq!(plan(&limits, &running, 3, 1000).unwrap(), Plan { degrade: vec![], stop: vec![1] });
+    }
+
+    #[test]
+    fn bitrate_limit_stops_only_what_is_needed() {
+        let limits = stream_limits(None, Some(3000), PreemptionPolicy::Stop);
+        let running = [load(2, 1500, true), load(1, 1000, true), load(3, 500, true)];
+
+        assert_eq!(plan(&limits, &running, 5, 1000).unwrap(), Plan { degrade: vec![], stop: vec![1] });
+        assert_eq!(plan(&limits, &running, 5, 2500).unwrap(), Plan { degrade: vec![], stop: vec![1, 0] });
+        assert_eq!(plan(&limits, &running, 5, 0).unwrap(), Plan::default());
+    }
+
+    #[test]
+    fn degrade_then_stop_degrades_before_stopping() {
+        let limits = stream_limits(None, Some(2500), PreemptionPolicy::DegradeThenStop);
+
+        // Halving the degradable stream is enough
+        let running = [load(1, 400, false), load(2, 2000, true)];
+        assert_eq!(plan(&limits, &running, 5, 600).unwrap(), Plan { degrade: vec![(1, 1000)], stop: vec![] });
+
+        // Never below min_bitrate_kbps
+        let running = [load(1, 300, true), load(2, 2000, false)];
+        assert_eq!(plan(&limits, &running, 5, 240).unwrap(), Plan { degrade: vec![(0, 250)], stop: vec![] });
+
+        // A degraded stream that has to be stopped anyway frees its whole
+        // bitrate once and is no longer listed as degraded
+        let running = [load(1, 1000, true), load(2, 2000, false)];
+        assert_eq!(plan(&limits, &running, 5, 500).unwrap(), Plan { degrade: vec![], stop: vec![0] });
+        assert_eq!(plan(&limits, &running, 5, 1600).unwrap(), Plan { degrade: vec![], stop: vec![0, 1] });
+    }
+
+    #[test]
+    fn refuses_when_preemption_cannot_make_room() {
+        let running = [load(1, 1000, true), load(4, 1000, true)];
+
+        let limits = stream_limits(Some(2), None, PreemptionPolicy::None);
+        assert!(plan(&limits, &running, 9, 1000).unwrap_err().starts_with("Stream limits reached"));
+
+        // Stopping the only lower-priority stream is not enough, so nothing
+        // is stopped
+        for limits in [
+            stream_limits(Some(1), None, PreemptionPolicy::Stop),
+            stream_limits(None, Some(1500), PreemptionPolicy::DegradeThenStop),
+        ] {
+            let error = plan(&limits, &running, 3, 1000).unwrap_err();
+            assert!(error.ends_with("not enough lower-priority streams to preempt"), "{}", error);
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use crate::config::StreamOptions;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+// Video stream details of a source, from ffprobe
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MediaInfo {
+    pub codec: String,
+    pub profile: Option<String>,
+    pub width: u32,
+    pub height: u32,
+    pub framerate: Option<f64>,
+}
+
+#[derive(Deserialize)]
+struct ProbeOutput {
+    #[serde(default)]
+    streams: Vec<ProbeStream>,
+}
+
+#[derive(Deserialize)]
+struct ProbeStream {
+    codec_name: Option<String>,
+    profile: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+    avg_frame_rate: Option<String>,
+}
+
+// Probe the first video stream of a source with the ffprobe next to `ffmpeg`,
+// opening it with the stream's RTSP transport and input timeout
+pub async fn probe_source(
+    ffmpeg: &str,
+    url: &str,
+    options: &StreamOptions,
+    timeout: Duration,
+) -> Result<MediaInfo, String> {
+    let ffprobe = crate::ffmpeg::ffprobe_path(ffmpeg);
+    let mut cmd = tokio::process::Command::from(crate::ffmpeg::command(&ffprobe));
+    cmd.args(crate::ffmpeg::source_input_args(url, options));
+    cmd.args([
+        "-v", "error",
+        "-select_streams", "v:0",
+        "-show_entries", "stream=codec_name,profile,width,height,avg_frame_rate",
+        "-of", "json",
+        url,
+    ])
+    .kill_on_drop(true);
+
+    let output = tokio::time::timeout(timeout, cmd.output())
+        .await
+        .map_err(|_| format!("ffprobe timed out after {}s", timeout.as_secs()))?
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => format!("ffprobe not found (looked for {})", ffprobe),
+            _ => format!("Failed to run ffprobe: {}", e),
+        })?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("ffprobe failed: {}", crate::rtsp_url::redact(stderr.trim())));
+    }
+
+    parse_probe_output(&String::from_utf8_lossy(&output.stdout))
+}
+
+fn parse_probe_output(json: &str) -> Result<MediaInfo, String> {
+    let probe: ProbeOutput =
+        serde_json::from_str(json).map_err(|e| format!("Invalid ffprobe output: {}", e))?;
+    let stream = probe
+        .streams
+        .into_iter()
+        .next()
+        .ok_or_else(|| "Source has no video stream".to_string())?;
+
+    Ok(MediaInfo {
+        codec: stream.codec_name.unwrap_or_default(),
+        profile: stream.profile,
+        width: stream.width.unwrap_or(0),
+        height: stream.height.unwrap_or(0),
+        framerate: stream.avg_frame_rate.as_deref().and_then(parse_frame_rate),
+    })
+}
+
+// FFmpeg reconfigures its filters and logs it when the decoded frames change
+// size or format mid-stream, e.g. when a camera switches to night mode or
+// another resolution
+pub fn is_source_change(line: &str) -> bool {
+    line.contains("frame changed from size:")
+        || line.contains("Reconfiguring filter graph because video parameters changed")
+}
+
+// Parse ffprobe's "num/den" frame rate
+fn parse_frame_rate(rate: &str) -> Option<f64> {
+    let (num, den) = rate.split_once('/')?;
+    let num: f64 = num.parse().ok()?;
+    let den: f64 = den.parse().ok()?;
+    (den > 0.0 && num > 0.0).then_some(num / den)
+}
@@ -0,0 +1,518 @@
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::process::{Child, ChildStdin, ChildStdout, Command, ExitStatus};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+// How an FFmpeg run ended, to tell a broken FFmpeg from a source problem
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExitKind {
+    // Exited normally: the source ended
+    Eof,
+    // Exited with an error code: the source failed, timed out or dropped
+    SourceError,
+    // Killed by a signal or an exception (segfault, abort, out of memory)
+    Crash,
+    // Killed by us, e.g. a restart whose graceful stop timed out
+    Requested,
+}
+
+impl ExitKind {
+    // Classify an exit we didn't ask for; see FfmpegProcess::exit_kind
+    pub fn of(status: ExitStatus) -> ExitKind {
+        if status.success() {
+            return ExitKind::Eof;
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            if status.signal().is_some() {
+                return ExitKind::Crash;
+            }
+        }
+        // Unhandled exceptions exit with an NTSTATUS error code, e.g.
+        // 0xC0000005 for an access violation
+        #[cfg(windows)]
+        if status.code().is_some_and(|code| code as u32 >= 0xC000_0000) {
+            return ExitKind::Crash;
+        }
+        ExitKind::SourceError
+    }
+}
+
+// An FFmpeg child together with the threads writing its stdin and draining
+// its stderr. Finishing (or dropping) it kills the process, reaps it and
+// joins both threads, so restarting FFmpeg never leaves threads behind.
+pub struct FfmpegProcess {
+    child: Arc<Mutex<Child>>,
+    killed: Arc<AtomicBool>,
+    stdin: StdinQueue,
+    stdin_thread: Option<JoinHandle<()>>,
+    stderr_thread: Option<JoinHandle<()>>,
+}
+
+// Keys waiting for FFmpeg's stdin. A full queue means FFmpeg isn't reading
+// its stdin, and further keys are dropped rather than block the caller.
+const STDIN_QUEUE: usize = 8;
+
+enum StdinKey {
+    Command(String),
+    Quit,
+}
+
+// Sender of the stdin writer thread; None once stdin is closed
+type StdinQueue = Arc<Mutex<Option<SyncSender<StdinKey>>>>;
+
+// Kills a running FFmpeg from outside the thread blocked on its stdout
+#[derive(Clone)]
+pub struct KillHandle {
+    child: Arc<Mutex<Child>>,
+    // Set once we killed the process, so its exit isn't taken for a crash
+    killed: Arc<AtomicBool>,
+    stdin: StdinQueue,
+}
+
+impl KillHandle {
+    pub fn kill(&self) {
+        self.killed.store(true, Ordering::SeqCst);
+        let _ = self.child.lock().unwrap().kill();
+    }
+
+    // Ask FFmpeg to quit like pressing 'q': it stops reading input, finishes
+    // its outputs (trailers, the current segment) and exits. stdin is closed
+    // after the key.
+    pub fn quit(&self) {
+        if let Some(stdin) = self.stdin.lock().unwrap().take() {
+            if stdin.try_send(StdinKey::Quit).is_err() {
+                log::warn!("FFmpeg is not reading its stdin, it can't be asked to quit");
+            }
+        }
+    }
+
+    // Send a filter command through FFmpeg's interactive 'c' key: the line is
+    // "<filter> <time|-1> <command> <argument>". Never blocks; the command is
+    // dropped if FFmpeg is behind on reading them.
+    pub fn send_command(&self, command: &str) {
+        if let Some(stdin) = self.stdin.lock().unwrap().as_ref() {
+            if let Err(TrySendError::Full(_)) = stdin.try_send(StdinKey::Command(command.to_string())) {
+                log::debug!("FFmpeg is not reading its stdin, dropping filter command");
+            }
+        }
+    }
+
+    pub fn has_exited(&self) -> bool {
+        !matches!(self.child.lock().unwrap().try_wait(), Ok(None))
+    }
+}
+
+impl FfmpegProcess {
+    // Spawn the command with piped stdin/stdout/stderr. stdin is only used for
+    // FFmpeg's interactive keys ('q' and filter commands). Each stderr line is
+    // passed to `on_line` on a dedicated thread that ends when FFmpeg closes
+    // stderr.
+    // Lines end at '\n' or '\r', so each progress update (which FFmpeg
+    // rewrites in place with '\r') arrives as soon as it is printed.
+    pub fn spawn<F>(mut cmd: Command, mut on_line: F) -> io::Result<(Self, ChildStdout)>
+    where
+        F: FnMut(String) + Send + 'static,
+    {
+        use std::process::Stdio;
+        let mut child = cmd
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        let stdout = child
+            .stdout
+            .take()
+            .ok_or_else(|| io::Error::other("Failed to get FFmpeg stdout"))?;
+        let (stdin, stdin_thread) = match child.stdin.take() {
+            Some(stdin) => {
+                let (key_tx, key_rx) = std::sync::mpsc::sync_channel(STDIN_QUEUE);
+                (Some(key_tx), Some(std::thread::spawn(move || write_stdin(stdin, key_rx))))
+            }
+            None => (None, None),
+        };
+
+        let stderr_thread = child.stderr.take().map(|stderr| {
+            std::thread::spawn(move || {
+                use std::io::BufRead;
+                let mut stderr_reader = std::io::BufReader::new(stderr);
+                let mut line = Vec::new();
+                loop {
+                    let buffer = match stderr_reader.fill_buf() {
+                        Ok(buffer) if !buffer.is_empty() => buffer,
+                        _ => break,
+                    };
+                    let (taken, complete) = match buffer.iter().position(|&b| b == b'\n' || b == b'\r') {
+                        Some(end) => {
+                            line.extend_from_slice(&buffer[..end]);
+                            (end + 1, true)
+                        }
+                        None => {
+                            line.extend_from_slice(buffer);
+                            (buffer.len(), false)
+                        }
+                    };
+                    stderr_reader.consume(taken);
+                    if complete && !line.is_empty() {
+                        on_line(String::from_utf8_lossy(&line).into_owned());
+                        line.clear();
+                    }
+                }
+                if !line.is_empty() {
+                    on_line(String::from_utf8_lossy(&line).into_owned());
+                }
+            })
+        });
+
+        let process = Self {
+            child: Arc::new(Mutex::new(child)),
+            killed: Arc::default(),
+            stdin: Arc::new(Mutex::new(stdin)),
+            stdin_thread,
+            stderr_thread,
+        };
+        Ok((process, stdout))
+    }
+
+    pub fn id(&self) -> u32 {
+        self.child.lock().unwrap().id()
+    }
+
+    pub fn kill_handle(&self) -> KillHandle {
+        KillHandle {
+            child: Arc::clone(&self.child),
+            killed: Arc::clone(&self.killed),
+            stdin: Arc::clone(&self.stdin),
+        }
+    }
+
+    // How the process ended: Requested if a KillHandle killed it, otherwise
+    // judged from the exit status
+    pub fn exit_kind(&self, status: ExitStatus) -> ExitKind {
+        if self.killed.load(Ordering::SeqCst) {
+            return ExitKind::Requested;
+        }
+        ExitKind::of(status)
+    }
+
+    // Wait up to `timeout` for FFmpeg to exit on its own, without killing it.
+    // None if it is still running.
+    pub fn wait_exit(&self, timeout: Duration) -> Option<ExitStatus> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Ok(Some(status)) = self.child.lock().unwrap().try_wait() {
+                return Some(status);
+            }
+            if Instant::now() >= deadline {
+                return None;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    // Kill FFmpeg if still running, reap it and join the stderr thread
+    pub fn finish(mut self) -> Option<ExitStatus> {
+        self.shutdown()
+    }
+
+    fn shutdown(&mut self) -> Option<ExitStatus> {
+        let status = {
+            let mut child = self.child.lock().unwrap();
+            let _ = child.kill();
+            child.wait().ok()
+        };
+        // The writer ends with its queue closed, or on a write to the
+        // exited FFmpeg; stderr reaches EOF once FFmpeg has exited
+        self.stdin.lock().unwrap().take();
+        for thread in [self.stdin_thread.take(), self.stderr_thread.take()].into_iter().flatten() {
+            let _ = thread.join();
+        }
+        status
+    }
+}
+
+// Write queued keys to FFmpeg's stdin until the queue is closed or FFmpeg
+// is asked to quit, then close it
+fn write_stdin(mut stdin: ChildStdin, keys: Receiver<StdinKey>) {
+    use std::io::Write;
+    for key in keys {
+        let (bytes, last) = match key {
+            StdinKey::Command(command) => (format!("c{}\n", command).into_bytes(), false),
+            StdinKey::Quit => (b"q".to_vec(), true),
+        };
+        if stdin.write_all(&bytes).is_err() || last {
+            break;
+        }
+    }
+}
+
+impl Drop for FfmpegProcess {
+    // Killing or waiting again after finish() is a no-op for a reaped child
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+// Slot holding the current FFmpeg of a stream, so the server can stop it while
+// the runner is blocked reading. Once stopped, any process attached later is
+// refused and must be killed by the runner.
+#[derive(Clone, Default)]
+pub struct ProcessSlot(Arc<Mutex<SlotState>>);
+
+#[derive(Default)]
+struct SlotState {
+    current: Option<KillHandle>,
+    stopped: bool,
+}
+
+// Ask FFmpeg to quit and wait up to `timeout` for it to exit
+async fn quit_within(handle: &KillHandle, timeout: Duration) -> bool {
+    handle.quit();
+    let deadline = tokio::time::Instant::now() + timeout;
+    while !handle.has_exited() {
+        if tokio::time::Instant::now() >= deadline {
+            return false;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    true
+}
+
+impl ProcessSlot {
+    // Attach a newly spawned process; returns false if the slot was stopped
+    pub fn attach(&self, handle: KillHandle) -> bool {
+        let mut state = self.0.lock().unwrap();
+        if state.stopped {
+            return false;
+        }
+        state.current = Some(handle);
+        true
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.0.lock().unwrap().stopped
+    }
+
+    // Stop the stream like stop(), but let FFmpeg quit on its own first (see
+    // KillHandle::quit), so recordings are finalized. It is killed if it
+    // hasn't exited within `timeout`. Returns whether it exited in time.
+    pub async fn stop_gracefully(&self, timeout: Duration) -> bool {
+        let current = {
+            let mut state = self.0.lock().unwrap();
+            state.stopped = true;
+            state.current.clone()
+        };
+        let exited = match current {
+            Some(handle) => quit_within(&handle, timeout).await,
+            None => true,
+        };
+        self.stop();
+        exited
+    }
+
+    // Stop the current FFmpeg without stopping the stream, so the supervisor
+    // restarts it. It may quit on its own first and is killed after
+    // `timeout`. Only the process running now is waited for, not the one
+    // started after it.
+    pub async fn restart_gracefully(&self, timeout: Duration) {
+        let current = self.0.lock().unwrap().current.clone();
+        if let Some(handle) = current {
+            if !quit_within(&handle, timeout).await {
+                handle.kill();
+            }
+        }
+    }
+
+    // Send a filter command to the current FFmpeg, if any
+    pub fn send_command(&self, command: &str) {
+        if let Some(handle) = &self.0.lock().unwrap().current {
+            handle.send_command(command);
+        }
+    }
+
+    // Stop the stream's FFmpeg and refuse any further attach
+    pub fn stop(&self) {
+        let mut state = self.0.lock().unwrap();
+        state.stopped = true;
+        if let Some(handle) = state.current.take() {
+            handle.kill();
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::sync::mpsc;
+
+    fn thread_count() -> usize {
+        let status = std::fs::read_to_string("/proc/self/status").unwrap();
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix("Threads:"))
+            .and_then(|count| count.trim().parse().ok())
+            .unwrap()
+    }
+
+    // Stand-in for an FFmpeg that logs and keeps running until killed
+    fn long_running_command() -> Command {
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "echo started >&2; exec sleep 30"]);
+        cmd
+    }
+
+    #[test]
+    fn repeated_restarts_do_not_leak_reader_threads() {
+        let baseline = thread_count();
+
+        for _ in 0..25 {
+            let (line_tx, line_rx) = mpsc::channel();
+            let (process, mut stdout) = FfmpegProcess::spawn(long_running_command(), move |line| {
+                let _ = line_tx.send(line);
+            })
+            .unwrap();
+            assert_eq!(line_rx.recv_timeout(Duration::from_secs(5)).unwrap(), "started");
+
+            // The server stops the stream while the runner is blocked on stdout
+            let slot = ProcessSlot::default();
+            assert!(slot.attach(process.kill_handle()));
+            slot.stop();
+            let mut buffer = [0u8; 16];
+            assert_eq!(stdout.read(&mut buffer).unwrap(), 0);
+
+            process.finish();
+        }
+
+        // Allow some slack for threads started by other tests in parallel
+        assert!(thread_count() <= baseline + 8, "reader threads leaked");
+    }
+
+    #[test]
+    fn progress_updates_are_split_into_lines() {
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "printf 'frame=1\\rframe=2\\r\\nerror line\\r\\nlast' >&2"]);
+        let (line_tx, line_rx) = mpsc::channel();
+        let (process, mut stdout) = FfmpegProcess::spawn(cmd, move |line| {
+            let _ = line_tx.send(line);
+        })
+        .unwrap();
+        // Let the command exit on its own before reaping it
+        stdout.read_to_end(&mut Vec::new()).unwrap();
+        process.finish();
+
+        let lines: Vec<String> = line_rx.try_iter().collect();
+        assert_eq!(lines, ["frame=1", "frame=2", "error line", "last"]);
+    }
+
+    #[tokio::test]
+    async fn graceful_stop_lets_the_process_finish() {
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "read key; echo finished-$key >&2"]);
+        let (line_tx, line_rx) = mpsc::channel();
+        let (process, _stdout) = FfmpegProcess::spawn(cmd, move |line| {
+            let _ = line_tx.send(line);
+        })
+        .unwrap();
+
+        let slot = ProcessSlot::default();
+        assert!(slot.attach(process.kill_handle()));
+        assert!(slot.stop_gracefully(Duration::from_secs(5)).await);
+        process.finish();
+
+        assert_eq!(line_rx.try_iter().collect::<Vec<_>>(), ["finished-q"]);
+    }
+
+    #[tokio::test]
+    async fn graceful_stop_kills_after_timeout() {
+        let (process, _stdout) = FfmpegProcess::spawn(long_running_command(), |_| {}).unwrap();
+
+        let slot = ProcessSlot::default();
+        assert!(slot.attach(process.kill_handle()));
+        assert!(!slot.stop_gracefully(Duration::from_millis(200)).await);
+        process.finish();
+    }
+
+    #[test]
+    fn commands_are_written_to_stdin() {
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "read -r line; echo \"$line\" >&2"]);
+        let (line_tx, line_rx) = mpsc::channel();
+        let (process, mut stdout) = FfmpegProcess::spawn(cmd, move |line| {
+            let _ = line_tx.send(line);
+        })
+        .unwrap();
+
+        let slot = ProcessSlot::default();
+        assert!(slot.attach(process.kill_handle()));
+        slot.send_command("drawtext -1 reinit text=12\\:00");
+        stdout.read_to_end(&mut Vec::new()).unwrap();
+        process.finish();
+
+        assert_eq!(line_rx.try_iter().collect::<Vec<_>>(), ["cdrawtext -1 reinit text=12\\:00"]);
+    }
+
+    #[test]
+    fn commands_never_block_on_a_full_stdin() {
+        let (process, _stdout) = FfmpegProcess::spawn(long_running_command(), |_| {}).unwrap();
+        let slot = ProcessSlot::default();
+        assert!(slot.attach(process.kill_handle()));
+
+        // Far more than the pipe buffer holds; the process never reads them
+        let command = format!("drawtext -1 reinit text={}", "x".repeat(1000));
+        let start = Instant::now();
+        for _ in 0..1000 {
+            slot.send_command(&command);
+        }
+        assert!(start.elapsed() < Duration::from_secs(2));
+        process.finish();
+    }
+
+    #[test]
+    fn exits_are_told_apart() {
+        let exit = |script: &str| {
+            let mut cmd = Command::new("sh");
+            cmd.args(["-c", script]);
+            let (process, mut stdout) = FfmpegProcess::spawn(cmd, |_| {}).unwrap();
+            stdout.read_to_end(&mut Vec::new()).unwrap();
+            let status = process.wait_exit(Duration::from_secs(5)).unwrap();
+            process.finish();
+            ExitKind::of(status)
+        };
+        assert_eq!(exit("exit 0"), ExitKind::Eof);
+        assert_eq!(exit("exit 1"), ExitKind::SourceError);
+        assert_eq!(exit("kill -SEGV $$"), ExitKind::Crash);
+
+        // Our own kill after a timed-out graceful stop is not a crash
+        let (process, _stdout) = FfmpegProcess::spawn(long_running_command(), |_| {}).unwrap();
+        process.kill_handle().kill();
+        let status = process.wait_exit(Duration::from_secs(5)).unwrap();
+        assert_eq!(ExitKind::of(status), ExitKind::Crash);
+        assert_eq!(process.exit_kind(status), ExitKind::Requested);
+        process.finish();
+
+        // Still running when its output ends
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "exec sleep 30 >&-"]);
+        let (process, _stdout) = FfmpegProcess::spawn(cmd, |_| {}).unwrap();
+        assert!(process.wait_exit(Duration::from_millis(100)).is_none());
+        process.finish();
+    }
+
+    #[test]
+    fn stopped_slot_refuses_new_process() {
+        let slot = ProcessSlot::default();
+        slot.stop();
+
+        let (process, _stdout) = FfmpegProcess::spawn(long_running_command(), |_| {}).unwrap();
+        assert!(!slot.attach(process.kill_handle()));
+        process.finish();
+    }
+}
@@ -0,0 +1,139 @@
+use crate::timestamps::TimestampStats;
+use serde::{Deserialize, Serialize};
+
+// Picture health of a stream as seen by the blackdetect/freezedetect monitor.
+// Times are stream timestamps in seconds, as reported by FFmpeg.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct StreamQuality {
+    pub monitoring: bool,
+    pub black: bool,
+    pub black_since: Option<f64>,
+    pub last_black_duration: Option<f64>,
+    pub black_count: u32,
+    pub frozen: bool,
+    pub frozen_since: Option<f64>,
+    pub last_frozen_duration: Option<f64>,
+    pub frozen_count: u32,
+    // Set for streams with debug_timestamps
+    pub timestamps: Option<TimestampStats>,
+}
+
+// Payload of the stream://black and stream://frozen events
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DetectionEvent {
+    pub ws_port: u16,
+    pub active: bool,
+    pub start: f64,
+    // Set when the black/frozen period ends
+    pub duration: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Detection {
+    BlackStart(f64),
+    BlackEnd(f64),
+    FreezeStart(f64),
+    FreezeEnd(f64),
+}
+
+// Parse a detection from an FFmpeg stderr line. blackdetect start/end come
+// from the metadata filter printing lavfi.black_start/lavfi.black_end, while
+// freezedetect logs its own lavfi.freezedetect.* lines.
+pub fn parse_detection(line: &str) -> Option<Detection> {
+    let value = |key: &str| -> Option<f64> {
+        let rest = &line[line.find(key)? + key.len()..];
+        rest.trim_start_matches([':', '=', ' ']).split_whitespace().next()?.parse().ok()
+    };
+
+    if let Some(t) = value("lavfi.black_start") {
+        Some(Detection::BlackStart(t))
+    } else if let Some(t) = value("lavfi.black_end") {
+        Some(Detection::BlackEnd(t))
+    } else if let Some(t) = value("lavfi.freezedetect.freeze_start") {
+        Some(Detection::FreezeStart(t))
+    } else {
+        value("lavfi.freezedetect.freeze_end").map(Detection::FreezeEnd)
+    }
+}
+
+impl StreamQuality {
+    // Update state from a detection and return the event to emit
+    pub fn apply(&mut self, ws_port: u16, detection: Detection) -> (&'static str, DetectionEvent) {
+        match detection {
+            Detection::BlackStart(t) => {
+                self.black = true;
+                self.black_since = Some(t);
+                self.black_count += 1;
+                ("stream://black", DetectionEvent { ws_port, active: true, start: t, duration: None })
+            }
+            Detection::BlackEnd(t) => {
+                let start = self.black_since.take().unwrap_or(t);
+                self.black = false;
+                self.last_black_duration = Some(t - start);
+                ("stream://black", DetectionEvent { ws_port, active: false, start, duration: Some(t - start) })
+            }
+            Detection::FreezeStart(t) => {
+                self.frozen = true;
+                self.frozen_since = Some(t);
+                self.frozen_count += 1;
+                ("stream://frozen", DetectionEvent { ws_port, active: true, start: t, duration: None })
+            }
+            Detection::FreezeEnd(t) => {
+                let start = self.frozen_since.take().unwrap_or(t);
+                self.frozen = false;
+                self.last_frozen_duration = Some(t - start);
+                ("stream://frozen", DetectionEvent { ws_port, active: false, start, duration: Some(t - start) })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detections_are_parsed() {
+        assert_eq!(
+            parse_detection("[Parsed_metadata_3 @ 0x55d0c8] lavfi.black_start=6.04"),
+            Some(Detection::BlackStart(6.04))
+        );
+        assert_eq!(
+            parse_detection("[Parsed_metadata_3 @ 0x55d0c8] lavfi.black_end=9.5"),
+            Some(Detection::BlackEnd(9.5))
+        );
+        assert_eq!(
+            parse_detection("[freezedetect @ 0x55d0c9] lavfi.freezedetect.freeze_start: 5.005"),
+            Some(Detection::FreezeStart(5.005))
+        );
+        assert_eq!(
+            parse_detection("[freezedetect @ 0x55d0c9] lavfi.freezedetect.freeze_end: 7.5"),
+            Some(Detection::FreezeEnd(7.5))
+        );
+        assert_eq!(parse_detection("[freezedetect @ 0x55d0c9] lavfi.freezedetect.freeze_duration: 2.5"), None);
+        assert_eq!(parse_detection("[Parsed_metadata_3 @ 0x55d0c8] frame:12 pts:12 pts_time:6"), None);
+        assert_eq!(parse_detection("[Parsed_metadata_3 @ 0x55d0c8] lavfi.black_start=nan?"), None);
+    }
+
+    #[test]
+    fn periods_are_tracked() {
+        let mut quality = StreamQuality::default();
+
+        let (event, payload) = quality.apply(9999, Detection::BlackStart(6.0));
+        assert_eq!((event, payload.active, payload.start, payload.duration), ("stream://black", true, 6.0, None));
+        assert!(quality.black && quality.black_count == 1);
+
+        let (_, payload) = quality.apply(9999, Detection::BlackEnd(9.5));
+        assert_eq!((payload.active, payload.start, payload.duration), (false, 6.0, Some(3.5)));
+        assert!(!quality.black && quality.black_since.is_none());
+        assert_eq!(quality.last_black_duration, Some(3.5));
+
+        // An end without a start, e.g. after a restart, is a zero-length period
+        let (event, payload) = quality.apply(9999, Detection::FreezeEnd(2.0));
+        assert_eq!((event, payload.start, payload.duration), ("stream://frozen", 2.0, Some(0.0)));
+        assert_eq!(quality.frozen_count, 0);
+
+        quality.apply(9999, Detection::FreezeStart(3.0));
+        assert!(quality.frozen && quality.frozen_count == 1 && quality.frozen_since == Some(3.0));
+    }
+}
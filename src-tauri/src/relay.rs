@@ -0,0 +1,312 @@
+use crate::config::BufferConfig;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Notify;
+
+pub const DEFAULT_CAPACITY: usize = 100;
+
+// How often the adaptive mode re-evaluates the capacity
+const ADAPT_INTERVAL: Duration = Duration::from_secs(5);
+// Lag-free intervals required before shrinking (30s)
+const CALM_INTERVALS_TO_SHRINK: u32 = 6;
+
+// A message for WebSocket clients: video data, possibly with JSON text
+// markers describing it. Markers travel with their video so lag or a resize
+// can't separate them.
+#[derive(Clone, Debug)]
+pub enum RelayMessage {
+    Video(Vec<u8>),
+    Marked(Vec<String>, Vec<u8>),
+}
+
+// Broadcast buffer for a stream's video messages. Behaves like
+// tokio::sync::broadcast (a receiver that falls more than `capacity` messages
+// behind gets RecvError::Lagged), but the capacity can change while clients
+// are connected, which a broadcast channel cannot do.
+pub struct VideoRelay {
+    state: Mutex<RelayState>,
+    notify: Notify,
+}
+
+struct RelayState {
+    messages: VecDeque<RelayMessage>,
+    // Sequence number of messages[0]
+    first_seq: u64,
+    capacity: usize,
+    receivers: usize,
+    closed: bool,
+    stats: RelayStats,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct RelayStats {
+    // Times a receiver fell behind and lost messages
+    pub lag_events: u64,
+    pub lagged_messages: u64,
+    // Capacity changes made by the adaptive mode
+    pub resizes: u64,
+    // Deepest receiver backlog (messages) since the last adaptive check
+    pub peak_backlog: usize,
+    // Video bytes relayed since the stream started
+    pub video_bytes: u64,
+}
+
+impl Default for VideoRelay {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+impl VideoRelay {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            state: Mutex::new(RelayState {
+                messages: VecDeque::with_capacity(capacity),
+                first_seq: 0,
+                capacity,
+                receivers: 0,
+                closed: false,
+                stats: RelayStats::default(),
+            }),
+            notify: Notify::new(),
+        }
+    }
+
+    pub fn send(&self, data: Vec<u8>) {
+        self.push(RelayMessage::Video(data));
+    }
+
+    pub fn send_marked(&self, markers: Vec<String>, data: Vec<u8>) {
+        self.push(RelayMessage::Marked(markers, data));
+    }
+
+    fn push(&self, message: RelayMessage) {
+        {
+            let mut state = self.state.lock().unwrap();
+            let (RelayMessage::Video(data) | RelayMessage::Marked(_, data)) = &message;
+            state.stats.video_bytes += data.len() as u64;
+            state.messages.push_back(message);
+            state.trim();
+        }
+        self.notify.notify_waiters();
+    }
+
+    // New receivers only see messages sent after they subscribe
+    pub fn subscribe(self: &Arc<Self>) -> RelayReceiver {
+        let mut state = self.state.lock().unwrap();
+        state.receivers += 1;
+        RelayReceiver {
+            relay: Arc::clone(self),
+            next_seq: state.end_seq(),
+        }
+    }
+
+    // Copy of the buffered video messages, oldest first
+    pub fn recent(&self) -> Vec<Vec<u8>> {
+        self.state
+            .lock()
+            .unwrap()
+            .messages
+            .iter()
+            .map(|message| match message {
+                RelayMessage::Video(data) | RelayMessage::Marked(_, data) => data.clone(),
+            })
+            .collect()
+    }
+
+    pub fn receiver_count(&self) -> usize {
+        self.state.lock().unwrap().receivers
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.state.lock().unwrap().capacity
+    }
+
+    // Shrinking drops the oldest messages; receivers still on them lag
+    pub fn set_capacity(&self, capacity: usize) {
+        let mut state = self.state.lock().unwrap();
+        if state.capacity != capacity {
+            state.capacity = capacity;
+            state.stats.resizes += 1;
+            state.trim();
+        }
+    }
+
+    pub fn stats(&self) -> RelayStats {
+        self.state.lock().unwrap().stats.clone()
+    }
+
+    // Receivers get RecvError::Closed once they have drained the buffer
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.notify.notify_waiters();
+    }
+
+    fn take_peak_backlog(&self) -> usize {
+        std::mem::take(&mut self.state.lock().unwrap().stats.peak_backlog)
+    }
+}
+
+impl RelayState {
+    fn end_seq(&self) -> u64 {
+        self.first_seq + self.messages.len() as u64
+    }
+
+    fn trim(&mut self) {
+        while self.messages.len() > self.capacity {
+            self.messages.pop_front();
+            self.first_seq += 1;
+        }
+    }
+}
+
+pub struct RelayReceiver {
+    relay: Arc<VideoRelay>,
+    next_seq: u64,
+}
+
+impl RelayReceiver {
+    pub async fn recv(&mut self) -> Result<RelayMessage, RecvError> {
+        loop {
+            // Registered before checking, so a send in between still wakes us
+            let notified = self.relay.notify.notified();
+            {
+                let mut state = self.relay.state.lock().unwrap();
+                if self.next_seq < state.first_seq {
+                    let missed = state.first_seq - self.next_seq;
+                    self.next_seq = state.first_seq;
+                    state.stats.lag_events += 1;
+                    state.stats.lagged_messages += missed;
+                    return Err(RecvError::Lagged(missed));
+                }
+                let end_seq = state.end_seq();
+                if self.next_seq < end_seq {
+                    let backlog = (end_seq - self.next_seq) as usize;
+                    state.stats.peak_backlog = state.stats.peak_backlog.max(backlog);
+                    let data = state.messages[(self.next_seq - state.first_seq) as usize].clone();
+                    self.next_seq += 1;
+                    return Ok(data);
+                }
+                if state.closed {
+                    return Err(RecvError::Closed);
+                }
+            }
+            notified.await;
+        }
+    }
+}
+
+impl Drop for RelayReceiver {
+    fn drop(&mut self) {
+        self.relay.state.lock().unwrap().receivers -= 1;
+    }
+}
+
+// Adaptive capacity. A larger buffer lets slow clients ride out bursts and
+// network hiccups without being cut off, but also lets them fall further
+// behind live; a smaller one keeps every client close to live at the cost of
+// dropping those that stall. Capacity doubles (up to max_capacity) whenever a
+// receiver lags, and halves (down to min_capacity) after 30s without lag while
+// the deepest backlog stayed under a quarter of the buffer.
+pub async fn adapt_capacity(relay: Arc<VideoRelay>, config: BufferConfig) {
+    let mut last_lag_events = relay.stats().lag_events;
+    let mut calm_intervals = 0;
+
+    loop {
+        tokio::time::sleep(ADAPT_INTERVAL).await;
+
+        let lag_events = relay.stats().lag_events;
+        let lagged = lag_events > last_lag_events;
+        last_lag_events = lag_events;
+        let peak_backlog = relay.take_peak_backlog();
+        let capacity = relay.capacity();
+
+        let target = if lagged {
+            calm_intervals = 0;
+            (capacity * 2).min(config.max_capacity)
+        } else if peak_backlog < capacity / 4 {
+            calm_intervals += 1;
+            if calm_intervals >= CALM_INTERVALS_TO_SHRINK {
+                calm_intervals = 0;
+                (capacity / 2).max(config.min_capacity)
+            } else {
+                capacity
+            }
+        } else {
+            calm_intervals = 0;
+            capacity
+        };
+
+        if target != capacity {
+            log::info!("Resizing video buffer from {} to {} messages", capacity, target);
+            relay.set_capacity(target);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn video(message: RelayMessage) -> Vec<u8> {
+        match message {
+            RelayMessage::Video(data) => data,
+            RelayMessage::Marked(markers, _) => panic!("unexpected markers {:?}", markers),
+        }
+    }
+
+    #[tokio::test]
+    async fn receivers_get_messages_sent_after_subscribing() {
+        let relay = Arc::new(VideoRelay::new(10));
+        relay.send(vec![0]);
+        let mut receiver = relay.subscribe();
+        assert_eq!(relay.receiver_count(), 1);
+        relay.send_marked(vec!["{}".to_string()], vec![1, 2]);
+        relay.send(vec![3]);
+
+        assert!(matches!(receiver.recv().await, Ok(RelayMessage::Marked(markers, data)) if markers == ["{}"] && data == [1, 2]));
+        assert_eq!(video(receiver.recv().await.unwrap()), [3]);
+        assert_eq!(relay.recent(), [vec![0], vec![1, 2], vec![3]]);
+        assert_eq!(relay.stats().video_bytes, 4);
+
+        relay.close();
+        assert!(matches!(receiver.recv().await, Err(RecvError::Closed)));
+        drop(receiver);
+        assert_eq!(relay.receiver_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn slow_receivers_lag() {
+        let relay = Arc::new(VideoRelay::new(3));
+        let mut receiver = relay.subscribe();
+        for i in 0..5 {
+            relay.send(vec![i]);
+        }
+        assert!(matches!(receiver.recv().await, Err(RecvError::Lagged(2))));
+        assert_eq!(video(receiver.recv().await.unwrap()), [2]);
+        let stats = relay.stats();
+        assert_eq!((stats.lag_events, stats.lagged_messages, stats.peak_backlog), (1, 2, 3));
+
+        // Shrinking drops the oldest messages a receiver hasn't read yet
+        relay.set_capacity(1);
+        assert!(matches!(receiver.recv().await, Err(RecvError::Lagged(1))));
+        assert_eq!(video(receiver.recv().await.unwrap()), [4]);
+        assert_eq!((relay.capacity(), relay.stats().resizes), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn recv_waits_for_the_next_message() {
+        let relay = Arc::new(VideoRelay::new(3));
+        let mut receiver = relay.subscribe();
+        let sender = Arc::clone(&relay);
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            sender.send(vec![7]);
+        });
+        let message = tokio::time::timeout(Duration::from_secs(1), receiver.recv()).await.unwrap();
+        assert_eq!(video(message.unwrap()), [7]);
+    }
+}
@@ -0,0 +1,152 @@
+use crate::config::{HlsConfig, OutputCodec, StreamOptions};
+use base64::Engine;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::process::Stdio;
+use std::time::Duration;
+
+// Length of a sample fragment
+pub const SAMPLE_SECONDS: u32 = 2;
+
+// Connecting to the source and encoding the sample must finish within this
+const SAMPLE_TIMEOUT: Duration = Duration::from_secs(20);
+
+// Output mode a sample is produced for
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SampleFormat {
+    // MPEG1 in MPEG-TS, as sent to jsmpeg
+    Mpeg1,
+    // H.264 in fragmented MP4, as sent to MSE players
+    H264,
+    // An H.264 MPEG-TS segment, as served by start_hls_stream
+    Hls,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SampleFragment {
+    pub format: SampleFormat,
+    pub mime_type: String,
+    // The source's video was stream-copied, not re-encoded
+    pub video_copy: bool,
+    // The sample's bytes, base64-encoded
+    pub data: String,
+}
+
+// Produce a few seconds of the source in the same codec and container the
+// given output mode would deliver, so a frontend can test whether the
+// browser decodes it. The output is limited with -t, so the same arguments
+// as the live output are used otherwise.
+pub async fn fragment(
+    ffmpeg: &str,
+    url: &str,
+    options: &StreamOptions,
+    format: SampleFormat,
+    copy_video: bool,
+) -> Result<SampleFragment, String> {
+    let mut options = StreamOptions {
+        recording_config: None,
+        shadow_buffer: None,
+        pipe_output: None,
+        quality_monitor: None,
+        debug_timestamps: false,
+        webrtc: false,
+        max_frames: None,
+        ..options.clone()
+    };
+    let mut args = vec!["-v".to_string(), "error".to_string()];
+
+    let (data, mime_type) = match format {
+        SampleFormat::Mpeg1 | SampleFormat::H264 => {
+            let h264 = format == SampleFormat::H264;
+            options.encode.codec = if h264 { OutputCodec::H264 } else { OutputCodec::Mpeg1 };
+            args.extend(crate::ffmpeg::build_ffmpeg_args(url, &options, copy_video, None));
+            limit_output(&mut args);
+            (run(ffmpeg, args).await?, if h264 { "video/mp4" } else { "video/mp2t" })
+        }
+        SampleFormat::Hls => {
+            let hls = HlsConfig {
+                segment_seconds: SAMPLE_SECONDS,
+                ..HlsConfig::default()
+            };
+            hls.validate(&options)?;
+            let dir = std::env::temp_dir().join(format!("eroxii-sample-{}", crate::unix_millis()));
+            std::fs::create_dir_all(&dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+            args.extend(crate::ffmpeg::build_hls_args(url, &options, &hls, &dir));
+            limit_output(&mut args);
+            let result = run(ffmpeg, args).await.and_then(|_| {
+                // The first segment holds the whole sample
+                let segment = crate::hls::SEGMENT_PATTERN.replace("%05d", "00000");
+                std::fs::read(dir.join(segment)).map_err(|e| format!("FFmpeg wrote no segment: {}", e))
+            });
+            let _ = std::fs::remove_dir_all(&dir);
+            (result?, "video/mp2t")
+        }
+    };
+
+    if data.is_empty() {
+        return Err("FFmpeg produced no video".to_string());
+    }
+    Ok(SampleFragment {
+        format,
+        mime_type: mime_type.to_string(),
+        video_copy: copy_video && format != SampleFormat::Hls,
+        data: base64::engine::general_purpose::STANDARD.encode(data),
+    })
+}
+
+// Limit the first output to the sample length. -t before an input would
+// also cut a still image input short, so it goes right after the last one.
+fn limit_output(args: &mut Vec<String>) {
+    let inputs = args.iter().rposition(|arg| arg == "-i").map_or(0, |i| i + 2);
+    args.splice(inputs..inputs, ["-t".to_string(), SAMPLE_SECONDS.to_string()]);
+}
+
+// Run FFmpeg to completion and return what it wrote to stdout
+async fn run(ffmpeg: &str, args: Vec<String>) -> Result<Vec<u8>, String> {
+    let mut cmd = tokio::process::Command::from(crate::ffmpeg::command(ffmpeg));
+    cmd.args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+
+    let child = cmd.spawn().map_err(|e| format!("Failed to run FFmpeg: {}", e))?;
+    let output = tokio::time::timeout(SAMPLE_TIMEOUT, child.wait_with_output())
+        .await
+        .map_err(|_| format!("Sample timed out after {}s", SAMPLE_TIMEOUT.as_secs()))?
+        .map_err(|e| format!("Failed to run FFmpeg: {}", e))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!(
+            "FFmpeg could not produce a sample: {}",
+            crate::rtsp_url::redact(stderr.trim())
+        ));
+    }
+    Ok(output.stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_output_is_limited() {
+        let options = StreamOptions {
+            image_overlay: Some(crate::config::OverlayImageConfig {
+                path: "logo.png".into(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let mut args = crate::ffmpeg::build_ffmpeg_args("rtsp://cam/stream", &options, false, None);
+        limit_output(&mut args);
+
+        let limit = args.iter().position(|arg| arg == "-t").unwrap();
+        assert_eq!(args[limit + 1], SAMPLE_SECONDS.to_string());
+        // After every input, so only the output is cut
+        assert_eq!(args.iter().rposition(|arg| arg == "-i"), Some(limit - 2));
+        assert_eq!(args.iter().filter(|arg| *arg == "-t").count(), 1);
+    }
+}
@@ -0,0 +1,264 @@
+use crate::abr::ClientFeedback;
+use crate::config::{BenchmarkConfig, HlsConfig, StreamOptions};
+use crate::sample::SampleFormat;
+use crate::settings::Settings;
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+// Machine-readable description of the Tauri commands, generated from the same
+// typed structs the commands deserialize. The command list itself is
+// written out below; a test checks it against the handlers in lib.rs.
+// Defaults come from the Default impls; simple ranges are annotated on the
+// fields. Rules across fields (e.g. "sources need filter_complex") are only
+// checked by the commands themselves.
+#[derive(Serialize, Clone, Debug)]
+pub struct ApiSchema {
+    pub commands: Vec<CommandSchema>,
+    // Shared types referenced from the parameters as "#/definitions/<name>"
+    pub definitions: BTreeMap<String, Schema>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct CommandSchema {
+    pub name: &'static str,
+    pub parameters: Vec<ParameterSchema>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ParameterSchema {
+    pub name: &'static str,
+    // Key in the object passed to invoke(); Tauri expects camelCase
+    pub key: String,
+    // Optional parameters may be omitted or null
+    pub required: bool,
+    pub schema: Schema,
+}
+
+struct Builder {
+    generator: SchemaGenerator,
+    commands: Vec<CommandSchema>,
+}
+
+impl Builder {
+    fn command(&mut self, name: &'static str) -> &mut Self {
+        self.commands.push(CommandSchema {
+            name,
+            parameters: Vec::new(),
+        });
+        self
+    }
+
+    fn required<T: JsonSchema>(&mut self, name: &'static str) -> &mut Self {
+        self.parameter::<T>(name, true)
+    }
+
+    // For Option<T> parameters; described by the schema of T
+    fn optional<T: JsonSchema>(&mut self, name: &'static str) -> &mut Self {
+        self.parameter::<T>(name, false)
+    }
+
+    fn parameter<T: JsonSchema>(&mut self, name: &'static str, required: bool) -> &mut Self {
+        let schema = self.generator.subschema_for::<T>();
+        let command = self.commands.last_mut().expect("parameter added before any command");
+        command.parameters.push(ParameterSchema {
+            name,
+            key: camel_case(name),
+            required,
+            schema,
+        });
+        self
+    }
+}
+
+fn camel_case(name: &str) -> String {
+    let mut key = String::with_capacity(name.len());
+    let mut upper = false;
+    for c in name.chars() {
+        if c == '_' {
+            upper = true;
+        } else if upper {
+            key.push(c.to_ascii_uppercase());
+            upper = false;
+        } else {
+            key.push(c);
+        }
+    }
+    key
+}
+
+// Keep in sync with generate_handler! in lib.rs (checked by the test below)
+pub fn api_schema() -> ApiSchema {
+    let mut builder = Builder {
+        generator: SchemaSettings::draft07().into_generator(),
+        commands: Vec::new(),
+    };
+    builder
+        .command("start_stream")
+        .required::<String>("rtsp_url")
+        .required::<u16>("ws_port")
+        .optional::<StreamOptions>("options")
+        .optional::<PathBuf>("ffmpeg_path");
+    builder
+        .command("start_hls_stream")
+        .required::<String>("rtsp_url")
+        .required::<u16>("port")
+        .optional::<StreamOptions>("options")
+        .optional::<HlsConfig>("hls");
+    builder.command("stop_stream").required::<u16>("ws_port");
+    builder
+        .command("swap_ports")
+        .required::<u16>("port_a")
+        .required::<u16>("port_b");
+    builder.command("get_active_streams");
+    builder.command("probe_all_streams");
+    builder.command("check_ffmpeg");
+    for name in [
+        "get_stream_logs",
+        "get_stream_quality",
+        "get_stream_metrics",
+        "is_stream_watched",
+        "get_watch_history",
+        "get_stream_clients",
+        "get_socket_options",
+        "get_jsmpeg_config",
+        "disable_shadow_buffer",
+        "reprobe_stream",
+    ] {
+        builder.command(name).required::<u16>("ws_port");
+    }
+    builder
+        .command("export_metrics_csv")
+        .required::<u16>("ws_port")
+        .required::<PathBuf>("output_path");
+    builder
+        .command("push_client_feedback")
+        .required::<u16>("ws_port")
+        .required::<ClientFeedback>("feedback");
+    builder.command("get_settings");
+    builder.command("set_settings").required::<Settings>("settings");
+    builder.command("mark_source_dead").required::<String>("rtsp_url");
+    builder.command("unmark_source_dead").required::<String>("rtsp_url");
+    builder
+        .command("provide_stream_url")
+        .required::<u16>("ws_port")
+        .required::<String>("new_url");
+    builder
+        .command("update_credentials")
+        .required::<u16>("ws_port")
+        .required::<String>("username")
+        .required::<String>("password")
+        .optional::<bool>("restart");
+    builder
+        .command("enable_shadow_buffer")
+        .required::<u16>("ws_port")
+        .required::<u32>("minutes")
+        .optional::<PathBuf>("directory");
+    builder
+        .command("promote_shadow")
+        .required::<u16>("ws_port")
+        .required::<PathBuf>("output_path");
+    builder.command("normalize_rtsp_url").required::<String>("url");
+    builder.command("capture_all_snapshots").required::<PathBuf>("output_dir");
+    builder
+        .command("get_sample_fragment")
+        .required::<String>("rtsp_url")
+        .required::<SampleFormat>("codec")
+        .optional::<StreamOptions>("options")
+        .optional::<PathBuf>("ffmpeg_path");
+    builder.command("list_supported_protocols");
+    builder.command("list_capture_devices");
+    builder.command("get_capabilities").optional::<bool>("refresh");
+    builder.command("drain_recordings").optional::<u64>("timeout_ms");
+    builder.command("get_api_schema");
+    builder
+        .command("benchmark_capacity")
+        .required::<String>("source")
+        .optional::<StreamOptions>("options")
+        .optional::<BenchmarkConfig>("benchmark");
+    builder.command("cancel_benchmark");
+
+    ApiSchema {
+        commands: builder.commands,
+        definitions: builder.generator.take_definitions().into_iter().collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Split at the commas outside <...> and (...)
+    fn split_top_level(list: &str) -> Vec<&str> {
+        let (mut parts, mut depth, mut start) = (Vec::new(), 0, 0);
+        for (i, c) in list.char_indices() {
+            match c {
+                '<' | '(' => depth += 1,
+                '>' | ')' => depth -= 1,
+                ',' if depth == 0 => {
+                    parts.push(list[start..i].trim());
+                    start = i + 1;
+                }
+                _ => {}
+            }
+        }
+        parts.push(list[start..].trim());
+        parts.into_iter().filter(|part| !part.is_empty()).collect()
+    }
+
+    // Parameters a frontend passes to the command: name and whether it is
+    // required. Injected state and the app handle are left out.
+    fn handler_parameters(source: &str, name: &str) -> Vec<(String, bool)> {
+        let handler = source
+            .split("#[tauri::command]")
+            .find_map(|item| item.split_once(&format!("fn {}(", name)).filter(|(head, _)| !head.contains('{')))
+            .unwrap_or_else(|| panic!("no handler fn {}", name))
+            .1;
+        let mut depth = 1;
+        let end = handler
+            .char_indices()
+            .find(|&(_, c)| {
+                depth += match c {
+                    '(' => 1,
+                    ')' => -1,
+                    _ => 0,
+                };
+                depth == 0
+            })
+            .unwrap()
+            .0;
+        split_top_level(&handler[..end])
+            .into_iter()
+            .filter_map(|parameter| {
+                let (name, ty) = parameter.split_once(':').unwrap();
+                let ty = ty.trim();
+                let injected = ty.starts_with("State<") || ty == "AppHandle";
+                (!injected).then(|| (name.trim().to_string(), !ty.starts_with("Option<")))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn the_schema_matches_the_handlers() {
+        let source = include_str!("lib.rs");
+        let list = source.split("generate_handler![").nth(1).unwrap();
+        let mut handlers = split_top_level(&list[..list.find(']').unwrap()]);
+        handlers.sort();
+
+        let schema = api_schema();
+        let mut names: Vec<&str> = schema.commands.iter().map(|command| command.name).collect();
+        names.sort();
+        assert_eq!(names, handlers);
+        for command in &schema.commands {
+            let parameters: Vec<(String, bool)> = command
+                .parameters
+                .iter()
+                .map(|parameter| (parameter.name.to_string(), parameter.required))
+                .collect();
+            assert_eq!(parameters, handler_parameters(source, command.name), "{}", command.name);
+        }
+    }
+}
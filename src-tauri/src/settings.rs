@@ -0,0 +1,140 @@
+use crate::config::{StreamLimits, StreamOptions};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tokio::sync::RwLock;
+
+const SETTINGS_FILE: &str = "settings.json";
+
+// User settings persisted in the app config dir
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default)]
+#[serde(default)]
+pub struct Settings {
+    // FFmpeg binary picked by the user; takes precedence over discovery
+    pub ffmpeg_path: Option<PathBuf>,
+    // Options used by start_stream when the caller doesn't pass any
+    pub default_options: StreamOptions,
+    // Sources marked dead by the operator (see rtsp_url::source_key): their
+    // streams stop on the first failure instead of reconnecting
+    pub dead_sources: Vec<String>,
+    // Stream count and bitrate limits, and how priorities are enforced
+    pub stream_limits: StreamLimits,
+}
+
+impl Settings {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(path) = &self.ffmpeg_path {
+            crate::ffmpeg::validate_ffmpeg_binary(path)?;
+        }
+        self.stream_limits.validate()?;
+        self.default_options.validate()
+    }
+}
+
+pub struct SettingsStore {
+    path: Option<PathBuf>,
+    settings: RwLock<Settings>,
+}
+
+impl SettingsStore {
+    // Load settings from the config dir, falling back to defaults if the file
+    // is missing or unreadable
+    pub fn load(config_dir: Option<PathBuf>) -> Self {
+        let path = config_dir.map(|dir| dir.join(SETTINGS_FILE));
+        let settings = path
+            .as_ref()
+            .filter(|path| path.exists())
+            .and_then(|path| read_settings(path))
+            .unwrap_or_default();
+
+        crate::set_ffmpeg_override(settings.ffmpeg_path.clone());
+        Self {
+            path,
+            settings: RwLock::new(settings),
+        }
+    }
+
+    pub async fn get(&self) -> Settings {
+        self.settings.read().await.clone()
+    }
+
+    // Validate, persist and apply new settings. The dead source list is only
+    // changed through set_source_dead, so it is kept as it is.
+    pub async fn set(&self, mut settings: Settings) -> Result<(), String> {
+        settings.validate()?;
+        let mut current = self.settings.write().await;
+        settings.dead_sources = current.dead_sources.clone();
+        self.persist(&settings)?;
+
+        crate::set_ffmpeg_override(settings.ffmpeg_path.clone());
+        *current = settings;
+        Ok(())
+    }
+
+    pub async fn is_source_dead(&self, key: &str) -> bool {
+        self.settings.read().await.dead_sources.iter().any(|dead| dead == key)
+    }
+
+    // Add or remove a source key in the persisted dead list; returns the list
+    pub async fn set_source_dead(&self, key: String, dead: bool) -> Result<Vec<String>, String> {
+        let mut settings = self.settings.write().await;
+        let mut updated = settings.clone();
+        updated.dead_sources.retain(|existing| *existing != key);
+        if dead {
+            updated.dead_sources.push(key);
+        }
+        self.persist(&updated)?;
+        *settings = updated;
+        Ok(settings.dead_sources.clone())
+    }
+
+    fn persist(&self, settings: &Settings) -> Result<(), String> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+        }
+        let text = serde_json::to_string_pretty(settings).map_err(|e| e.to_string())?;
+        fs::write(path, text).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+    }
+}
+
+fn read_settings(path: &Path) -> Option<Settings> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) => {
+            log::warn!("Failed to read settings file {}: {}", path.display(), e);
+            return None;
+        }
+    };
+    match serde_json::from_str(&text) {
+        Ok(settings) => Some(settings),
+        Err(e) => {
+            log::warn!("Ignoring invalid settings file {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn replacing_settings_keeps_dead_sources() {
+        let store = SettingsStore::load(None);
+        let key = "rtsp://cam:554/live".to_string();
+        store.set_source_dead(key.clone(), true).await.unwrap();
+
+        let mut settings = Settings::default();
+        settings.default_options.encode.bitrate_kbps = 500;
+        store.set(settings).await.unwrap();
+        assert!(store.is_source_dead(&key).await);
+        assert_eq!(store.get().await.default_options.encode.bitrate_kbps, 500);
+
+        assert!(store.set_source_dead(key.clone(), false).await.unwrap().is_empty());
+        assert!(!store.is_source_dead(&key).await);
+    }
+}
@@ -0,0 +1,188 @@
+use crate::config::ShadowConfig;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+
+// Length of each shadow segment; the window is kept in whole segments
+pub const SEGMENT_SECONDS: u32 = 10;
+const SEGMENT_PREFIX: &str = "shadow_";
+const PROMOTE_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PromotedClip {
+    pub path: String,
+    pub segments: usize,
+    // Approximate, from the segment count
+    pub duration_seconds: u32,
+}
+
+// Default segment directory for the shadow buffer of the stream on a port
+pub fn default_dir(port: u16) -> PathBuf {
+    std::env::temp_dir().join(format!("eroxii-shadow-{}", port))
+}
+
+// Shadow config with the directory filled in and created
+pub fn prepare(config: &ShadowConfig, port: u16) -> Result<ShadowConfig, String> {
+    let directory = config.directory.clone().unwrap_or_else(|| default_dir(port));
+    std::fs::create_dir_all(&directory)
+        .map_err(|e| format!("Failed to create {}: {}", directory.display(), e))?;
+    Ok(ShadowConfig {
+        minutes: config.minutes,
+        directory: Some(directory),
+    })
+}
+
+// Segment files FFmpeg cycles through: the window plus the one being written
+pub fn segment_count(config: &ShadowConfig) -> u32 {
+    (config.minutes * 60).div_ceil(SEGMENT_SECONDS) + 1
+}
+
+pub fn segment_pattern(dir: &Path) -> PathBuf {
+    dir.join(format!("{}%03d.ts", SEGMENT_PREFIX))
+}
+
+// List of the completed segments, oldest first, which FFmpeg rewrites
+// whenever a segment is finished
+pub fn segment_list(dir: &Path) -> PathBuf {
+    dir.join(format!("{}list.txt", SEGMENT_PREFIX))
+}
+
+// Completed segments, oldest first. Listed segments written to after the
+// list are being overwritten by a new cycle and are left out.
+fn segments(dir: &Path) -> Vec<PathBuf> {
+    let list_path = segment_list(dir);
+    let (Ok(list), Ok(listed_at)) = (
+        std::fs::read_to_string(&list_path),
+        std::fs::metadata(&list_path).and_then(|metadata| metadata.modified()),
+    ) else {
+        return Vec::new();
+    };
+    list.lines()
+        .filter_map(|line| Path::new(line.trim()).file_name())
+        .map(|name| dir.join(name))
+        .filter(|path| {
+            std::fs::metadata(path)
+                .and_then(|metadata| metadata.modified())
+                .is_ok_and(|modified| modified <= listed_at)
+        })
+        .collect()
+}
+
+// Delete the shadow segments and their list, and the directory too when it
+// is the default temp directory
+pub fn clear(dir: &Path, port: u16) {
+    for entry in std::fs::read_dir(dir).into_iter().flatten().flatten() {
+        if entry.file_name().to_string_lossy().starts_with(SEGMENT_PREFIX) {
+            let _ = std::fs::remove_file(entry.path());
+        }
+    }
+    if dir == default_dir(port) {
+        let _ = std::fs::remove_dir(dir);
+    }
+}
+
+// Join the current shadow window into one file. The container follows the
+// extension of `output`; streams are copied, not re-encoded. The completed
+// segments are copied out first, as FFmpeg keeps overwriting the oldest one.
+pub async fn promote(ffmpeg: &str, dir: &Path, output: &Path) -> Result<PromotedClip, String> {
+    let work_dir = std::env::temp_dir().join(format!("eroxii-promote-{}", crate::unix_millis()));
+    tokio::fs::create_dir_all(&work_dir)
+        .await
+        .map_err(|e| format!("Failed to create {}: {}", work_dir.display(), e))?;
+    let result = promote_in(ffmpeg, dir, &work_dir, output).await;
+    let _ = tokio::fs::remove_dir_all(&work_dir).await;
+    result
+}
+
+async fn promote_in(ffmpeg: &str, dir: &Path, work_dir: &Path, output: &Path) -> Result<PromotedClip, String> {
+    let listed = segments(dir);
+    let mut copies = Vec::new();
+    for (index, segment) in listed.iter().enumerate() {
+        let copy = work_dir.join(format!("{:03}.ts", index));
+        tokio::fs::copy(segment, &copy)
+            .await
+            .map_err(|e| format!("Failed to copy {}: {}", segment.display(), e))?;
+        copies.push(copy);
+    }
+    // A segment that left the list meanwhile may have been overwritten
+    // while it was copied
+    let current = segments(dir);
+    let copies: Vec<PathBuf> = listed
+        .iter()
+        .zip(copies)
+        .filter(|(segment, _)| current.contains(segment))
+        .map(|(_, copy)| copy)
+        .collect();
+    if copies.is_empty() {
+        return Err("Shadow buffer has no recorded segments yet".to_string());
+    }
+
+    // concat demuxer list; single quotes in paths are escaped as '\''
+    let list: String = copies
+        .iter()
+        .map(|path| format!("file '{}'\n", path.to_string_lossy().replace('\'', "'\\''")))
+        .collect();
+    let list_path = work_dir.join("promote.txt");
+    tokio::fs::write(&list_path, list)
+        .await
+        .map_err(|e| format!("Failed to write segment list: {}", e))?;
+
+    let mut cmd = tokio::process::Command::from(crate::ffmpeg::command(ffmpeg));
+    cmd.args(["-v", "error", "-y", "-f", "concat", "-safe", "0", "-i"])
+        .arg(&list_path)
+        .args(["-c", "copy"])
+        .arg(output)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+
+    let output_result = tokio::time::timeout(PROMOTE_TIMEOUT, cmd.output())
+        .await
+        .map_err(|_| format!("Promoting the shadow buffer timed out after {}s", PROMOTE_TIMEOUT.as_secs()))?
+        .map_err(|e| format!("Failed to run FFmpeg: {}", e))?;
+    if !output_result.status.success() {
+        let stderr = String::from_utf8_lossy(&output_result.stderr);
+        return Err(format!("FFmpeg could not join the shadow segments: {}", stderr.trim()));
+    }
+
+    Ok(PromotedClip {
+        path: output.to_string_lossy().to_string(),
+        segments: copies.len(),
+        duration_seconds: copies.len() as u32 * SEGMENT_SECONDS,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_window_has_one_spare_segment() {
+        let config = |minutes| ShadowConfig { minutes, directory: None };
+        assert_eq!(segment_count(&config(1)), 7);
+        assert_eq!(segment_count(&config(5)), 31);
+    }
+
+    #[test]
+    fn only_completed_segments_are_promoted() {
+        let dir = std::env::temp_dir().join(format!("eroxii-shadow-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        assert!(segments(&dir).is_empty());
+
+        for index in [4, 5, 0] {
+            std::fs::write(dir.join(format!("shadow_{:03}.ts", index)), b"").unwrap();
+        }
+        std::fs::write(segment_list(&dir), "shadow_004.ts\nshadow_005.ts\nshadow_000.ts\n").unwrap();
+        // The oldest segment is overwritten by the next cycle
+        std::thread::sleep(Duration::from_millis(20));
+        std::fs::write(dir.join("shadow_004.ts"), b"").unwrap();
+        std::fs::write(dir.join("shadow_001.ts"), b"").unwrap();
+
+        assert_eq!(segments(&dir), [dir.join("shadow_005.ts"), dir.join("shadow_000.ts")]);
+        clear(&dir, 0);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        std::fs::remove_dir(&dir).unwrap();
+    }
+}
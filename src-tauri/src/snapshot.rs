@@ -0,0 +1,68 @@
+use crate::config::StreamOptions;
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(15);
+
+// Decode the newest frame of a stream's buffered live output into a JPEG.
+// `container` is the live output format ("mpegts" or "mp4"); fMP4 data must
+// start with the init segment. The image is rewritten for every decoded
+// frame, so the file ends up holding the last one.
+pub async fn from_buffer(ffmpeg: &str, data: Vec<u8>, container: &str, output: &Path) -> Result<(), String> {
+    let mut cmd = snapshot_command(ffmpeg);
+    cmd.args(["-f", container, "-i", "pipe:0"])
+        .args(["-update", "1", "-q:v", "2", "-f", "image2"])
+        .arg(output)
+        .stdin(Stdio::piped());
+
+    let mut child = cmd.spawn().map_err(|e| format!("Failed to run FFmpeg: {}", e))?;
+    let mut stdin = child.stdin.take().ok_or("Failed to open FFmpeg stdin")?;
+    // FFmpeg may stop reading early on bad data; its exit status tells
+    let _ = tokio::time::timeout(SNAPSHOT_TIMEOUT, stdin.write_all(&data)).await;
+    drop(stdin);
+
+    wait(child, output).await
+}
+
+// Grab one frame straight from the source, for streams with nothing buffered
+pub async fn from_source(ffmpeg: &str, url: &str, options: &StreamOptions, output: &Path) -> Result<(), String> {
+    let mut cmd = snapshot_command(ffmpeg);
+    if let Some(format) = &options.input_format {
+        cmd.args(["-f", format]);
+    }
+    cmd.args(crate::ffmpeg::source_input_args(url, options));
+    cmd.args(["-i", url, "-frames:v", "1", "-q:v", "2", "-f", "image2"])
+        .arg(output)
+        .stdin(Stdio::null());
+
+    let child = cmd.spawn().map_err(|e| format!("Failed to run FFmpeg: {}", e))?;
+    wait(child, output).await
+}
+
+fn snapshot_command(ffmpeg: &str) -> tokio::process::Command {
+    let mut cmd = tokio::process::Command::from(crate::ffmpeg::command(ffmpeg));
+    cmd.args(["-v", "error", "-y"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+
+    cmd
+}
+
+async fn wait(child: tokio::process::Child, output: &Path) -> Result<(), String> {
+    let result = tokio::time::timeout(SNAPSHOT_TIMEOUT, child.wait_with_output())
+        .await
+        .map_err(|_| format!("Snapshot timed out after {}s", SNAPSHOT_TIMEOUT.as_secs()))?
+        .map_err(|e| format!("Failed to run FFmpeg: {}", e))?;
+
+    if !result.status.success() || !output.is_file() {
+        let stderr = String::from_utf8_lossy(&result.stderr);
+        return Err(format!(
+            "FFmpeg could not extract a frame: {}",
+            crate::rtsp_url::redact(stderr.trim())
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,180 @@
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+
+// A forward DTS step counts as a gap, e.g. a camera that stalled or dropped
+// a burst of frames, when it spans more than GAP_FRAMES frame intervals and
+// more than MIN_GAP seconds. The interval is learned per input from its own
+// steps, so a camera sending a frame every few seconds isn't flagged.
+const GAP_FRAMES: f64 = 3.0;
+const MIN_GAP: f64 = 1.0;
+// Weight of each new step in the learned frame interval
+const INTERVAL_WEIGHT: f64 = 0.1;
+
+// Stages FFmpeg prints a packet or frame line for with -debug_ts
+const DEBUG_TS_PREFIXES: [&str; 7] = [
+    "demuxer ->",
+    "demuxer+ffmpeg ->",
+    "decoder ->",
+    "filter ->",
+    "encoder <-",
+    "encoder ->",
+    "muxer <-",
+];
+
+// Timestamp problems of a stream started with debug_timestamps, from the
+// demuxer lines of -debug_ts and FFmpeg's own timestamp warnings
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct TimestampStats {
+    // Demuxed packets whose DTS went backwards
+    pub dts_backwards: u64,
+    // Forward DTS steps of more than GAP_FRAMES frames and MIN_GAP seconds
+    pub dts_gaps: u64,
+    // FFmpeg's "timestamp discontinuity" notices on the inputs
+    pub input_discontinuities: u64,
+    // FFmpeg's "Non-monotonous DTS" corrections on the outputs
+    pub output_non_monotonic: u64,
+    // Description of the most recent problem
+    pub last_issue: Option<String>,
+    // Timeline per input stream ("0:0")
+    #[serde(skip)]
+    inputs: HashMap<String, Timeline>,
+}
+
+#[derive(Clone, Debug)]
+struct Timeline {
+    // Last demuxed DTS in seconds
+    dts: f64,
+    // Average step between packets in seconds, once one was seen
+    interval: Option<f64>,
+}
+
+impl TimestampStats {
+    // Forget the previous FFmpeg run's timeline; a new run starts over
+    pub fn restart(&mut self) {
+        self.inputs.clear();
+    }
+
+    // Count a timestamp problem in an FFmpeg stderr line. Returns a
+    // description of problems found in the -debug_ts timeline, for the
+    // stream log; FFmpeg's own warnings are already logged as they are.
+    pub fn observe(&mut self, line: &str) -> Option<String> {
+        if line.contains("timestamp discontinuity") {
+            self.input_discontinuities += 1;
+            self.last_issue = Some(line.to_string());
+            return None;
+        }
+        if line.contains("Non-monotonous DTS") || line.contains("non monotonically increasing dts") {
+            self.output_non_monotonic += 1;
+            self.last_issue = Some(line.to_string());
+            return None;
+        }
+
+        let (input, dts) = parse_demuxer_line(line)?;
+        let timeline = match self.inputs.entry(input.clone()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                entry.insert(Timeline { dts, interval: None });
+                return None;
+            }
+        };
+        let (previous, step) = (timeline.dts, dts - timeline.dts);
+        timeline.dts = dts;
+        let issue = if step < 0.0 {
+            self.dts_backwards += 1;
+            format!("DTS of input {} went back from {:.3}s to {:.3}s", input, previous, dts)
+        } else if timeline.interval.is_some_and(|interval| step > MIN_GAP.max(GAP_FRAMES * interval)) {
+            self.dts_gaps += 1;
+            format!("DTS of input {} jumped {:.3}s ({:.3}s to {:.3}s)", input, step, previous, dts)
+        } else {
+            // Gaps are left out so they don't stretch the interval
+            if step > 0.0 {
+                timeline.interval = Some(match timeline.interval {
+                    Some(interval) => interval + (step - interval) * INTERVAL_WEIGHT,
+                    None => step,
+                });
+            }
+            return None;
+        };
+        self.last_issue = Some(issue.clone());
+        Some(issue)
+    }
+}
+
+// Whether a line is one of -debug_ts's per-packet lines, which are too many
+// for the application log
+pub fn is_debug_line(line: &str) -> bool {
+    DEBUG_TS_PREFIXES.iter().any(|prefix| line.contains(prefix))
+}
+
+// Input stream and DTS in seconds of a -debug_ts demuxer line, e.g.
+// "demuxer -> ist_index:0:0 type:video pkt_pts:3600 pkt_pts_time:0.04
+// pkt_dts:3600 pkt_dts_time:0.04 ...". Older FFmpeg prints "ist_index:0".
+fn parse_demuxer_line(line: &str) -> Option<(String, f64)> {
+    let rest = &line[line.find("demuxer ->")? + "demuxer ->".len()..];
+    let field = |key: &str| {
+        rest.split_whitespace()
+            .filter_map(|token| token.split_once(':'))
+            .find(|(name, _)| *name == key)
+            .map(|(_, value)| value)
+    };
+    let dts = field("pkt_dts_time")?.parse().ok()?;
+    Some((field("ist_index")?.to_string(), dts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn demuxer_line(ist_index: &str, dts: &str) -> String {
+        format!(
+            "demuxer -> ist_index:{} type:video next_dts:NOPTS pkt_pts:NOPTS pkt_pts_time:NOPTS pkt_dts:0 pkt_dts_time:{} off:0",
+            ist_index, dts
+        )
+    }
+
+    #[test]
+    fn demuxer_lines_are_parsed() {
+        assert_eq!(parse_demuxer_line(&demuxer_line("0:0", "1.04")), Some(("0:0".to_string(), 1.04)));
+        // Older FFmpeg prints the input stream index only
+        assert_eq!(parse_demuxer_line(&demuxer_line("0", "2.5")), Some(("0".to_string(), 2.5)));
+        assert_eq!(parse_demuxer_line(&demuxer_line("0:0", "NOPTS")), None);
+        assert_eq!(parse_demuxer_line("decoder -> ist_index:0:0 pkt_dts_time:1.0"), None);
+        assert!(is_debug_line(&demuxer_line("0:0", "0")));
+    }
+
+    #[test]
+    fn timeline_problems_are_counted() {
+        let mut stats = TimestampStats::default();
+        let mut observe = |input: &str, dts: f64| stats.observe(&demuxer_line(input, &dts.to_string()));
+
+        // 25 fps, then a backwards step and a 2 s gap
+        for frame in 0..10 {
+            assert_eq!(observe("0:0", frame as f64 * 0.04), None);
+        }
+        assert!(observe("0:0", 0.2).unwrap().contains("went back"));
+        assert_eq!(observe("0:0", 0.24), None);
+        assert!(observe("0:0", 2.24).unwrap().contains("jumped"));
+        assert_eq!(observe("0:0", 2.28), None);
+        // Packets without a DTS are skipped
+        assert_eq!(stats.observe(&demuxer_line("0:0", "NOPTS")), None);
+
+        // A camera sending a frame every 2 s has no gaps, until it skips
+        // several frames
+        let mut observe = |dts: f64| stats.observe(&demuxer_line("1:0", &dts.to_string()));
+        for frame in 0..5 {
+            assert_eq!(observe(frame as f64 * 2.0), None);
+        }
+        assert!(observe(20.0).is_some());
+
+        assert_eq!((stats.dts_backwards, stats.dts_gaps), (1, 2));
+        assert!(stats.last_issue.as_ref().unwrap().contains("input 1:0"));
+        assert_eq!(stats.observe("[mpegts @ 0x1] Non-monotonous DTS in output stream 0:0"), None);
+        assert_eq!(stats.output_non_monotonic, 1);
+
+        // A restarted FFmpeg starts a new timeline
+        stats.restart();
+        assert_eq!(stats.observe(&demuxer_line("0:0", "0")), None);
+        assert_eq!(stats.dts_backwards, 1);
+    }
+}
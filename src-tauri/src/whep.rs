@@ -0,0 +1,356 @@
+// WHEP (WebRTC-HTTP Egress Protocol) endpoint for a stream. FFmpeg sends an
+// H.264 RTP output to a local UDP socket and every packet is written to one
+// shared track; each WHEP session is a peer connection subscribed to it.
+// Clients POST an SDP offer to /whep on the stream port and get the answer
+// back with the session URL in Location; DELETE on that URL ends the session.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use webrtc::api::interceptor_registry::register_default_interceptors;
+use webrtc::api::media_engine::{MediaEngine, MIME_TYPE_H264};
+use webrtc::api::{APIBuilder, API};
+use webrtc::interceptor::registry::Registry;
+use webrtc::peer_connection::configuration::RTCConfiguration;
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
+use webrtc::track::track_local::track_local_static_rtp::TrackLocalStaticRTP;
+use webrtc::track::track_local::{TrackLocal, TrackLocalWriter};
+
+const ENDPOINT: &str = "/whep";
+// SDP offers are a few KB; anything larger is not a WHEP request
+const MAX_REQUEST_SIZE: usize = 64 * 1024;
+// How often an incomplete request head is peeked again
+const PEEK_INTERVAL: std::time::Duration = std::time::Duration::from_millis(10);
+
+type Sessions = Arc<Mutex<HashMap<String, Arc<RTCPeerConnection>>>>;
+
+pub struct WhepServer {
+    api: API,
+    track: Arc<TrackLocalStaticRTP>,
+    sessions: Sessions,
+    next_session: AtomicU64,
+    rtp_port: u16,
+    relay_task: JoinHandle<()>,
+}
+
+impl WhepServer {
+    // Bind the RTP socket FFmpeg sends to and start relaying it to the track
+    pub async fn start() -> Result<Self, String> {
+        let mut media_engine = MediaEngine::default();
+        media_engine.register_default_codecs().map_err(|e| e.to_string())?;
+        let registry = register_default_interceptors(Registry::new(), &mut media_engine)
+            .map_err(|e| e.to_string())?;
+        let api = APIBuilder::new()
+            .with_media_engine(media_engine)
+            .with_interceptor_registry(registry)
+            .build();
+
+        let track = Arc::new(TrackLocalStaticRTP::new(
+            RTCRtpCodecCapability {
+                mime_type: MIME_TYPE_H264.to_owned(),
+                ..Default::default()
+            },
+            "video".to_owned(),
+            "eroxii-camera".to_owned(),
+        ));
+
+        let socket = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .map_err(|e| format!("Failed to bind RTP socket: {}", e))?;
+        let rtp_port = socket.local_addr().map_err(|e| e.to_string())?.port();
+        let relay_task = tokio::spawn(relay_rtp(socket, Arc::clone(&track)));
+
+        Ok(Self {
+            api,
+            track,
+            sessions: Arc::default(),
+            next_session: AtomicU64::new(1),
+            rtp_port,
+            relay_task,
+        })
+    }
+
+    // Local UDP port for FFmpeg's RTP output
+    pub fn rtp_port(&self) -> u16 {
+        self.rtp_port
+    }
+
+    // Serve a single HTTP request on a connection that is not a WebSocket upgrade
+    pub async fn handle_http(&self, mut stream: TcpStream, read_timeout: std::time::Duration) {
+        let response = match tokio::time::timeout(read_timeout, read_request(&mut stream)).await {
+            Ok(Ok(request)) => self.route(request).await,
+            Ok(Err(e)) => HttpResponse::text(400, "Bad Request", &e),
+            Err(_) => HttpResponse::text(408, "Request Timeout", "Request not received in time"),
+        };
+        let _ = stream.write_all(&response.into_bytes()).await;
+        let _ = stream.shutdown().await;
+    }
+
+    async fn route(&self, request: HttpRequest) -> HttpResponse {
+        let session = request
+            .path
+            .strip_prefix(ENDPOINT)
+            .and_then(|rest| rest.strip_prefix('/'));
+
+        match (request.method.as_str(), request.path.as_str(), session) {
+            // CORS preflight from the webview (application/sdp is not a simple type)
+            ("OPTIONS", _, _) => HttpResponse::new(204, "No Content"),
+            ("POST", ENDPOINT, _) => match self.create_session(request.body).await {
+                Ok((id, answer)) => {
+                    let mut response = HttpResponse::new(201, "Created");
+                    response.header("Content-Type", "application/sdp");
+                    response.header("Location", &format!("{}/{}", ENDPOINT, id));
+                    response.body = answer.into_bytes();
+                    response
+                }
+                Err(e) => {
+                    log::warn!("WHEP session setup failed: {}", e);
+                    HttpResponse::text(400, "Bad Request", &e)
+                }
+            },
+            ("DELETE", _, Some(id)) => match self.sessions.lock().await.remove(id) {
+                Some(peer) => {
+                    let _ = peer.close().await;
+                    log::info!("WHEP session {} closed", id);
+                    HttpResponse::new(200, "OK")
+                }
+                None => HttpResponse::text(404, "Not Found", "Unknown WHEP session"),
+            },
+            (_, ENDPOINT, _) | (_, _, Some(_)) => {
+                HttpResponse::text(405, "Method Not Allowed", "Unsupported method")
+            }
+            _ => HttpResponse::text(404, "Not Found", "Not found"),
+        }
+    }
+
+    // Answer an SDP offer with a peer connection subscribed to the track. ICE
+    // candidates are gathered up front (no trickle), so the answer is complete.
+    async fn create_session(&self, offer: Vec<u8>) -> Result<(String, String), String> {
+        let offer = String::from_utf8(offer).map_err(|_| "SDP offer is not UTF-8".to_string())?;
+        let peer = Arc::new(
+            self.api
+                .new_peer_connection(RTCConfiguration::default())
+                .await
+                .map_err(|e| e.to_string())?,
+        );
+
+        let answer = match negotiate(&peer, &self.track, offer).await {
+            Ok(answer) => answer,
+            Err(e) => {
+                let _ = peer.close().await;
+                return Err(e);
+            }
+        };
+
+        let id = self.next_session.fetch_add(1, Ordering::Relaxed).to_string();
+        let sessions = Arc::clone(&self.sessions);
+        let session_id = id.clone();
+        peer.on_peer_connection_state_change(Box::new(move |state: RTCPeerConnectionState| {
+            let sessions = Arc::clone(&sessions);
+            let id = session_id.clone();
+            Box::pin(async move {
+                if matches!(state, RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed) {
+                    // Closing from inside the state callback would deadlock
+                    if let Some(peer) = sessions.lock().await.remove(&id) {
+                        log::info!("WHEP session {} ended ({})", id, state);
+                        tokio::spawn(async move {
+                            let _ = peer.close().await;
+                        });
+                    }
+                }
+            })
+        }));
+
+        self.sessions.lock().await.insert(id.clone(), peer);
+        log::info!("WHEP session {} started", id);
+        Ok((id, answer))
+    }
+
+    // Close every session and stop relaying RTP
+    pub async fn close(&self) {
+        self.relay_task.abort();
+        let peers: Vec<_> = self.sessions.lock().await.drain().map(|(_, peer)| peer).collect();
+        for peer in peers {
+            let _ = peer.close().await;
+        }
+    }
+}
+
+async fn negotiate(
+    peer: &RTCPeerConnection,
+    track: &Arc<TrackLocalStaticRTP>,
+    offer: String,
+) -> Result<String, String> {
+    let sender = peer
+        .add_track(Arc::clone(track) as Arc<dyn TrackLocal + Send + Sync>)
+        .await
+        .map_err(|e| e.to_string())?;
+    // Drain RTCP so the interceptors (NACK, receiver reports) keep running
+    tokio::spawn(async move {
+        let mut buffer = vec![0u8; 1500];
+        while sender.read(&mut buffer).await.is_ok() {}
+    });
+
+    let offer = RTCSessionDescription::offer(offer).map_err(|e| e.to_string())?;
+    peer.set_remote_description(offer).await.map_err(|e| e.to_string())?;
+    let answer = peer.create_answer(None).await.map_err(|e| e.to_string())?;
+    let mut gathering = peer.gathering_complete_promise().await;
+    peer.set_local_description(answer).await.map_err(|e| e.to_string())?;
+    let _ = gathering.recv().await;
+
+    peer.local_description()
+        .await
+        .map(|description| description.sdp)
+        .ok_or_else(|| "No local description".to_string())
+}
+
+// Forward FFmpeg's RTP packets to the track, which rewrites SSRC and payload
+// type for each subscribed peer
+async fn relay_rtp(socket: UdpSocket, track: Arc<TrackLocalStaticRTP>) {
+    let mut buffer = vec![0u8; 1500];
+    loop {
+        match socket.recv(&mut buffer).await {
+            Ok(n) => {
+                if let Err(e) = track.write(&buffer[..n]).await {
+                    log::debug!("WHEP track write failed: {}", e);
+                }
+            }
+            Err(e) => {
+                log::error!("WHEP RTP socket error: {}", e);
+                break;
+            }
+        }
+    }
+}
+
+// WebSocket upgrades are GET requests with "Upgrade: websocket"; WHEP uses
+// POST, DELETE and OPTIONS. The request head can arrive in several segments,
+// so it is peeked again until complete; the caller bounds the wait.
+pub async fn is_websocket_request(stream: &TcpStream) -> bool {
+    let mut head = vec![0u8; MAX_REQUEST_SIZE];
+    loop {
+        let n = match stream.peek(&mut head).await {
+            Ok(0) | Err(_) => return true, // Left to the handshake to report
+            Ok(n) => n,
+        };
+        if let Some(end) = head[..n].windows(4).position(|window| window == b"\r\n\r\n") {
+            let head = String::from_utf8_lossy(&head[..end]).to_ascii_lowercase();
+            return head.starts_with("get ")
+                && head
+                    .lines()
+                    .filter_map(|line| line.split_once(':'))
+                    .any(|(name, value)| name.trim() == "upgrade" && value.trim() == "websocket");
+        }
+        if n == head.len() {
+            // Headers too large for WHEP
+            return head.starts_with(b"GET ");
+        }
+        tokio::time::sleep(PEEK_INTERVAL).await;
+    }
+}
+
+struct HttpRequest {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+async fn read_request(stream: &mut TcpStream) -> Result<HttpRequest, String> {
+    let mut data = Vec::new();
+    let mut chunk = [0u8; 4096];
+    let header_end = loop {
+        if let Some(end) = data.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end + 4;
+        }
+        if data.len() > MAX_REQUEST_SIZE {
+            return Err("Request too large".to_string());
+        }
+        let n = stream.read(&mut chunk).await.map_err(|e| e.to_string())?;
+        if n == 0 {
+            return Err("Connection closed before end of headers".to_string());
+        }
+        data.extend_from_slice(&chunk[..n]);
+    };
+
+    let head = String::from_utf8_lossy(&data[..header_end]).to_string();
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().ok_or("Missing method")?.to_string();
+    let path = request_line.next().ok_or("Missing path")?;
+    let path = path.split('?').next().unwrap_or_default().to_string();
+
+    let content_length = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .map(|(_, value)| value.trim().parse::<usize>().map_err(|_| "Invalid Content-Length"))
+        .transpose()?
+        .unwrap_or(0);
+    if content_length > MAX_REQUEST_SIZE {
+        return Err("Request too large".to_string());
+    }
+
+    let mut body = data.split_off(header_end);
+    while body.len() < content_length {
+        let n = stream.read(&mut chunk).await.map_err(|e| e.to_string())?;
+        if n == 0 {
+            return Err("Connection closed before end of body".to_string());
+        }
+        body.extend_from_slice(&chunk[..n]);
+    }
+    body.truncate(content_length);
+
+    Ok(HttpRequest { method, path, body })
+}
+
+struct HttpResponse {
+    status: u16,
+    reason: &'static str,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl HttpResponse {
+    fn new(status: u16, reason: &'static str) -> Self {
+        Self {
+            status,
+            reason,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    fn text(status: u16, reason: &'static str, message: &str) -> Self {
+        let mut response = Self::new(status, reason);
+        response.header("Content-Type", "text/plain");
+        response.body = message.as_bytes().to_vec();
+        response
+    }
+
+    fn header(&mut self, name: &str, value: &str) {
+        self.headers.push((name.to_string(), value.to_string()));
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, self.reason);
+        // The app's webview calls the endpoint cross-origin
+        head.push_str("Access-Control-Allow-Origin: *\r\n");
+        head.push_str("Access-Control-Allow-Methods: POST, DELETE, OPTIONS\r\n");
+        head.push_str("Access-Control-Allow-Headers: Content-Type, Authorization\r\n");
+        head.push_str("Access-Control-Expose-Headers: Location\r\n");
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str(&format!("Content-Length: {}\r\nConnection: close\r\n\r\n", self.body.len()));
+
+        let mut bytes = head.into_bytes();
+        bytes.extend(self.body);
+        bytes
+    }
+}